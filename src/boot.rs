@@ -0,0 +1,309 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use snafu::{ResultExt, OptionExt};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component::Component;
+use crate::config::{Config, BootConfig, LinuxConfig};
+use crate::interrupt::Interrupt;
+use crate::linux;
+use crate::uboot;
+use crate::util;
+
+/// Magic number of the legacy U-Boot images (uImage)
+const IH_MAGIC: u32 = 0x2705_1956;
+const IH_OS_LINUX: u8 = 5;
+const IH_TYPE_SCRIPT: u8 = 6;
+const IH_COMP_NONE: u8 = 0;
+
+/// Boot flow of a target, as U-Boot runs it
+pub struct Boot {
+    cfg: BootConfig,
+    /// Device tree, relative to dtbs/. None when neither the target nor
+    /// Linux provide one.
+    fdt: Option<String>,
+    arch: String,
+    target_name: String,
+    work_dir: PathBuf,
+}
+
+impl Boot {
+    /// Retrieve the name of the kernel image, for a given Linux version
+    pub fn kernel(&self, version: &str) -> String {
+        self.cfg.kernel.as_deref()
+            .unwrap_or("vmlinuz-{version}")
+            .replace("{version}", version)
+    }
+
+    /// Compose the commands that select the slot to be booted. An upgraded
+    /// slot (upgrade_available=1) that failed to boot bootlimit times is
+    /// given up for the next one.
+    fn slot_selection(&self) -> String {
+        let slots = &self.cfg.slots;
+        let mut script = format!("if test -z \"${{slot}}\"; then setenv slot {}; fi\n", slots[0].name);
+        script.push_str("if test \"${upgrade_available}\" = \"1\"; then\n");
+        // U-Boot cannot compare numbers reliably, so the boot count is
+        // incremented as a string
+        for count in 0..self.cfg.bootlimit {
+            script.push_str(&format!("    {} test \"${{bootcount}}\" = \"{}\"; then setenv bootcount {}\n",
+                if count == 0 { "if" } else { "elif" }, count, count + 1));
+        }
+        script.push_str(&format!("    else\n        echo \"Slot ${{slot}} failed to boot {} times. Falling back.\"\n",
+            self.cfg.bootlimit));
+        for (index, slot) in slots.iter().enumerate() {
+            let next = &slots[(index + 1) % slots.len()];
+            script.push_str(&format!("        {} test \"${{slot}}\" = \"{}\"; then setenv slot {}\n",
+                if index == 0 { "if" } else { "elif" }, slot.name, next.name));
+        }
+        script.push_str("        fi\n        setenv upgrade_available 0\n        setenv bootcount 0\n    fi\n    saveenv\nfi\n");
+        for slot in slots {
+            // Partition numbers are hexadecimal for U-Boot
+            script.push_str(&format!("if test \"${{slot}}\" = \"{}\"; then setenv bootpart {:x}; setenv rootdev {}; fi\n",
+                slot.name, slot.partition, slot.root));
+        }
+        script
+    }
+
+    /// Render the boot script (boot.cmd), for a given Linux version. It is
+    /// meant to be run by the distro boot of U-Boot, that sets devtype,
+    /// devnum and distro_bootpart.
+    pub fn script(&self, version: &str) -> String {
+        let expand = |s: &str| s.replace("{version}", version);
+        let kernel_addr = self.cfg.kernel_addr.clone().unwrap_or("${kernel_addr_r}".to_string());
+        let fdt_addr = self.cfg.fdt_addr.clone().unwrap_or("${fdt_addr_r}".to_string());
+        let ramdisk_addr = self.cfg.ramdisk_addr.clone().unwrap_or("${ramdisk_addr_r}".to_string());
+        let command = self.cfg.command.clone().unwrap_or(
+            if self.arch == "arm" { "bootz" } else { "booti" }.to_string());
+
+        let mut script = format!("# Boot script of the {}, generated by mktcb. Do not edit.\n\n",
+            self.target_name);
+        let mut bootargs = self.cfg.bootargs.clone();
+        if self.cfg.slots.is_empty() {
+            script.push_str("setenv bootpart ${distro_bootpart}\n");
+        } else {
+            script.push_str(&self.slot_selection());
+            bootargs = format!("{} root=${{rootdev}}", bootargs).trim().to_string();
+        }
+        script.push_str(&format!("setenv bootargs \"{}\"\n\n", bootargs));
+
+        let load = "load ${devtype} ${devnum}:${bootpart}";
+        script.push_str(&format!("{} {} {}\n", load, kernel_addr, self.kernel(version)));
+        let fdt = match &self.fdt {
+            Some(fdt) => {
+                script.push_str(&format!("{} {} dtbs/{}\n", load, fdt_addr, expand(fdt)));
+                fdt_addr
+            },
+            None => "${fdtcontroladdr}".to_string(),
+        };
+        // The ramdisk is loaded last, as its size is needed
+        let ramdisk = match &self.cfg.initrd {
+            Some(initrd) => {
+                script.push_str(&format!("{} {} {}\n", load, ramdisk_addr, expand(initrd)));
+                format!("{}:${{filesize}}", ramdisk_addr)
+            },
+            None => "-".to_string(),
+        };
+        script.push_str(&format!("{} {} {} {}\n", command, kernel_addr, ramdisk, fdt));
+        script
+    }
+
+    /// Architecture of the legacy image, as understood by U-Boot
+    fn image_arch(&self) -> Result<u8> {
+        match self.arch.as_str() {
+            "arm" => Ok(2),
+            "x86" => Ok(3),
+            "arm64" => Ok(22),
+            "x86_64" => Ok(24),
+            "riscv" => Ok(26),
+            arch => error::UnsupportedImageArch{ arch: arch.to_string() }.fail(),
+        }
+    }
+
+    /// Wrap a script in a legacy U-Boot image, for the architecture of the
+    /// target
+    fn legacy_image(&self, script: &str, epoch: u64) -> Result<Vec<u8>> {
+        Ok(script_image(script, self.image_arch()?, epoch))
+    }
+
+    /// Write the boot script (boot.cmd) and its compiled version (boot.scr)
+    /// for a given Linux version, dated with its sources. The path to
+    /// boot.scr is returned.
+    pub fn write_script(&self, version: &str, epoch: u64) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.work_dir).context(
+            error::CreateDirError{ path: self.work_dir.clone() })?;
+        let script = self.script(version);
+
+        let mut cmd = self.work_dir.clone();
+        cmd.push("boot.cmd");
+        std::fs::write(&cmd, &script).context(error::FailedToWrite{ path: cmd.clone() })?;
+
+        let mut scr = self.work_dir.clone();
+        scr.push("boot.scr");
+        std::fs::write(&scr, self.legacy_image(&script, epoch)?)
+            .context(error::FailedToWrite{ path: scr.clone() })?;
+        info!("Boot script written to {:#?}", scr);
+        Ok(scr)
+    }
+
+    /// Retrieve the variables that the boot flow adds to the U-Boot
+    /// environment. Those of the target take precedence.
+    pub fn env(&self) -> BTreeMap<String, String> {
+        let mut env = BTreeMap::new();
+        if let Some(slot) = self.cfg.slots.first() {
+            env.insert("slot".to_string(), slot.name.clone());
+            env.insert("bootcount".to_string(), "0".to_string());
+            env.insert("upgrade_available".to_string(), "0".to_string());
+        }
+        env.extend(self.cfg.env.clone());
+        env
+    }
+}
+
+/// Wrap a script in a legacy U-Boot image, as mkimage -T script does.
+/// The payload is a multi-file image holding a single file. The image is
+/// dated at epoch (UNIX timestamp).
+fn script_image(script: &str, arch: u8, epoch: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(script.len() as u32).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(script.as_bytes());
+
+    let mut header = vec![0u8; 64];
+    header[0..4].copy_from_slice(&IH_MAGIC.to_be_bytes());
+    header[8..12].copy_from_slice(&(epoch as u32).to_be_bytes());
+    header[12..16].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    header[24..28].copy_from_slice(&util::crc32(&data).to_be_bytes());
+    header[28] = IH_OS_LINUX;
+    header[29] = arch;
+    header[30] = IH_TYPE_SCRIPT;
+    header[31] = IH_COMP_NONE;
+    for (i, byte) in "boot script".bytes().enumerate() {
+        header[32 + i] = byte;
+    }
+    let crc = util::crc32(&header);
+    header[4..8].copy_from_slice(&crc.to_be_bytes());
+
+    header.extend(data);
+    header
+}
+
+/// Create the boot flow of the target, if it declares one
+pub fn new(config: &Config) -> Result<Option<Boot>> {
+    let cfg = match &config.boot {
+        Some(cfg) => cfg.clone(),
+        None => return Ok(None),
+    };
+
+    let fdt = match &cfg.fdt {
+        Some(fdt) => Some(fdt.clone()),
+        None => match config.first_component("linux") {
+            Some(name) => {
+                let linux: LinuxConfig = config.component(&name)?;
+                match linux.dtbs.first() {
+                    Some(dtb) => Some(dtb.file_name()
+                        .context(error::IllFormedPath{ path: dtb.clone() })?
+                        .to_str().unwrap().to_string()),
+                    None => None,
+                }
+            },
+            None => None,
+        },
+    };
+
+    let mut work_dir = config.build_dir.clone();
+    work_dir.push("boot");
+    Ok(Some(Boot {
+        cfg: cfg,
+        fdt: fdt,
+        arch: config.toolchain.linux_arch.clone(),
+        target_name: config.target_name.clone(),
+        work_dir: work_dir,
+    }))
+}
+
+/// Write the boot script of the target for the fetched Linux sources, and
+/// the U-Boot environment if the target has U-Boot. Their paths are
+/// returned.
+pub fn run(config: &Config, interrupt: Interrupt) -> Result<Vec<PathBuf>> {
+    let boot = new(config)?.context(error::NoBootFlow{})?;
+    let name = config.first_component("linux").context(error::NoComponent{
+        component: "linux".to_string() })?;
+    let mut linux = linux::new(config, &name, interrupt.clone())?;
+    let version = linux.fetched_version()?.context(error::LinuxNotFetched{})?;
+
+    let mut paths = vec![boot.write_script(&version, linux.source_date_epoch())?];
+    if let Some(name) = config.first_component("uboot") {
+        let uboot = uboot::new(config, &name, interrupt)?;
+        paths.push(uboot.env_image(&boot.env())?);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_image_header() {
+        // Header of mkimage -A arm -O linux -T script -C none -n "boot script"
+        // at the same date
+        let image = script_image("echo hello\n", 2, 1_600_000_000);
+        let header = "270519565b237a925f5e100000000013000000000000000\
+            03db55e6f05020600626f6f7420736372697074000000000000000000000000000\
+            000000000000000";
+        let hex: String = image[..64].iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, header);
+        assert_eq!(image[64..72], [0, 0, 0, 11, 0, 0, 0, 0]);
+        assert_eq!(&image[72..], b"echo hello\n");
+    }
+
+    #[test]
+    fn slots_script() {
+        let cfg: BootConfig = toml::from_str(r#"
+            bootargs = "console=ttyS0,115200"
+            bootlimit = 2
+            slots = [
+                { name = "a", partition = 2, root = "/dev/mmcblk0p2" },
+                { name = "b", partition = 11, root = "/dev/mmcblk0p11" },
+            ]
+        "#).unwrap();
+        let boot = Boot {
+            cfg: cfg,
+            fdt: Some("board-{version}.dtb".to_string()),
+            arch: "arm".to_string(),
+            target_name: "Board".to_string(),
+            work_dir: PathBuf::new(),
+        };
+        // Partition numbers are hexadecimal
+        let expected = r#"# Boot script of the Board, generated by mktcb. Do not edit.
+
+if test -z "${slot}"; then setenv slot a; fi
+if test "${upgrade_available}" = "1"; then
+    if test "${bootcount}" = "0"; then setenv bootcount 1
+    elif test "${bootcount}" = "1"; then setenv bootcount 2
+    else
+        echo "Slot ${slot} failed to boot 2 times. Falling back."
+        if test "${slot}" = "a"; then setenv slot b
+        elif test "${slot}" = "b"; then setenv slot a
+        fi
+        setenv upgrade_available 0
+        setenv bootcount 0
+    fi
+    saveenv
+fi
+if test "${slot}" = "a"; then setenv bootpart 2; setenv rootdev /dev/mmcblk0p2; fi
+if test "${slot}" = "b"; then setenv bootpart b; setenv rootdev /dev/mmcblk0p11; fi
+setenv bootargs "console=ttyS0,115200 root=${rootdev}"
+
+load ${devtype} ${devnum}:${bootpart} ${kernel_addr_r} vmlinuz-5.4.1
+load ${devtype} ${devnum}:${bootpart} ${fdt_addr_r} dtbs/board-5.4.1.dtb
+bootz ${kernel_addr_r} - ${fdt_addr_r}
+"#;
+        assert_eq!(boot.script("5.4.1"), expected);
+    }
+}
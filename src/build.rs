@@ -0,0 +1,184 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use snafu::{ResultExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::component::Component;
+use crate::config::Config;
+use crate::interrupt::Interrupt;
+use crate::manifest;
+use crate::toolchain;
+
+/// File created in the build directory of a component once it was built
+/// successfully. Its modification time tells when the last build happened.
+const STAMP: &str = ".mktcb-build";
+
+type Components = BTreeMap<String, Box<dyn Component>>;
+
+/// What must be done for a component, and why
+struct Step {
+    name: String,
+    fetch: bool,
+    target: String,
+    reason: String,
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.fetch {
+            write!(f, "{}: fetch, make {} ({})", self.name, self.target, self.reason)
+        } else {
+            write!(f, "{}: make {} ({})", self.name, self.target, self.reason)
+        }
+    }
+}
+
+fn mtime(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn stamp_path(component: &dyn Component) -> PathBuf {
+    let mut path = component.build_dir().clone();
+    path.push(STAMP);
+    path
+}
+
+fn write_stamp(component: &dyn Component) -> Result<()> {
+    let dir = component.build_dir();
+    std::fs::create_dir_all(dir).context(error::CreateDirError{ path: dir.clone() })?;
+    let path = stamp_path(component);
+    std::fs::File::create(&path).context(error::CreateFileError{ path: path.clone() })?;
+    Ok(())
+}
+
+/// Order the components so that each one comes after the ones it depends on
+fn sort(components: &Components) -> Result<Vec<String>> {
+    fn visit(name: &str, components: &Components, visiting: &mut BTreeSet<String>,
+             order: &mut Vec<String>) -> Result<()> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        ensure!(visiting.insert(name.to_string()), error::DependencyCycle{
+            component: name.to_string() });
+        for input in components[name].inputs() {
+            visit(&input.component, components, visiting, order)?;
+        }
+        visiting.remove(name);
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    let mut visiting = BTreeSet::new();
+    for name in components.keys() {
+        visit(name, components, &mut visiting, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Retrieve the paths to the artifacts a component consumes
+fn input_paths(name: &str, components: &Components) -> Vec<PathBuf> {
+    components[name].inputs().iter()
+        .filter_map(|input| components[&input.component].artifacts().into_iter()
+            .find(|a| a.name == input.artifact))
+        .map(|a| a.path)
+        .collect()
+}
+
+/// Tell why a component that was already fetched must be re-built, if it
+/// must be at all
+fn staleness(component: &dyn Component, inputs: &[PathBuf], rebuilt: &[String]) -> Option<String> {
+    if let Some(input) = component.inputs().iter().find(|i| rebuilt.contains(&i.component)) {
+        return Some(format!("{} is re-built", input.component));
+    }
+    let stamp = match mtime(&stamp_path(component)) {
+        Some(stamp) => stamp,
+        None => return Some("never built".to_string()),
+    };
+    for artifact in component.artifacts() {
+        if ! artifact.path.exists() {
+            return Some(format!("{:#?} is missing", artifact.path));
+        }
+    }
+    let mut files = component.watched_files();
+    files.extend(inputs.iter().cloned());
+    for file in files {
+        if mtime(&file).map(|t| t > stamp).unwrap_or(false) {
+            return Some(format!("{:#?} changed", file));
+        }
+    }
+    None
+}
+
+/// Fetch and build all the components of the target, in the order of their
+/// dependencies. Only the components that are out-of-date are built. If
+/// dry_run is set, what would be done is printed instead.
+pub fn run(config: &Config, interrupt: Interrupt, dry_run: bool) -> Result<()> {
+    let mut components = Components::new();
+    for name in config.components.keys() {
+        components.insert(name.clone(), component::new(name, config, interrupt.clone())?);
+    }
+    let order = sort(&components)?;
+
+    // Work out the plan before doing anything, so it can be printed
+    let toolchain = toolchain::new(config)?;
+    let mut steps = Vec::new();
+    let mut rebuilt = Vec::new();
+    for name in &order {
+        let inputs = input_paths(name, &components);
+        let component = components.get_mut(name).unwrap();
+        let (fetch, reason) = if component.fetched_version()?.is_none() {
+            (true, Some("not fetched".to_string()))
+        } else if ! component.new_patches()?.is_empty() {
+            (true, Some("new library patches".to_string()))
+        } else {
+            (false, staleness(component.as_ref(), &inputs, &rebuilt))
+        };
+        match reason {
+            Some(reason) => {
+                rebuilt.push(name.clone());
+                steps.push(Step {
+                    name: name.clone(),
+                    fetch: fetch,
+                    target: component.default_target().to_string(),
+                    reason: reason,
+                });
+            },
+            None => info!("{} is up-to-date", name),
+        }
+    }
+
+    if dry_run {
+        if ! toolchain.is_fetched() {
+            println!("toolchain: fetch");
+        }
+        for step in &steps {
+            println!("{}", step);
+        }
+        if steps.is_empty() {
+            println!("Nothing to be done");
+        }
+        return Ok(());
+    }
+
+    toolchain.fetch()?;
+    for step in &steps {
+        info!("Building {}", step);
+        let component = components.get_mut(&step.name).unwrap();
+        if step.fetch {
+            component.fetch()?;
+        }
+        let started = SystemTime::now();
+        let make = component.make(&step.target, &toolchain)?;
+        write_stamp(component.as_ref())?;
+        manifest::record(config, component.as_mut(), "make", Some(&step.target), started, &make, &[])?;
+    }
+    Ok(())
+}
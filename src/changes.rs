@@ -0,0 +1,157 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use snafu::ResultExt;
+
+use crate::error::Result;
+use crate::error;
+
+/// Number of lines added and removed
+#[derive(Default, Clone, Copy)]
+pub struct Stat {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Summary of the changes brought by one or several incremental patches
+#[derive(Default)]
+pub struct Changes {
+    /// Per-file diffstat, indexed by the path of the file in the sources
+    pub files: BTreeMap<String, Stat>,
+    /// Subjects of the commits, in the order of the ChangeLog
+    pub subjects: Vec<String>,
+}
+
+impl Changes {
+    /// Account for the contents of a diff file (as published on kernel.org)
+    pub fn add_diff(&mut self, diff: &PathBuf) -> Result<()> {
+        let contents = std::fs::read(diff)
+            .context(error::FailedToRead{path: diff.clone()})?;
+        let text = String::from_utf8_lossy(&contents);
+
+        let mut current: Option<String> = None;
+        // The ---/+++ file headers come between "diff --git" and the first
+        // hunk. Within hunks, such lines are removed or added lines.
+        let mut in_headers = false;
+        for line in text.lines() {
+            if let Some(paths) = line.strip_prefix("diff --git a/") {
+                // diff --git a/path b/path
+                let file = match paths.find(" b/") {
+                    Some(idx) => &paths[..idx],
+                    None => paths,
+                };
+                self.files.entry(file.to_string()).or_default();
+                current = Some(file.to_string());
+                in_headers = true;
+            } else if line.starts_with("@@") {
+                in_headers = false;
+            } else if in_headers {
+                continue;
+            } else if let Some(file) = &current {
+                let stat = self.files.get_mut(file).unwrap();
+                if line.starts_with('+') {
+                    stat.added += 1;
+                } else if line.starts_with('-') {
+                    stat.removed += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Extract the commit subjects of a kernel.org ChangeLog file. Each
+    /// commit is introduced by a "commit <sha>" line, followed by the
+    /// headers, an empty line and the indented commit message.
+    pub fn add_changelog(&mut self, changelog: &PathBuf) -> Result<()> {
+        let contents = std::fs::read(changelog)
+            .context(error::FailedToRead{path: changelog.clone()})?;
+        let text = String::from_utf8_lossy(&contents);
+
+        let mut in_headers = false;
+        let mut want_subject = false;
+        for line in text.lines() {
+            if line.starts_with("commit ") {
+                in_headers = true;
+                want_subject = false;
+            } else if in_headers && line.is_empty() {
+                in_headers = false;
+                want_subject = true;
+            } else if want_subject && ! line.trim().is_empty() {
+                self.subjects.push(line.trim().to_string());
+                want_subject = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compute the diffstat per subsystem. A subsystem is made of the first
+    /// two path components (e.g. drivers/net, arch/arm), or of the first one
+    /// for files that are not nested deeper (e.g. Makefile, kernel/).
+    pub fn subsystems(&self) -> BTreeMap<String, (usize, Stat)> {
+        let mut subsystems: BTreeMap<String, (usize, Stat)> = BTreeMap::new();
+        for (file, stat) in &self.files {
+            let components: Vec<&str> = file.split('/').collect();
+            let subsystem = if components.len() > 2 {
+                format!("{}/{}", components[0], components[1])
+            } else {
+                components[0].to_string()
+            };
+            let entry = subsystems.entry(subsystem).or_default();
+            entry.0 += 1;
+            entry.1.added += stat.added;
+            entry.1.removed += stat.removed;
+        }
+        subsystems
+    }
+}
+
+/// Determine whether a source file is compiled, by looking for the matching
+/// object file in the build directory.
+pub fn is_compiled(build_dir: &PathBuf, file: &str) -> bool {
+    if file.ends_with(".c") || file.ends_with(".S") {
+        let mut obj = build_dir.clone();
+        obj.push(file);
+        obj.set_extension("o");
+        obj.exists()
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffstat() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("mktcb-changes-{}.diff", std::process::id()));
+        std::fs::write(&path, "\
+diff --git a/Makefile b/Makefile
+index 1111111..2222222 100644
+--- a/Makefile
++++ b/Makefile
+@@ -1,3 +1,3 @@
+--- a comment that starts like a header
++++ a comment that starts like a header
+ SUBLEVEL = 1
+diff --git a/kernel/fork.c b/kernel/fork.c
+--- a/kernel/fork.c
++++ b/kernel/fork.c
+@@ -10,2 +10,3 @@
++int added;
+-int removed;
++int replaced;
+").unwrap();
+        let mut changes = Changes::default();
+        changes.add_diff(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let makefile = changes.files["Makefile"];
+        assert_eq!((makefile.added, makefile.removed), (1, 1));
+        let fork = changes.files["kernel/fork.c"];
+        assert_eq!((fork.added, fork.removed), (2, 1));
+    }
+}
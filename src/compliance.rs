@@ -0,0 +1,262 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::fmt::Write;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, OptionExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::component::Provenance;
+use crate::config::Config;
+use crate::interrupt::Interrupt;
+use crate::linux;
+use crate::reproducible;
+use crate::toolchain;
+use crate::util;
+
+/// Sources that are part of the bundle: those of a component, or of an
+/// out-of-tree module
+struct Sources {
+    name: String,
+    version: String,
+    description: String,
+    provenance: Provenance,
+    /// Configuration the sources were built with, if any
+    config: Option<PathBuf>,
+}
+
+/// File of the bundle, listed in its index
+struct Entry {
+    path: PathBuf,
+    description: String,
+}
+
+fn copy(from: &PathBuf, dir: &PathBuf, name: &PathBuf) -> Result<PathBuf> {
+    ensure!(from.is_file(), error::FileDoesNotExist{ path: from.clone() });
+    let mut to = dir.clone();
+    to.push(name);
+    let parent = to.parent().unwrap().to_path_buf();
+    std::fs::create_dir_all(&parent).context(error::CreateDirError{ path: parent.clone() })?;
+    std::fs::copy(from, &to).context(error::CopyFailed{ from: from.clone(), to: to.clone() })?;
+    Ok(to)
+}
+
+fn file_name(path: &PathBuf) -> String {
+    path.file_name().unwrap().to_string_lossy().to_string()
+}
+
+/// Create an archive of a commit of a git repository, as it was before
+/// the library patches were applied
+fn git_archive(git_dir: &PathBuf, commit: &str, prefix: &str, output: &PathBuf) -> Result<()> {
+    let status = Command::new("git")
+        .stdin(Stdio::null())
+        .arg("-C").arg(git_dir)
+        .arg("archive")
+        .arg("--format=tar.gz")
+        .arg(format!("--prefix={}/", prefix))
+        .arg("-o").arg(output)
+        .arg(commit)
+        .status()
+        .context(error::ProgFailed{ proc: "git".to_string() })?;
+    ensure!(status.success(), error::GitFailed{
+        url: git_dir.to_string_lossy().to_string(),
+        rev: commit.to_string(),
+    });
+    Ok(())
+}
+
+/// Gather the sources of a component (or module) in a directory of the
+/// bundle: the original sources, the library patches that were applied on
+/// them, in order, and the configuration they were built with. Patches keep
+/// their path in the library.
+fn gather(config: &Config, sources: &Sources, dir: &PathBuf) -> Result<Vec<Entry>> {
+    let provenance = &sources.provenance;
+    let patches = provenance.patches.as_ref().context(error::PatchesNotRecorded{
+        component: sources.name.clone() })?;
+    let prefix = format!("{}-{}", sources.name, sources.version);
+    let mut entries = Vec::new();
+
+    let mut original = dir.clone();
+    original.push("original");
+    std::fs::create_dir_all(&original).context(error::CreateDirError{ path: original.clone() })?;
+    for (url, file) in &provenance.downloads {
+        if let Some(file) = file {
+            entries.push(Entry {
+                path: copy(file, &original, &PathBuf::from(file_name(file)))?,
+                description: format!("Downloaded from {}", url),
+            });
+        } else if let (Some(git_dir), Some(commit)) = (&provenance.git_dir, &provenance.commit) {
+            let mut path = original.clone();
+            path.push(format!("{}.tar.gz", prefix));
+            git_archive(git_dir, commit, &prefix, &path)?;
+            entries.push(Entry {
+                path: path,
+                description: format!("Commit {} of {}", commit, url),
+            });
+        }
+    }
+    if let Some(local) = &provenance.local {
+        entries.push(Entry {
+            path: copy(local, &original, &PathBuf::from(format!("{}.tar.gz", prefix)))?,
+            description: "Sources maintained in the library, as they were fetched".to_string(),
+        });
+    }
+
+    // The library may have changed since the sources were fetched: patches
+    // must be the ones that were applied
+    let mut series = String::new();
+    for (index, patch) in patches.iter().enumerate() {
+        let name = patch.path.strip_prefix(&config.lib_dir).unwrap_or(&patch.path).to_path_buf();
+        let path = copy(&patch.path, dir, &name)?;
+        match &patch.sha256 {
+            Some(sha256) => ensure!(&util::checksum("sha256sum", &path)? == sha256,
+                error::PatchChanged{ patch: patch.path.clone(), component: sources.name.clone() }),
+            None => warn!("The SHA-256 of {:#?} was not recorded when it was applied on {}. \
+                It cannot be checked.", patch.path, sources.name),
+        }
+        entries.push(Entry {
+            path: path,
+            description: format!("Library patch #{}", index + 1),
+        });
+        series.push_str(&format!("{}\n", name.display()));
+    }
+    if ! patches.is_empty() {
+        let mut path = dir.clone();
+        path.push("series");
+        std::fs::write(&path, series).context(error::FailedToWrite{ path: path.clone() })?;
+        entries.push(Entry {
+            path: path,
+            description: "Order in which the patches are applied".to_string(),
+        });
+    }
+
+    if let Some(config) = &sources.config {
+        entries.push(Entry {
+            path: copy(config, dir, &PathBuf::from("config"))?,
+            description: "Build configuration (.config)".to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Collect the sources of all the components of the target that were
+/// fetched, including the out-of-tree modules of Linux
+fn collect(config: &Config, interrupt: Interrupt) -> Result<Vec<Sources>> {
+    let mut all = Vec::new();
+    for name in config.components.keys() {
+        let kind = config.component_kind(name)?;
+        let mut agent = component::new(name, config, interrupt.clone())?;
+        let version = match agent.fetched_version()? {
+            Some(version) => version,
+            None => {
+                warn!("{} was not fetched. Its sources are not part of the bundle.", name);
+                continue;
+            },
+        };
+        let mut dot_config = agent.build_dir().clone();
+        dot_config.push(".config");
+        all.push(Sources {
+            name: name.clone(),
+            version: version,
+            description: format!("Component of kind '{}'", kind),
+            provenance: agent.provenance()?,
+            config: if dot_config.is_file() { Some(dot_config) } else { None },
+        });
+
+        if kind == "linux" {
+            let linux = linux::new(config, name, interrupt.clone())?;
+            for (module, version, provenance) in linux.module_provenances()? {
+                all.push(Sources {
+                    name: module.clone(),
+                    version: version,
+                    description: format!("Out-of-tree module of {}", name),
+                    provenance: provenance,
+                    config: None,
+                });
+            }
+        }
+    }
+    Ok(all)
+}
+
+/// Write the index of the bundle, that describes each of its files
+fn index(config: &Config, bundle: &PathBuf, name: &str, epoch: u64,
+         sections: &[(&Sources, Vec<Entry>)]) -> Result<String> {
+    let mut index = String::new();
+    let _ = writeln!(index, "Corresponding sources of the {} ({})", config.target_name, name);
+    let _ = writeln!(index, "Generated by mktcb {} on {}\n", env!("CARGO_PKG_VERSION"),
+        util::rfc2822(epoch));
+    let _ = writeln!(index, "Each directory holds the original sources of a component, the \
+        library\npatches that were applied on them, in the order of its series file \
+        (patch -p1),\nand the configuration it was built with. Checksums are SHA-256.\n");
+
+    for (sources, entries) in sections {
+        let _ = writeln!(index, "{} {} - {}", sources.name, sources.version, sources.description);
+        for entry in entries {
+            let relative = entry.path.strip_prefix(bundle).unwrap_or(&entry.path);
+            let _ = writeln!(index, "  {}\n    {}\n    {}", relative.display(), entry.description,
+                util::checksum("sha256sum", &entry.path)?);
+        }
+        let _ = writeln!(index);
+    }
+
+    let toolchain = toolchain::new(config)?;
+    let archive = toolchain.archive()?;
+    let _ = writeln!(index, "Toolchain {}", toolchain.name());
+    let _ = writeln!(index, "  Downloaded from {}", toolchain.url());
+    if archive.is_file() {
+        let _ = writeln!(index, "  {}", util::checksum("sha256sum", &archive)?);
+    }
+    let _ = writeln!(index, "  Its sources are provided by its vendor, along with the binaries.");
+    Ok(index)
+}
+
+/// Gather the corresponding sources of the components of the target into
+/// a self-contained archive, with an index. They are the ones recorded when
+/// the components were fetched, whatever the library holds today. The path
+/// to the archive is returned.
+pub fn run(config: &Config, interrupt: Interrupt, output: Option<PathBuf>) -> Result<PathBuf> {
+    let all = collect(config, interrupt)?;
+    let versions: Vec<String> = all.iter()
+        .map(|s| format!("{}-{}", s.name, s.version))
+        .collect();
+    let name = format!("{}-sources-{}", config.target, versions.join("+"));
+    let epoch = reproducible::source_date_epoch(None);
+
+    let mut work_dir = config.build_dir.clone();
+    work_dir.push("compliance");
+    let mut bundle = work_dir.clone();
+    bundle.push(&name);
+    if bundle.exists() {
+        std::fs::remove_dir_all(&bundle).context(error::RemoveDirError{ path: bundle.clone() })?;
+    }
+
+    let mut sections = Vec::new();
+    for sources in &all {
+        let mut dir = bundle.clone();
+        dir.push(format!("{}-{}", sources.name, sources.version));
+        info!("Gathering the sources of {} {}", sources.name, sources.version);
+        sections.push((sources, gather(config, sources, &dir)?));
+    }
+    let mut index_path = bundle.clone();
+    index_path.push("INDEX");
+    std::fs::create_dir_all(&bundle).context(error::CreateDirError{ path: bundle.clone() })?;
+    std::fs::write(&index_path, index(config, &bundle, &name, epoch, &sections)?)
+        .context(error::FailedToWrite{ path: index_path.clone() })?;
+
+    let output = output.unwrap_or_else(|| {
+        let mut path = work_dir.clone();
+        path.push(format!("{}.tar.gz", name));
+        path
+    });
+    info!("Creating compliance bundle {:#?}", output);
+    util::tar(&work_dir, &name, &output, epoch)?;
+    Ok(output)
+}
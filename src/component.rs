@@ -0,0 +1,242 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Command;
+
+use snafu::{ResultExt, OptionExt, ensure};
+
+use crate::error::Result;
+use crate::error;
+use crate::config::Config;
+use crate::interrupt::Interrupt;
+use crate::toolchain::Toolchain;
+use crate::version_file::AppliedPatch;
+use crate::generic;
+use crate::linux;
+use crate::optee;
+use crate::reproducible;
+use crate::tfa;
+use crate::uboot;
+
+/// File produced by a component, that other components may consume
+#[derive(Clone)]
+pub struct Artifact {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Artifact of another component that a component consumes. It is passed
+/// to the build of the component as the make variable var.
+#[derive(Clone)]
+pub struct Input {
+    pub component: String,
+    pub artifact: String,
+    pub var: String,
+}
+
+/// Origin of the sources of a component, as recorded by the build manifest
+#[derive(Default)]
+pub struct Provenance {
+    /// URLs the sources were retrieved from, in order, with the file each
+    /// one was downloaded as. Git repositories have no such file.
+    pub downloads: Vec<(url::Url, Option<PathBuf>)>,
+    /// Commit that was checked out, for sources retrieved with git
+    pub commit: Option<String>,
+    /// Repository the commit was checked out in
+    pub git_dir: Option<PathBuf>,
+    /// Archive of the directory of the library the sources were copied
+    /// from, as it was when they were fetched, if they are part of the
+    /// library
+    pub local: Option<PathBuf>,
+    /// Library patches applied on the sources, in order, as recorded when
+    /// they were fetched. None if they were fetched by a version of mktcb
+    /// that did not record them.
+    pub patches: Option<Vec<AppliedPatch>>,
+}
+
+/// Invocation of make, as run by run_make(). The build manifest records
+/// them.
+#[derive(Clone)]
+pub struct MakeInvocation {
+    pub command: Vec<String>,
+    /// Variables set in the environment of make, on top of the one of mktcb
+    pub env: BTreeMap<String, String>,
+}
+
+/// Packages built by a component, with the invocations of make that built
+/// them
+pub struct Packages {
+    pub files: Vec<PathBuf>,
+    pub make: Vec<MakeInvocation>,
+}
+
+/// Operations that mktcb performs on each component of a target (Linux,
+/// U-Boot, ...). Operations that make no sense for a given component are
+/// reported as not supported.
+pub trait Component {
+    /// Name of the component, as declared by the target
+    fn name(&self) -> &str;
+
+    /// Retrieve the sources of the component, or update them
+    fn fetch(&mut self) -> Result<()>;
+
+    /// Check whether an update of the sources is available
+    fn check_update(&mut self) -> Result<bool> {
+        not_supported(self.name(), "check-update")
+    }
+
+    /// Re-generate the build configuration from the library
+    fn reconfigure(&self) -> Result<()> {
+        not_supported(self.name(), "reconfigure")
+    }
+
+    /// Run a make target in the sources of the component. The invocations
+    /// of make are returned.
+    fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>>;
+
+    /// Build the Debian packages of the component
+    fn package(&mut self, _toolchain: &Toolchain) -> Result<Packages> {
+        not_supported(self.name(), "package")
+    }
+
+    /// Build the RPM packages of the component
+    fn rpm_package(&mut self, _toolchain: &Toolchain) -> Result<Packages> {
+        not_supported(self.name(), "rpmpkg")
+    }
+
+    /// Retrieve the version of the sources that were fetched, if any
+    fn fetched_version(&mut self) -> Result<Option<String>>;
+
+    /// Library patches that were added since the sources were fetched.
+    /// Fetching the sources again applies them.
+    fn new_patches(&self) -> Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    fn build_dir(&self) -> &PathBuf;
+
+    fn patches_dir(&self) -> &PathBuf;
+
+    /// Make target that builds the artifacts of the component
+    fn default_target(&self) -> &str {
+        "all"
+    }
+
+    /// Files that the build depends on, besides input artifacts (e.g. the
+    /// version file, that is rewritten when the sources are updated, or the
+    /// library configuration). The component is re-built when they change.
+    fn watched_files(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Version file of the sources, if the component fetches any
+    fn version_file(&self) -> Option<&PathBuf> {
+        None
+    }
+
+    /// Date of the sources (UNIX timestamp), that builds record instead of
+    /// the current time. It was recorded in the version file at fetch.
+    fn source_date_epoch(&self) -> u64 {
+        reproducible::source_date_epoch(self.version_file())
+    }
+
+    /// Where the sources that were fetched come from
+    fn provenance(&self) -> Result<Provenance> {
+        Ok(Provenance::default())
+    }
+
+    /// Files produced by the build, that other components may consume
+    fn artifacts(&self) -> Vec<Artifact> {
+        Vec::new()
+    }
+
+    /// Artifacts of other components that are needed to build this one
+    fn inputs(&self) -> Vec<Input> {
+        Vec::new()
+    }
+
+    /// Provide the path to an input artifact, as the make variable var
+    fn add_input(&mut self, _var: &str, _path: PathBuf) {}
+}
+
+fn not_supported<T>(name: &str, operation: &str) -> Result<T> {
+    error::NotSupported{
+        component: name.to_string(),
+        operation: operation.to_string(),
+    }.fail()
+}
+
+/// Components that are not named after their kind (e.g. [component.spl] of
+/// kind uboot) are given their own sub-directory, so that several components
+/// of the same kind can co-exist.
+pub fn get_dir(base_dir: &PathBuf, name: &str, kind: &str) -> PathBuf {
+    let mut path = base_dir.clone();
+    if name != kind {
+        path.push(name);
+    }
+    path
+}
+
+/// Run the provided make command on a make target. The invocation is
+/// returned, so the build manifest can record it.
+pub fn run_make(make_cmd: &mut Command, make_target: &str) -> Result<MakeInvocation> {
+    make_cmd.arg("--").arg(make_target);
+    let lossy = |s: &std::ffi::OsStr| s.to_string_lossy().to_string();
+    let invocation = MakeInvocation {
+        command: std::iter::once(make_cmd.get_program())
+            .chain(make_cmd.get_args())
+            .map(lossy)
+            .collect(),
+        env: make_cmd.get_envs()
+            .filter_map(|(var, value)| value.map(|v| (lossy(var), lossy(v))))
+            .collect(),
+    };
+
+    let status = make_cmd
+        .status()
+        .context(error::ProgFailed{ proc: "make".to_string() })?;
+    ensure!(status.success(), error::MakeFailed{
+        target: make_target.to_string() });
+    Ok(invocation)
+}
+
+/// Pass input artifacts to a make command. They must have been built.
+pub fn add_inputs(make_cmd: &mut Command, inputs: &[(String, PathBuf)]) -> Result<()> {
+    for (var, path) in inputs {
+        ensure!(path.is_file(), error::FileDoesNotExist{path: path.clone()});
+        make_cmd.arg(format!("{}={}", var, path.to_str().unwrap()));
+    }
+    Ok(())
+}
+
+/// Instantiate a component according to its kind
+fn create(name: &str, config: &Config, interrupt: Interrupt) -> Result<Box<dyn Component>> {
+    let kind = config.component_kind(name)?;
+    let component: Box<dyn Component> = match kind.as_str() {
+        "linux" => Box::new(linux::new(config, name, interrupt)?),
+        "uboot" => Box::new(uboot::new(config, name, interrupt)?),
+        "tfa" => Box::new(tfa::new(config, name, interrupt)?),
+        "optee" => Box::new(optee::new(config, name, interrupt)?),
+        // Other kinds are described by the library, or by the target itself
+        kind => Box::new(generic::new(config, name, kind, interrupt)?),
+    };
+    Ok(component)
+}
+
+/// Create the component that the target declares as name. It is provided
+/// with the artifacts of the components it depends on.
+pub fn new(name: &str, config: &Config, interrupt: Interrupt) -> Result<Box<dyn Component>> {
+    let mut component = create(name, config, interrupt.clone())?;
+    for input in component.inputs() {
+        let dependency = create(&input.component, config, interrupt.clone())?;
+        let artifact = dependency.artifacts().into_iter()
+            .find(|a| a.name == input.artifact)
+            .context(error::NoArtifact{
+                component: input.component.clone(),
+                artifact: input.artifact.clone(),
+            })?;
+        component.add_input(&input.var, artifact.path);
+    }
+    Ok(component)
+}
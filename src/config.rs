@@ -1,7 +1,8 @@
 /* This is part of mktcb - which is under the MIT License ********************/
 
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use snafu::{ResultExt, ensure};
+use snafu::{ResultExt, OptionExt, ensure};
 use clap::ArgMatches;
 use serde_derive::Deserialize;
 use serde::de;
@@ -10,42 +11,568 @@ use log::*;
 use crate::error::Result;
 use crate::error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     pub build_dir: PathBuf,
     pub lib_dir: PathBuf,
     pub download_dir: PathBuf,
     pub toolchain: ToolchainConfig,
-    pub linux: ComponentConfig,
-    pub uboot: ComponentConfig,
+    /// Raw configuration of the components of the target, indexed by their
+    /// names. Each component interprets its own configuration.
+    pub components: BTreeMap<String, toml::Value>,
     /// Pretty name of the target
     pub target_name: String,
     /// Stem of the target
     pub target: String,
     pub jobs: usize,
+    pub packaging: PackagingConfig,
+    pub repository: RepositoryConfig,
+    /// Layout of the disk image of the target, if it has one
+    pub image: Option<ImageConfig>,
+    /// Boot flow of the target, if U-Boot is to be scripted
+    pub boot: Option<BootConfig>,
+    /// FIT image of the target, if it has one
+    pub fit: Option<FitConfig>,
+    /// QEMU machine on which the builds are smoke tested, if any
+    pub qemu: Option<QemuConfig>,
+    /// Directory holding the private keys of the targets. It lives outside
+    /// of the build directory, so keys are never shipped along artifacts.
+    pub keys_dir: PathBuf,
 }
 
-#[derive(Debug, Deserialize)]
+impl Config {
+    /// Retrieve the first component (by name) of a given kind, if any
+    pub fn first_component(&self, kind: &str) -> Option<String> {
+        self.components.keys()
+            .find(|name| self.component_kind(name).map(|k| k == kind).unwrap_or(false))
+            .cloned()
+    }
+
+    /// Retrieve the kind of a component. It defaults to its name, so
+    /// [component.uboot] is a U-Boot component.
+    pub fn component_kind(&self, name: &str) -> Result<String> {
+        let value = self.components.get(name).context(error::NoComponent{
+            component: name.to_string() })?;
+        Ok(value.get("kind")
+            .and_then(|kind| kind.as_str())
+            .unwrap_or(name)
+            .to_string())
+    }
+
+    /// De-serialize the configuration of a component
+    pub fn component<T>(&self, name: &str) -> Result<T>
+    where
+        T: de::DeserializeOwned
+    {
+        let value = self.components.get(name).context(error::NoComponent{
+            component: name.to_string() })?;
+        value.clone().try_into().context(error::FailedToDeserComponent{
+            component: name.to_string() })
+    }
+
+    /// De-serialize the configuration of a component whose kind is defined
+    /// in the library (components/<kind>.toml), rather than by mktcb. The
+    /// settings of the target take precedence over the ones of the library.
+    /// Components of kind "make" are entirely defined by the target.
+    pub fn defined_component<T>(&self, name: &str, kind: &str) -> Result<T>
+    where
+        T: de::DeserializeOwned
+    {
+        if kind == "make" {
+            return self.component(name);
+        }
+        let mut path = self.lib_dir.clone();
+        path.push("components");
+        path.push(kind);
+        path.set_extension("toml");
+        ensure!(path.is_file(), error::UnknownComponentKind{
+            kind: kind.to_string() });
+
+        let file_contents = load_file(&path)?;
+        let mut definition = load_toml::<toml::value::Table>(&file_contents, &path)?;
+        let value = self.components.get(name).context(error::NoComponent{
+            component: name.to_string() })?;
+        if let Some(table) = value.as_table() {
+            for (key, val) in table {
+                if key != "kind" {
+                    definition.insert(key.clone(), val.clone());
+                }
+            }
+        }
+        toml::Value::Table(definition).try_into()
+            .context(error::FailedToDeserComponent{ component: name.to_string() })
+    }
+
+    /// Load a toolchain from the library, that is not the main toolchain
+    /// of the target
+    pub fn load_toolchain(&self, name: &str) -> Result<ToolchainConfig> {
+        load_toolchain_config(&self.lib_dir, name)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct ToolchainConfig {
     pub url: String,
     pub linux_arch: String,
     pub uboot_arch: String,
     pub debian_arch: String,
+    /// Defaults to the RPM architecture matching debian_arch
+    pub rpm_arch: Option<String>,
     pub cross_compile: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ComponentConfig {
+#[derive(Debug, Deserialize, Clone)]
+pub struct LinuxConfig {
+    pub version: String,
+    pub config: Option<PathBuf>,
+    /// Out-of-tree modules, built against the kernel
+    #[serde(default)]
+    pub modules: Vec<ModuleConfig>,
+    /// Packages produced by bindeb-pkg that are to be shipped (image,
+    /// headers, libc-dev, dbg). Defaults to all the produced ones.
+    pub packages: Option<Vec<String>>,
+    /// Device trees of the board, relative to arch/<arch>/boot/dts. When
+    /// provided, they are shipped in their own package.
+    #[serde(default)]
+    pub dtbs: Vec<PathBuf>,
+    /// Kernel image, relative to arch/<arch>/boot. Defaults to the usual
+    /// one of the architecture (zImage, Image, bzImage).
+    pub image: Option<String>,
+    /// Sign the out-of-tree modules with the module signing key of the
+    /// target, as the kernel does for the in-tree ones
+    #[serde(default)]
+    pub sign_modules: bool,
+    /// Signing of the kernel image for EFI secure boot
+    pub efi: Option<EfiConfig>,
+}
+
+/// Authenticode signing of the kernel image (EFI stub), and unified kernel
+/// image. Paths are relative to the library. Images are signed by
+/// osslsigncode, and unified kernel images are built by ukify.
+#[derive(Debug, Deserialize, Clone)]
+pub struct EfiConfig {
+    /// Private key (PEM) whose certificate is enrolled in the db
+    pub key: PathBuf,
+    /// Certificate (PEM) of the key
+    pub cert: PathBuf,
+    /// Unified kernel image to be built, if any
+    pub uki: Option<UkiConfig>,
+}
+
+/// Unified kernel image: an EFI stub holding the kernel, its command line,
+/// initrd and device tree. The image and the kernel it holds are signed.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UkiConfig {
+    /// EFI stub (e.g. linuxaa64.efi.stub of systemd), relative to the
+    /// library
+    pub stub: PathBuf,
+    pub cmdline: Option<String>,
+    /// Initial ramdisk, relative to the library
+    pub initrd: Option<PathBuf>,
+    /// Device tree, relative to arch/<arch>/boot/dts. Defaults to the first
+    /// one of the kernel, if any.
+    pub dtb: Option<PathBuf>,
+}
+
+/// Out-of-tree kernel module. Its sources are either a directory of the
+/// library (path), an archive (url) or a git repository (git). In the
+/// strings, {version} is replaced by the version.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModuleConfig {
+    pub name: String,
+    pub version: String,
+    /// Path to the sources, relative to the library
+    pub path: Option<PathBuf>,
+    /// URL of an archive (.tar.*) of the sources
+    pub url: Option<String>,
+    /// URL of a git repository
+    pub git: Option<String>,
+    /// Revision of the git repository. Defaults to the version.
+    pub rev: Option<String>,
+    /// Directory in which the archive unpacks. Defaults to <name>-<version>.
+    pub dir: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UbootConfig {
     pub version: String,
     pub config: Option<PathBuf>,
+    /// Files produced by the U-Boot build that are shipped in the
+    /// u-boot-<target> Debian package
+    #[serde(default)]
+    pub artifacts: Vec<ArtifactConfig>,
+    /// Device on which artifacts are flashed when the package is installed
+    pub boot_device: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
+pub struct ArtifactConfig {
+    /// Path to the file, relative to the U-Boot build directory
+    pub file: PathBuf,
+    /// Offset (in bytes) at which the file is to be written on the boot
+    /// device. If not specified, the file is installed but never flashed.
+    pub offset: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TfaConfig {
+    pub version: String,
+    /// TF-A platform to be built (PLAT= make variable)
+    pub platform: String,
+    /// Build TF-A in debug mode (DEBUG=1)
+    #[serde(default)]
+    pub debug: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OpteeConfig {
+    pub version: String,
+    /// OP-TEE platform to be built (PLATFORM= make variable)
+    pub platform: String,
+    /// Build a 64-bits OP-TEE core (CFG_ARM64_core=y)
+    #[serde(default)]
+    pub arm64: bool,
+    /// Name of the toolchain used to build 32-bits code when the core is
+    /// 64-bits
+    pub toolchain32: Option<String>,
+}
+
+/// How the revision of the packages built by mktcb is computed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RevisionScheme {
+    /// The version of the packages is the upstream one. This is the default,
+    /// so that the packages of existing targets keep their versions, and
+    /// upgrade as they always did. Packaging the same version again warns,
+    /// as the packages cannot be told apart.
+    #[default]
+    Fixed,
+    /// An auto-incremented build number is used as the Debian revision
+    /// (<version>-<N>)
+    Build,
+    /// The target and an auto-incremented build number are appended to the
+    /// version (<version>+<target><N>)
+    Target,
+}
+
+/// Metadata of the packages built by mktcb. It is read from packaging.toml
+/// in the library, and from the [packaging] section of the target, that
+/// takes precedence.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct PackagingConfig {
+    /// Defaults to the MAINTAINER environment variable
+    pub maintainer: Option<String>,
+    #[serde(default)]
+    pub revision: RevisionScheme,
+    /// Overrides the section of all the packages
+    pub section: Option<String>,
+    /// Overrides the priority of all the packages
+    pub priority: Option<String>,
+    /// Added to the dependencies of all the packages
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    /// Template of the one-line description of the packages. {summary},
+    /// {package}, {version}, {target} and {name} are replaced.
+    pub description: Option<String>,
+    pub epoch: Option<u32>,
+}
+
+fn default_suite() -> String {
+    "stable".to_string()
+}
+
+fn default_repo_component() -> String {
+    "main".to_string()
+}
+
+/// APT repository in which the packages are published. It is read from
+/// repository.toml in the library, and from the [repository] section of
+/// the target, that takes precedence.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RepositoryConfig {
+    #[serde(default = "default_suite")]
+    pub suite: String,
+    #[serde(default = "default_repo_component")]
+    pub component: String,
+    pub origin: Option<String>,
+    pub label: Option<String>,
+    /// GnuPG key used to sign the repository. It is not signed otherwise.
+    pub key: Option<String>,
+    /// Number of versions of each package that are kept. All of them are
+    /// kept otherwise.
+    pub keep: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionTable {
+    #[default]
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filesystem {
+    Fat,
+    Ext4,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionContent {
+    /// The filesystem is left empty
+    #[default]
+    Empty,
+    /// Kernel image, device trees, and the files of the partition
+    Boot,
+    /// Contents of a root filesystem tarball
+    Rootfs,
+}
+
+/// Layout of the disk image of a target (SD card, eMMC). Sizes and offsets
+/// are expressed in bytes, optionally suffixed by K, M or G.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub table: PartitionTable,
+    pub size: String,
+    #[serde(default)]
+    pub partitions: Vec<PartitionConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PartitionConfig {
+    pub label: Option<String>,
+    /// The partition is not formatted if not specified
+    pub filesystem: Option<Filesystem>,
+    /// Defaults to the first MiB boundary after the previous partition (or
+    /// the raw U-Boot artifacts)
+    pub start: Option<String>,
+    /// Defaults to the remaining space of the image
+    pub size: Option<String>,
+    #[serde(default)]
+    pub bootable: bool,
+    #[serde(default)]
+    pub content: PartitionContent,
+    /// Tarball of the root filesystem, relative to the library
+    pub rootfs: Option<PathBuf>,
+    /// Additional files of the library to be copied in the partition
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+}
+
+/// Root filesystem that U-Boot may boot, for A/B updates
+#[derive(Debug, Deserialize, Clone)]
+pub struct BootSlotConfig {
+    pub name: String,
+    /// Partition holding the kernel of the slot
+    pub partition: u32,
+    /// Root device passed to the kernel (e.g. /dev/mmcblk0p2)
+    pub root: String,
+}
+
+fn default_bootlimit() -> u32 {
+    3
+}
+
+/// How U-Boot boots the kernel. It is rendered as a boot script (boot.scr)
+/// and a U-Boot environment (uboot.env). In file names, {version} is
+/// replaced by the Linux version.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BootConfig {
+    /// Kernel image, relative to the boot partition. Defaults to
+    /// vmlinuz-{version}, as installed by the linux-image package.
+    pub kernel: Option<String>,
+    /// Device tree, relative to the dtbs/ directory of the boot partition.
+    /// Defaults to the first device tree of Linux. Without any, the device
+    /// tree of U-Boot is passed to the kernel.
+    pub fdt: Option<String>,
+    /// Initial ramdisk, relative to the boot partition
+    pub initrd: Option<String>,
+    #[serde(default)]
+    pub bootargs: String,
+    /// Load addresses. They default to the ones of the U-Boot environment
+    /// (kernel_addr_r, fdt_addr_r, ramdisk_addr_r).
+    pub kernel_addr: Option<String>,
+    pub fdt_addr: Option<String>,
+    pub ramdisk_addr: Option<String>,
+    /// U-Boot command that boots the kernel. Defaults to bootz on arm,
+    /// booti on other architectures.
+    pub command: Option<String>,
+    /// Slots for A/B updates. Without any, the kernel is loaded from the
+    /// partition the script was loaded from.
+    #[serde(default)]
+    pub slots: Vec<BootSlotConfig>,
+    /// Number of attempts to boot an upgraded slot before falling back. It
+    /// must be at least 1.
+    #[serde(default = "default_bootlimit")]
+    pub bootlimit: u32,
+    /// Variables of the U-Boot environment, on top of the default ones
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+fn default_hashes() -> Vec<String> {
+    vec!["sha256".to_string()]
+}
+
+fn default_fit_algo() -> String {
+    "sha256,rsa2048".to_string()
+}
+
+/// FIT image (.itb) holding the kernel, the device trees and an optional
+/// initramfs. It is signed when a key is provided.
+#[derive(Debug, Deserialize, Clone)]
+pub struct FitConfig {
+    pub description: Option<String>,
+    /// Load and entry addresses of the kernel
+    pub load: String,
+    pub entry: String,
+    /// Initramfs, relative to the library
+    pub initramfs: Option<PathBuf>,
+    /// Algorithms of the hash nodes of each image
+    #[serde(default = "default_hashes")]
+    pub hashes: Vec<String>,
+    /// Directory of the library holding the keys, as mkimage expects them
+    /// (<key>.key and <key>.crt for RSA)
+    pub keys: Option<PathBuf>,
+    /// Name of the key that signs the configurations
+    pub key: Option<String>,
+    #[serde(default = "default_fit_algo")]
+    pub algo: String,
+}
+
+fn default_qemu_memory() -> String {
+    "512M".to_string()
+}
+
+fn default_boot_pattern() -> String {
+    "Freeing unused kernel".to_string()
+}
+
+fn default_boot_timeout() -> u64 {
+    120
+}
+
+/// QEMU machine that emulates the target closely enough to boot its kernel
+/// (mktcb test boot)
+#[derive(Debug, Deserialize, Clone)]
+pub struct QemuConfig {
+    /// Emulator. Defaults to qemu-system-arm or qemu-system-aarch64,
+    /// depending on the architecture of the toolchain.
+    pub qemu: Option<String>,
+    pub machine: String,
+    pub cpu: Option<String>,
+    #[serde(default = "default_qemu_memory")]
+    pub memory: String,
+    /// Device tree of Linux passed to the kernel, by file name (e.g.
+    /// sun8i-h3-nanopi-r1.dtb). QEMU provides its own one otherwise.
+    pub dtb: Option<String>,
+    /// Initramfs, relative to the library
+    pub initramfs: Option<PathBuf>,
+    /// Kernel command line
+    #[serde(default)]
+    pub append: String,
+    /// U-Boot binary, relative to the build directory of U-Boot (e.g.
+    /// u-boot.bin). When set, QEMU runs U-Boot as its firmware, instead of
+    /// the kernel.
+    pub uboot: Option<PathBuf>,
+    /// Interface through which the disk image (mktcb image) is attached,
+    /// if it is (e.g. sd, virtio)
+    pub drive: Option<String>,
+    /// Console output telling that the boot succeeded
+    #[serde(default = "default_boot_pattern")]
+    pub pattern: String,
+    /// Seconds after which the boot is considered failed
+    #[serde(default = "default_boot_timeout")]
+    pub timeout: u64,
+    /// Additional arguments of QEMU
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Component that is built with make, and that is entirely described by
+/// TOML. In the strings, {version} is replaced by the version.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MakeConfig {
+    /// Pretty name of the component, for messages
+    pub name: Option<String>,
+    pub version: String,
+    /// URL of an archive (.tar.*) of the sources
+    pub url: Option<String>,
+    /// URL of a git repository, to be used instead of an archive
+    pub git: Option<String>,
+    /// Revision of the git repository. Defaults to the version.
+    pub rev: Option<String>,
+    /// Directory in which the archive unpacks. Defaults to <kind>-<version>.
+    pub dir: Option<String>,
+    /// Name of the toolchain of the library to be used, when it is not the
+    /// toolchain of the target (e.g. for a co-processor)
+    pub toolchain: Option<String>,
+    /// Pass the Linux architecture of the toolchain as ARCH=
+    #[serde(default)]
+    pub arch: bool,
+    /// Pass the prefix of the toolchain as CROSS_COMPILE=
+    #[serde(default = "default_true")]
+    pub cross_compile: bool,
+    /// Make variable through which the build directory is passed (e.g. O).
+    /// Without it, the component is built in its source tree.
+    pub build_dir_var: Option<String>,
+    /// Make target that builds the artifacts. Defaults to all.
+    pub target: Option<String>,
+    /// Additional make variables
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default)]
+    pub artifacts: Vec<MakeArtifactConfig>,
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MakeArtifactConfig {
+    pub name: String,
+    /// Path to the file, relative to the build directory
+    pub file: PathBuf,
+}
+
+/// Artifact of another component, passed to the build as the make
+/// variable var
+#[derive(Debug, Deserialize, Clone)]
+pub struct InputConfig {
+    pub component: String,
+    pub artifact: String,
+    pub var: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
 struct TargetConfig {
     toolchain: String,
     name: String,
-    linux: ComponentConfig,
-    uboot: ComponentConfig,
+    /// Components declared as [component.<name>]
+    #[serde(default)]
+    component: BTreeMap<String, toml::Value>,
+    /// Components declared at the top-level of the target ([linux],
+    /// [uboot], ...). They are merged with the other components.
+    linux: Option<toml::Value>,
+    uboot: Option<toml::Value>,
+    tfa: Option<toml::Value>,
+    optee: Option<toml::Value>,
+    packaging: Option<toml::value::Table>,
+    repository: Option<toml::value::Table>,
+    image: Option<ImageConfig>,
+    boot: Option<BootConfig>,
+    fit: Option<FitConfig>,
+    qemu: Option<QemuConfig>,
 }
 
 
@@ -71,12 +598,12 @@ where
 /// Once we have loaded a target configuration, the config paths must be
 /// updated to reflect their actual location. This function does exactly
 /// this, and makes sure the file is a valid one
-fn make_config_path(library: &PathBuf, comp: &str, item: &ComponentConfig) -> Result<Option<PathBuf>> {
-    if let Some(cfg) = &item.config {
+pub fn make_config_path(library: &PathBuf, comp: &str, version: &str, config: &Option<PathBuf>) -> Result<Option<PathBuf>> {
+    if let Some(cfg) = config {
         let mut path = library.clone();
         path.push("configs");
         path.push(comp);
-        path.push(item.version.clone());
+        path.push(version);
         path.push(cfg);
 
         ensure!(path.exists(), error::FileDoesNotExist{ path: path.clone() });
@@ -100,12 +627,49 @@ fn load_target_config(library: &PathBuf, target: &str) -> Result<TargetConfig> {
 
     info!("Using target configuration at path {:#?}", path);
 
-    cfg.linux.config = make_config_path(library, "linux", &cfg.linux)?;
-    cfg.uboot.config = make_config_path(library, "uboot", &cfg.uboot)?;
+    let legacy = vec![
+        ("linux", cfg.linux.take()),
+        ("uboot", cfg.uboot.take()),
+        ("tfa", cfg.tfa.take()),
+        ("optee", cfg.optee.take()),
+    ];
+    for (name, value) in legacy {
+        if let Some(value) = value {
+            ensure!(! cfg.component.contains_key(name), error::DuplicateComponent{
+                component: name.to_string() });
+            cfg.component.insert(name.to_string(), value);
+        }
+    }
+    if let Some(boot) = &cfg.boot {
+        ensure!(boot.slots.is_empty() || boot.bootlimit > 0, error::ZeroBootLimit{});
+    }
 
     Ok(cfg)
 }
 
+/// Load a section that the library provides as <section>.toml (if any), and
+/// that the target may override with its own [<section>] (if any)
+fn load_layered_config<T>(library: &PathBuf, section: &str, target: Option<toml::value::Table>) -> Result<T>
+where
+    T: de::DeserializeOwned
+{
+    let mut path = library.clone();
+    path.push(section);
+    path.set_extension("toml");
+
+    let mut table = if path.is_file() {
+        let file_contents = load_file(&path)?;
+        load_toml::<toml::value::Table>(&file_contents, &path)?
+    } else {
+        toml::value::Table::new()
+    };
+    if let Some(target) = target {
+        table.extend(target);
+    }
+    toml::Value::Table(table).try_into().context(error::FailedToDeserSection{
+        section: section.to_string() })
+}
+
 fn load_toolchain_config(library: &PathBuf, toolchain: &str) -> Result<ToolchainConfig> {
     let mut path = library.clone();
     path.push("toolchains");
@@ -156,12 +720,27 @@ pub fn new(matches: &ArgMatches) -> Result<Config> {
                 .context(error::CanonFailed{dir: val.clone()})?
         },
         None => {
-            let mut build_dir = current_dir;
+            let mut build_dir = current_dir.clone();
             build_dir.push("build");
             build_dir
         }
     };
 
+    // Keys directory - if not provided by the user, default to
+    // ~/.mktcb/keys, so that it is shared by all the build directories
+    let keys_dir = match matches.value_of("keys_dir") {
+        Some(val) => current_dir.join(val),
+        None => {
+            let mut keys_dir = match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home),
+                None => current_dir.clone(),
+            };
+            keys_dir.push(".mktcb");
+            keys_dir.push("keys");
+            keys_dir
+        }
+    };
+
     // Target  - it is required, so it is guaranteed to have a value
     let target = matches.value_of("target").unwrap();
 
@@ -179,18 +758,26 @@ pub fn new(matches: &ArgMatches) -> Result<Config> {
 
     // ------------------------------------------------------------------------
     // Load the target TOML file
-    let target_cfg = load_target_config(&library, &target)?;
+    let mut target_cfg = load_target_config(&library, &target)?;
+    let packaging = load_layered_config(&library, "packaging", target_cfg.packaging.take())?;
+    let repository = load_layered_config(&library, "repository", target_cfg.repository.take())?;
 
 
     Ok(Config {
         build_dir: build_dir,
         download_dir: download_dir,
         toolchain: load_toolchain_config(&library, target_cfg.toolchain.as_str())?,
-        linux: target_cfg.linux,
-        uboot: target_cfg.uboot,
+        components: target_cfg.component,
         jobs: jobs,
         target: target.to_string(),
         target_name: target_cfg.name.clone(),
+        packaging: packaging,
+        repository: repository,
+        image: target_cfg.image.take(),
+        boot: target_cfg.boot.take(),
+        fit: target_cfg.fit.take(),
+        qemu: target_cfg.qemu.take(),
+        keys_dir: keys_dir,
         lib_dir: library,
     })
 }
@@ -0,0 +1,560 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use snafu::{ResultExt, ensure};
+use serde_json::Value;
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::changes;
+
+/// A component (Linux, U-Boot) whose fetched version is to be checked
+/// against the vulnerability database.
+pub struct Subject {
+    /// Name of the product, as it is expected to be found in the database
+    pub product: Product,
+    /// Version recorded in the version file
+    pub version: String,
+    /// Directory in which the component is built. It is used to determine
+    /// whether the affected files are actually compiled.
+    pub build_dir: PathBuf,
+    /// Directory that holds the library patches of the component
+    pub patches_dir: PathBuf,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Product {
+    Linux,
+    Uboot,
+}
+
+impl std::fmt::Display for Product {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Product::Linux => write!(f, "linux"),
+            Product::Uboot => write!(f, "u-boot"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Affected,
+    Unaffected,
+}
+
+/// Upper bound of a version range
+enum Bound {
+    /// No upper bound: all versions from the lower bound on are concerned
+    Unbounded,
+    /// All versions strictly lower than the provided one are concerned
+    Before(String),
+    /// All versions of the stable series X.Y are concerned (X.Y.*)
+    Series(String),
+}
+
+/// A rule states that versions in [from, until] have a given status
+struct Rule {
+    status: Status,
+    from: String,
+    until: Bound,
+}
+
+/// Vulnerability, normalized from one of the supported database formats
+struct Advisory {
+    id: String,
+    summary: String,
+    product: Product,
+    default: Status,
+    rules: Vec<Rule>,
+    files: Vec<String>,
+}
+
+/// Version number, as found in Linux (X.Y.Z) or U-Boot (YYYY.MM[-rcN])
+/// versions. A release candidate always precedes its final release.
+struct Version {
+    numbers: Vec<u64>,
+    rc: Option<u64>,
+}
+
+/// Versions are equal when they compare so: trailing zeros do not matter
+/// (5.4 is 5.4.0)
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = std::cmp::max(self.numbers.len(), other.numbers.len());
+        for i in 0..len {
+            let a = self.numbers.get(i).unwrap_or(&0);
+            let b = other.numbers.get(i).unwrap_or(&0);
+            match a.cmp(b) {
+                Ordering::Equal => {},
+                ord => return ord,
+            }
+        }
+        match (self.rc, other.rc) {
+            (None, None) => Ordering::Equal,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(a), Some(b)) => a.cmp(&b),
+        }
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Parse a textual version. Anything that does not look like a dotted
+/// version (e.g. git commit hashes) is rejected.
+fn parse_version(string: &str) -> Option<Version> {
+    let string = string.trim_start_matches('v');
+    let (base, rc) = match string.find("-rc") {
+        Some(idx) => (&string[..idx], string[idx + 3..].parse().ok()),
+        None => (string, None),
+    };
+    let numbers: Option<Vec<u64>> = base.split('.')
+        .map(|n| n.parse().ok())
+        .collect();
+    numbers.map(|numbers| Version { numbers: numbers, rc: rc })
+}
+
+fn version_ge(version: &Version, other: &str) -> bool {
+    parse_version(other).is_some_and(|other| *version >= other)
+}
+
+/// Determine whether the X.Y series of a version matches the provided
+/// wildcard (e.g. "5.4.*")
+fn in_series(version: &Version, series: &str) -> bool {
+    let prefix: Vec<&str> = series.trim_end_matches(".*").split('.').collect();
+    prefix.iter().enumerate().all(|(i, n)| {
+        n.parse::<u64>().ok() == Some(*version.numbers.get(i).unwrap_or(&0))
+    })
+}
+
+impl Rule {
+    fn matches(&self, version: &Version) -> bool {
+        version_ge(version, &self.from) && match &self.until {
+            Bound::Unbounded => true,
+            Bound::Before(until) => ! version_ge(version, until),
+            Bound::Series(series) => in_series(version, series),
+        }
+    }
+}
+
+impl Advisory {
+    fn is_affected(&self, version: &Version) -> bool {
+        let matching: Vec<&Rule> = self.rules.iter()
+            .filter(|rule| rule.matches(version))
+            .collect();
+        if matching.iter().any(|rule| rule.status == Status::Unaffected) {
+            false
+        } else if matching.iter().any(|rule| rule.status == Status::Affected) {
+            true
+        } else {
+            self.default == Status::Affected
+        }
+    }
+
+    /// Retrieve the version that fixes the vulnerability for the provided
+    /// (affected) version. Fixes in the same stable series are preferred.
+    fn fixed_in(&self, version: &Version) -> Option<String> {
+        for rule in &self.rules {
+            if let (Status::Affected, Bound::Before(until)) = (rule.status, &rule.until) {
+                if rule.matches(version) {
+                    return Some(until.clone());
+                }
+            }
+        }
+        let mut candidates: Vec<&Rule> = self.rules.iter()
+            .filter(|rule| rule.status == Status::Unaffected)
+            .filter(|rule| ! version_ge(version, &rule.from))
+            .filter(|rule| parse_version(&rule.from).is_some())
+            .collect();
+        candidates.sort_by(|a, b| {
+            parse_version(&a.from).cmp(&parse_version(&b.from))
+        });
+        candidates.iter()
+            .find(|rule| match &rule.until {
+                Bound::Series(series) => in_series(version, series),
+                _ => false,
+            })
+            .or_else(|| candidates.first())
+            .map(|rule| rule.from.clone())
+    }
+}
+
+fn get_product(name: &str) -> Option<Product> {
+    let name = name.to_lowercase();
+    if name.contains("u-boot") || name.contains("uboot") {
+        Some(Product::Uboot)
+    } else if name == "linux" || name == "kernel" || name.contains("linux kernel") {
+        Some(Product::Linux)
+    } else {
+        None
+    }
+}
+
+fn as_str(value: &Value) -> Option<String> {
+    value.as_str().map(|s| s.to_string())
+}
+
+/// Load an advisory from the OSV format (https://ossf.github.io/osv-schema/)
+fn load_osv(json: &Value) -> Vec<Advisory> {
+    let id = json["aliases"].as_array()
+        .and_then(|aliases| aliases.iter()
+            .filter_map(|a| a.as_str())
+            .find(|a| a.starts_with("CVE-"))
+            .map(|a| a.to_string()))
+        .or_else(|| as_str(&json["id"]))
+        .unwrap_or_default();
+    let summary = as_str(&json["summary"])
+        .or_else(|| as_str(&json["details"]))
+        .unwrap_or_default();
+
+    let mut advisories = Vec::new();
+    for affected in json["affected"].as_array().into_iter().flatten() {
+        let product = match as_str(&affected["package"]["name"])
+            .and_then(|name| get_product(&name))
+        {
+            Some(product) => product,
+            None => continue,
+        };
+
+        // OSV events are a sequence of introduced/fixed versions. Each
+        // introduced version opens a range that is closed by the next
+        // fixed version, if any.
+        let mut rules = Vec::new();
+        for range in affected["ranges"].as_array().into_iter().flatten() {
+            if range["type"].as_str() == Some("GIT") {
+                continue;
+            }
+            let mut introduced: Option<String> = None;
+            for event in range["events"].as_array().into_iter().flatten() {
+                if let Some(v) = as_str(&event["introduced"]) {
+                    introduced = Some(v);
+                } else if let Some(v) = as_str(&event["fixed"]) {
+                    rules.push(Rule {
+                        status: Status::Affected,
+                        from: introduced.take().unwrap_or_else(|| "0".to_string()),
+                        until: Bound::Before(v),
+                    });
+                }
+            }
+            if let Some(from) = introduced {
+                rules.push(Rule {
+                    status: Status::Affected,
+                    from: from,
+                    until: Bound::Unbounded,
+                });
+            }
+        }
+
+        advisories.push(Advisory {
+            id: id.clone(),
+            summary: summary.clone(),
+            product: product,
+            default: Status::Unaffected,
+            rules: rules,
+            files: Vec::new(),
+        });
+    }
+    advisories
+}
+
+/// Load an advisory from the CVE JSON 5 format, as published by the
+/// Linux kernel CNA (https://git.kernel.org/pub/scm/linux/security/vulns.git)
+fn load_cve5(json: &Value) -> Vec<Advisory> {
+    let cna = &json["containers"]["cna"];
+    let id = as_str(&json["cveMetadata"]["cveId"]).unwrap_or_default();
+    let summary = cna["descriptions"].as_array()
+        .and_then(|d| d.first())
+        .and_then(|d| as_str(&d["value"]))
+        .unwrap_or_default();
+
+    let mut advisories = Vec::new();
+    for affected in cna["affected"].as_array().into_iter().flatten() {
+        let product = match as_str(&affected["product"])
+            .and_then(|name| get_product(&name))
+        {
+            Some(product) => product,
+            None => continue,
+        };
+        let default = match affected["defaultStatus"].as_str() {
+            Some("affected") => Status::Affected,
+            _ => Status::Unaffected,
+        };
+
+        let mut rules = Vec::new();
+        for version in affected["versions"].as_array().into_iter().flatten() {
+            // Only semantic versions are meaningful to us. Git commits
+            // cannot be resolved without a git tree.
+            if version["versionType"].as_str() == Some("git") {
+                continue;
+            }
+            let status = match version["status"].as_str() {
+                Some("affected") => Status::Affected,
+                Some("unaffected") => Status::Unaffected,
+                _ => continue,
+            };
+            let from = match as_str(&version["version"]) {
+                Some(from) => from,
+                None => continue,
+            };
+            let until = if let Some(lt) = as_str(&version["lessThan"]) {
+                if lt == "*" { Bound::Unbounded } else { Bound::Before(lt) }
+            } else if let Some(le) = as_str(&version["lessThanOrEqual"]) {
+                if le == "*" { Bound::Unbounded } else { Bound::Series(le) }
+            } else if status == Status::Affected {
+                Bound::Unbounded
+            } else {
+                // A single unaffected version is reported as such
+                Bound::Series(from.clone())
+            };
+            rules.push(Rule { status: status, from: from, until: until });
+        }
+
+        let files = affected["programFiles"].as_array().into_iter().flatten()
+            .filter_map(as_str)
+            .collect();
+
+        advisories.push(Advisory {
+            id: id.clone(),
+            summary: summary.clone(),
+            product: product,
+            default: default,
+            rules: rules,
+            files: files,
+        });
+    }
+    advisories
+}
+
+/// Recursively go through a database directory and load all the JSON
+/// advisories it contains
+fn load_db(dir: &PathBuf, advisories: &mut Vec<Advisory>) -> Result<()> {
+    let dir_iter = std::fs::read_dir(dir)
+        .context(error::DirIterFailed{dir: dir.clone()})?;
+    for dir_it in dir_iter {
+        let entry = dir_it
+            .context(error::DirIterFailed{dir: dir.clone()})?
+            .path();
+        if entry.is_dir() {
+            load_db(&entry, advisories)?;
+        } else if entry.extension().is_some_and(|ext| ext == "json") {
+            let contents = std::fs::read(&entry)
+                .context(error::FailedToRead{path: entry.clone()})?;
+            let json: Value = serde_json::from_slice(&contents)
+                .context(error::FailedToDeserJson{path: entry.clone()})?;
+            if json.get("cveMetadata").is_some() {
+                advisories.append(&mut load_cve5(&json));
+            } else if json.get("affected").is_some() {
+                advisories.append(&mut load_osv(&json));
+            } else {
+                debug!("Ignoring {:#?}: unknown vulnerability format", entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extract all CVE identifiers that appear in a text
+fn find_cve_ids(text: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = text;
+    while let Some(idx) = rest.find("CVE-") {
+        rest = &rest[idx + 4..];
+        let id: String = rest.chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '-')
+            .collect();
+        let parts: Vec<&str> = id.split('-').collect();
+        if parts.len() >= 2 && parts[0].len() == 4 && ! parts[1].is_empty() {
+            ids.push(format!("CVE-{}-{}", parts[0], parts[1]));
+        }
+    }
+    ids
+}
+
+/// Go through the library patches of a component and collect the CVE
+/// identifiers they claim to address. Only patches that apply to the
+/// fetched version are considered.
+fn load_patch_claims(subject: &Subject) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    fn walk(dir: &PathBuf, claims: &mut BTreeMap<String, Vec<PathBuf>>) -> Result<()> {
+        let dir_iter = std::fs::read_dir(dir)
+            .context(error::DirIterFailed{dir: dir.clone()})?;
+        for dir_it in dir_iter {
+            let entry = dir_it
+                .context(error::DirIterFailed{dir: dir.clone()})?
+                .path();
+            if entry.is_dir() {
+                walk(&entry, claims)?;
+            } else if entry.is_file() {
+                let contents = std::fs::read(&entry)
+                    .context(error::FailedToRead{path: entry.clone()})?;
+                let text = String::from_utf8_lossy(&contents);
+                for id in find_cve_ids(&text) {
+                    let patches = claims.entry(id).or_default();
+                    if ! patches.contains(&entry) {
+                        patches.push(entry.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let mut claims = BTreeMap::new();
+    if ! subject.patches_dir.is_dir() {
+        return Ok(claims);
+    }
+    match subject.product {
+        Product::Uboot => walk(&subject.patches_dir, &mut claims)?,
+        Product::Linux => {
+            // Linux patches are stored in directories named after the
+            // version they apply to (X.Y or X.Y.Z).
+            let version = parse_version(&subject.version);
+            let dir_iter = std::fs::read_dir(&subject.patches_dir)
+                .context(error::DirIterFailed{dir: subject.patches_dir.clone()})?;
+            for dir_it in dir_iter {
+                let entry = dir_it
+                    .context(error::DirIterFailed{dir: subject.patches_dir.clone()})?
+                    .path();
+                let applies = entry.file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(parse_version)
+                    .is_some_and(|dir_version| match &version {
+                        Some(version) => {
+                            dir_version.numbers.get(..2) == version.numbers.get(..2)
+                                && *version >= dir_version
+                        },
+                        None => false,
+                    });
+                if applies && entry.is_dir() {
+                    walk(&entry, &mut claims)?;
+                }
+            }
+        }
+    }
+    Ok(claims)
+}
+
+/// Determine whether any of the files affected by a vulnerability is
+/// compiled with the current configuration. This relies on the presence
+/// of object files in the build directory, so the component must have been
+/// built beforehand. None is returned when this cannot be determined.
+fn is_built(subject: &Subject, files: &[String]) -> Option<bool> {
+    let sources: Vec<&String> = files.iter()
+        .filter(|f| f.ends_with(".c") || f.ends_with(".S"))
+        .collect();
+    if sources.is_empty() || ! subject.build_dir.is_dir() {
+        return None;
+    }
+    Some(sources.iter().any(|file| changes::is_compiled(&subject.build_dir, file)))
+}
+
+/// Match the fetched versions of the components against the vulnerability
+/// databases and report open vulnerabilities.
+/// If show_all is set, vulnerabilities claimed to be addressed by library
+/// patches, or that concern files that are not built are also reported.
+pub fn check(db_dirs: &[PathBuf], subjects: &[Subject], show_all: bool) -> Result<()> {
+    let dbs: Vec<&PathBuf> = db_dirs.iter().filter(|d| d.is_dir()).collect();
+    ensure!(! dbs.is_empty(), error::NoVulnDb{dirs: db_dirs.to_vec()});
+
+    let mut advisories = Vec::new();
+    for db in dbs {
+        info!("Loading vulnerability database {:#?}", db);
+        load_db(db, &mut advisories)?;
+    }
+    debug!("{} advisories loaded", advisories.len());
+
+    for subject in subjects {
+        let version = match parse_version(&subject.version) {
+            Some(version) => version,
+            None => {
+                warn!("Cannot interpret {} version '{}'", subject.product, subject.version);
+                continue;
+            }
+        };
+        let claims = load_patch_claims(subject)?;
+
+        let mut open = 0;
+        println!("{} {}:", subject.product, subject.version);
+        for advisory in &advisories {
+            if advisory.product != subject.product || ! advisory.is_affected(&version) {
+                continue;
+            }
+            let fixed = advisory.fixed_in(&version)
+                .unwrap_or_else(|| "-".to_string());
+            let patches = claims.get(&advisory.id);
+            let built = is_built(subject, &advisory.files);
+
+            let state = if patches.is_some() {
+                "patched"
+            } else if built == Some(false) {
+                "not built"
+            } else {
+                open += 1;
+                "open"
+            };
+            if state == "open" || show_all {
+                println!("  {:<16} {:<10} fixed in {:<12} {}",
+                    advisory.id, state, fixed, advisory.summary.lines().next().unwrap_or(""));
+                for patch in patches.into_iter().flatten() {
+                    println!("  {:<16} claimed by {}", "", patch.display());
+                }
+            }
+        }
+        if open == 0 {
+            info!("No open vulnerability found for {} {}", subject.product, subject.version);
+        } else {
+            warn!("{} open vulnerabilities found for {} {}", open, subject.product, subject.version);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(string: &str) -> Version {
+        parse_version(string).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert!(v("5.4.1") == Version { numbers: vec![5, 4, 1], rc: None });
+        assert!(v("v2020.04-rc3") == Version { numbers: vec![2020, 4], rc: Some(3) });
+        assert!(parse_version("1a2b3c4d").is_none());
+        assert!(parse_version("").is_none());
+    }
+
+    #[test]
+    fn order() {
+        assert!(v("5.4") < v("5.4.1"));
+        assert!(v("5.4") == v("5.4.0"));
+        assert!(v("5.4.10") > v("5.4.9"));
+        assert!(v("5.5-rc1") < v("5.5"));
+        assert!(v("5.5-rc1") < v("5.5-rc2"));
+        assert!(v("5.4.99") < v("5.5-rc1"));
+        assert!(v("2020.04") > v("2020.01"));
+    }
+
+    #[test]
+    fn series() {
+        assert!(in_series(&v("5.4.12"), "5.4.*"));
+        assert!(! in_series(&v("5.5.1"), "5.4.*"));
+    }
+}
@@ -0,0 +1,131 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, ensure};
+
+use crate::error::Result;
+use crate::error;
+use crate::packaging::Metadata;
+
+/// Create the directory in which a Debian package will be assembled, as well
+/// as its DEBIAN/ directory. The path to the root of the package is returned.
+/// Anything left from a previous build is removed, so that the package only
+/// ships what the current build installs.
+pub fn prepare(pkg_dir: &PathBuf, package: &str) -> Result<PathBuf> {
+    let mut deb_dir = pkg_dir.clone();
+    deb_dir.push(package);
+    if deb_dir.exists() {
+        std::fs::remove_dir_all(&deb_dir).context(
+            error::RemoveDirError{ path: deb_dir.clone() })?;
+    }
+    let mut deb = deb_dir.clone();
+    deb.push("DEBIAN");
+    std::fs::create_dir_all(&deb).context(
+        error::CreateDirError{ path: deb.clone() })?;
+    Ok(deb_dir)
+}
+
+/// Generate the contents of the DEBIAN/control file of a package
+pub fn control(meta: &Metadata, arch: &str) -> String {
+    let mut control = format!("Package: {}\nArchitecture: {}\nMaintainer: {}\n",
+        meta.package, arch, meta.maintainer);
+    control.push_str(&format!("Description: {}\n", meta.summary));
+    for line in &meta.description {
+        if line.is_empty() {
+            control.push_str(" .\n");
+        } else {
+            control.push_str(&format!(" {}\n", line));
+        }
+    }
+    for (field, values) in &[
+        ("Depends", &meta.depends),
+        ("Conflicts", &meta.conflicts),
+        ("Replaces", &meta.replaces),
+    ] {
+        if ! values.is_empty() {
+            control.push_str(&format!("{}: {}\n", field, values.join(", ")));
+        }
+    }
+    control.push_str(&format!("Version: {}\nSection: {}\nPriority: {}\n",
+        meta.version, meta.section, meta.priority));
+    control
+}
+
+/// Write a file within the package being assembled in deb_dir. Parent
+/// directories are created if needed.
+/// The file is scoped so that it is EFFECTIVELY flushed to the filesystem
+/// before dpkg-deb tries to read it.
+pub fn write_file(deb_dir: &PathBuf, name: &str, contents: &str, executable: bool) -> Result<()> {
+    let mut path = deb_dir.clone();
+    path.push(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context(
+            error::CreateDirError{ path: parent.to_path_buf() })?;
+    }
+    {
+        let mut file = std::fs::File::create(&path)
+            .context(error::CreateFileError{path: path.clone()})?;
+        file.write_all(contents.as_bytes())
+            .context(error::FailedToWrite{path: path.clone()})?;
+    }
+    if executable {
+        let perms = std::fs::Permissions::from_mode(0o755);
+        std::fs::set_permissions(&path, perms)
+            .context(error::FailedToWrite{path: path.clone()})?;
+    }
+    Ok(())
+}
+
+/// Run dpkg-deb to create the package assembled in pkg_dir/package.
+/// Upon success, the path to the created package is returned.
+pub fn build(pkg_dir: &PathBuf, package: &str, epoch: u64) -> Result<PathBuf> {
+    // The files of the package are dated with the sources, so that building
+    // it again gives the same package
+    let status = Command::new("dpkg-deb")
+        .arg("--build")
+        .arg(package)
+        .current_dir(pkg_dir)
+        .env("SOURCE_DATE_EPOCH", epoch.to_string())
+        .stdin(Stdio::null())
+        .status()
+        .context(error::ProgFailed{ proc: "dpkg-deb".to_string() })?;
+    ensure!(status.success(), error::DebFailed{package: package.to_string()});
+
+    // Finally, return the path to the debian file. Hoping that it
+    // was indeed created.
+    let mut result = pkg_dir.clone();
+    result.push(format!("{}.deb", package));
+    ensure!(result.is_file(), error::NoPackage{path:result.clone()});
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prepare_removes_stale_files() {
+        let mut pkg_dir = std::env::temp_dir();
+        pkg_dir.push(format!("mktcb-debian-{}", std::process::id()));
+        let deb_dir = prepare(&pkg_dir, "pkg").unwrap();
+        let mut stale = deb_dir.clone();
+        stale.push("boot");
+        std::fs::create_dir_all(&stale).unwrap();
+        stale.push("boot.scr");
+        std::fs::write(&stale, "stale").unwrap();
+
+        assert_eq!(prepare(&pkg_dir, "pkg").unwrap(), deb_dir);
+        assert!(! stale.exists());
+        let mut deb = deb_dir.clone();
+        deb.push("DEBIAN");
+        assert!(deb.is_dir());
+        std::fs::remove_dir_all(&pkg_dir).unwrap();
+    }
+}
@@ -40,6 +40,7 @@ pub fn check(handle: &mut Easy, url: &url::Url) -> Result<bool> {
 
 pub fn to_file(handle: &mut Easy, url: &url::Url, path: &std::path::PathBuf) -> Result<()> {
     handle.url(url.as_str()).context(error::URLError{url: url.clone()})?;
+    handle.fetch_filetime(true).context(error::CURLSetupError{})?;
 
     let mut file = std::fs::File::create(&path).context(
         error::CreateFileError{ path: path.clone() }
@@ -90,6 +91,46 @@ pub fn to_file(handle: &mut Easy, url: &url::Url, path: &std::path::PathBuf) ->
 }
 
 
+/// Retrieve when the file that was last downloaded was modified on the
+/// server (UNIX timestamp), if it tells
+pub fn last_modified(handle: &mut Easy) -> Option<u64> {
+    match handle.filetime() {
+        Ok(Some(time)) if time >= 0 => Some(time as u64),
+        _ => None,
+    }
+}
+
+/// Retrieve the contents of an URL as a string. This is intended for
+/// small text documents, such as directory listings.
+pub fn to_string(handle: &mut Easy, url: &url::Url) -> Result<String> {
+    debug!("Retrieving contents of {:#?}", url);
+    handle.url(url.as_str()).context(error::URLError{url: url.clone()})?;
+
+    let mut data = Vec::new();
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|chunk| {
+            data.extend_from_slice(chunk);
+            Ok(chunk.len())
+        }).context(error::CURLSetupError{})?;
+        transfer.perform().context(error::RequestError{url: url.clone()})?;
+    }
+
+    let code = handle.response_code()
+        .context(error::RequestError{url: url.clone()})?;
+    let is_ok = match code {
+        200 => true,
+        226 => true, // See https://tools.ietf.org/html/rfc3229
+        _ => false,
+    };
+    ensure!(is_ok, error::DownloadError{
+        url: url.clone(),
+        code: code,
+    });
+    Ok(String::from_utf8_lossy(&data).to_string())
+}
+
+
 /// Downloads a compressed tar archive from URL and store it in in_dir.
 /// The archive will be unpacked and also placed in in_dir, and the
 /// resulting output directory must patch expected_dir.
@@ -98,6 +139,21 @@ pub fn to_unpacked_dir(
     url: &url::Url,
     in_dir: &PathBuf,
     expected_dir: &PathBuf) -> Result<()>
+{
+    let archive = util::url_last(url)?;
+    to_unpacked_dir_as(http_handle, url, in_dir, &archive, expected_dir)
+}
+
+/// Same as to_unpacked_dir(), but the archive is stored under the provided
+/// name. This is useful for archives whose name does not match the
+/// directory they contain (e.g. GitHub archives: v1.0.tar.gz contains
+/// project-1.0/).
+pub fn to_unpacked_dir_as(
+    http_handle: &mut curl::easy::Easy,
+    url: &url::Url,
+    in_dir: &PathBuf,
+    archive: &PathBuf,
+    expected_dir: &PathBuf) -> Result<()>
 {
     // The output dir shall not already exist
     assert!(! expected_dir.is_dir());
@@ -108,7 +164,7 @@ pub fn to_unpacked_dir(
 
     // Compose the full path to the archive to be downloaded
     let mut tar_path = in_dir.clone();
-    tar_path.push(util::url_last(url)?);
+    tar_path.push(archive);
 
     // Download the archive and unpack it, effectively returning the unpacked
     // directory
@@ -0,0 +1,166 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::config::{Config, EfiConfig, UkiConfig};
+
+/// Signs EFI images with Authenticode (osslsigncode), and builds unified
+/// kernel images (ukify)
+pub struct Efi {
+    key: PathBuf,
+    cert: PathBuf,
+    uki: Option<UkiConfig>,
+    /// EFI stub and initrd of the unified kernel image, if any
+    stub: Option<PathBuf>,
+    initrd: Option<PathBuf>,
+    work_dir: PathBuf,
+}
+
+/// EFI images of a kernel
+pub struct Images {
+    /// Signed kernel image
+    pub kernel: PathBuf,
+    /// Signed unified kernel image, if requested
+    pub uki: Option<PathBuf>,
+}
+
+/// Run a program, that does not need any input
+fn run(cmd: &mut Command, prog: &str) -> Result<bool> {
+    debug!("Running {:?}", cmd);
+    let status = cmd
+        .stdin(Stdio::null())
+        .status()
+        .context(error::ProgFailed{ proc: prog.to_string() })?;
+    Ok(status.success())
+}
+
+/// Remove a file that a previous build produced. Some tools refuse to
+/// overwrite their output.
+fn remove_output(path: &PathBuf) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path).context(error::RemoveFileError{ path: path.clone() })?;
+    }
+    Ok(())
+}
+
+impl Efi {
+    /// Sign an image with Authenticode. Existing signatures are replaced.
+    /// The signing time is the epoch of the sources, so that the signed
+    /// image is reproducible. The signature is verified against the
+    /// certificate afterwards.
+    fn sign(&self, input: &PathBuf, output: &PathBuf, epoch: u64) -> Result<()> {
+        ensure!(self.key.is_file(), error::FileDoesNotExist{ path: self.key.clone() });
+        ensure!(self.cert.is_file(), error::FileDoesNotExist{ path: self.cert.clone() });
+        remove_output(output)?;
+        let signed = run(Command::new("osslsigncode")
+            .arg("sign")
+            .arg("-certs").arg(&self.cert)
+            .arg("-key").arg(&self.key)
+            .arg("-h").arg("sha256")
+            .arg("-time").arg(epoch.to_string())
+            .arg("-in").arg(input)
+            .arg("-out").arg(output), "osslsigncode")?;
+        ensure!(signed, error::EfiSigningFailed{ path: input.clone() });
+
+        let verified = run(Command::new("osslsigncode")
+            .arg("verify")
+            .arg("-CAfile").arg(&self.cert)
+            .arg("-in").arg(output), "osslsigncode")?;
+        ensure!(verified, error::EfiVerificationFailed{ path: output.clone() });
+        Ok(())
+    }
+
+    /// Assemble a unified kernel image around a (signed) kernel image. The
+    /// os-release of the host is not embedded, so that the image does not
+    /// depend on where it is built.
+    fn uki(&self, uki: &UkiConfig, stub: &PathBuf, version: &str, kernel: &PathBuf,
+           dtb: Option<PathBuf>, output: &PathBuf) -> Result<()> {
+        ensure!(stub.is_file(), error::FileDoesNotExist{ path: stub.clone() });
+        remove_output(output)?;
+        let mut cmd = Command::new("ukify");
+        cmd.arg("build")
+            .arg("--linux").arg(kernel)
+            .arg("--stub").arg(stub)
+            .arg("--uname").arg(version)
+            .arg("--os-release").arg(format!("NAME=Linux\nVERSION_ID={}\n", version))
+            .arg("--output").arg(output);
+        if let Some(cmdline) = &uki.cmdline {
+            cmd.arg("--cmdline").arg(cmdline);
+        }
+        if let Some(dtb) = dtb {
+            ensure!(dtb.is_file(), error::FileDoesNotExist{ path: dtb.clone() });
+            cmd.arg("--devicetree").arg(dtb);
+        }
+        if let Some(initrd) = &self.initrd {
+            ensure!(initrd.is_file(), error::FileDoesNotExist{ path: initrd.clone() });
+            cmd.arg("--initrd").arg(initrd);
+        }
+        ensure!(run(&mut cmd, "ukify")?, error::UkiFailed{ path: output.clone() });
+        Ok(())
+    }
+
+    /// Retrieve the device tree of the unified kernel image, if it is set
+    pub fn dtb(&self) -> Option<&PathBuf> {
+        self.uki.as_ref().and_then(|uki| uki.dtb.as_ref())
+    }
+
+    fn output(&self, name: &str) -> PathBuf {
+        let mut path = self.work_dir.clone();
+        path.push(name);
+        path
+    }
+
+    /// Sign a kernel image, and build the unified kernel image if requested.
+    /// The device tree is only used by the latter. Signatures are dated at
+    /// epoch (UNIX timestamp).
+    pub fn build(&self, version: &str, kernel: &PathBuf, dtb: Option<PathBuf>, epoch: u64) -> Result<Images> {
+        std::fs::create_dir_all(&self.work_dir).context(
+            error::CreateDirError{ path: self.work_dir.clone() })?;
+        info!("Signing kernel image {:#?} for EFI", kernel);
+        let kernel_path = self.output(&format!("vmlinuz-{}.efi.signed", version));
+        self.sign(kernel, &kernel_path, epoch)?;
+
+        let uki = match (&self.uki, &self.stub) {
+            (Some(uki), Some(stub)) => {
+                info!("Building unified kernel image from {:#?}", stub);
+                let unsigned = self.output(&format!("linux-{}.efi.unsigned", version));
+                self.uki(uki, stub, version, &kernel_path, dtb, &unsigned)?;
+                let path = self.output(&format!("linux-{}.efi", version));
+                self.sign(&unsigned, &path, epoch)?;
+                Some(path)
+            },
+            _ => None,
+        };
+        Ok(Images {
+            kernel: kernel_path,
+            uki: uki,
+        })
+    }
+}
+
+/// Create the EFI signer of a kernel, if its configuration requests one
+pub fn new(config: &Config, cfg: &Option<EfiConfig>) -> Option<Efi> {
+    let cfg = cfg.as_ref()?;
+    let library = |path: &PathBuf| {
+        let mut full = config.lib_dir.clone();
+        full.push(path);
+        full
+    };
+    let uki = cfg.uki.clone();
+    let mut work_dir = config.build_dir.clone();
+    work_dir.push("efi");
+    Some(Efi {
+        key: library(&cfg.key),
+        cert: library(&cfg.cert),
+        stub: uki.as_ref().map(|u| library(&u.stub)),
+        initrd: uki.as_ref().and_then(|u| u.initrd.as_ref()).map(library),
+        uki: uki,
+        work_dir: work_dir,
+    })
+}
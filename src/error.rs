@@ -36,6 +36,98 @@ pub enum Error {
         source: url::ParseError,
     },
 
+    #[snafu(display("The URL to retrieve TF-A seems invalid: {}", source))]
+    InvalidTfaURL {
+        source: url::ParseError,
+    },
+
+    #[snafu(display("The URL to retrieve OP-TEE seems invalid: {}", source))]
+    InvalidOpteeURL {
+        source: url::ParseError,
+    },
+
+    #[snafu(display("The target does not define the component '{}'", component))]
+    NoComponent {
+        component: String,
+    },
+
+    #[snafu(display("Invalid configuration for component '{}': {}", component, source))]
+    FailedToDeserComponent {
+        component: String,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("Invalid {} configuration: {}", section, source))]
+    FailedToDeserSection {
+        section: String,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("Invalid build number in {:#?}", path))]
+    InvalidBuildNumber {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Component '{}' is declared more than once", component))]
+    DuplicateComponent {
+        component: String,
+    },
+
+    #[snafu(display("Device trees {:#?} and {:#?} have the same file name", first, second))]
+    DuplicateDtb {
+        first: std::path::PathBuf,
+        second: std::path::PathBuf,
+    },
+
+    #[snafu(display("Unknown kind of component '{}'", kind))]
+    UnknownComponentKind {
+        kind: String,
+    },
+
+    #[snafu(display("Component '{}' does not support the '{}' operation", component, operation))]
+    NotSupported {
+        component: String,
+        operation: String,
+    },
+
+    #[snafu(display("The URL to retrieve component '{}' seems invalid: {}", component, source))]
+    InvalidComponentURL {
+        component: String,
+        source: url::ParseError,
+    },
+
+    #[snafu(display("Component '{}' does not define where to retrieve its sources", component))]
+    NoComponentSource {
+        component: String,
+    },
+
+    #[snafu(display("Cannot determine the archive format of {:#?}", url))]
+    UnknownArchiveFormat {
+        url: url::Url,
+    },
+
+    #[snafu(display("Failed to retrieve revision '{}' of git repository '{}'", rev, url))]
+    GitFailed {
+        url: String,
+        rev: String,
+    },
+
+    #[snafu(display("Component '{}' depends on itself", component))]
+    DependencyCycle {
+        component: String,
+    },
+
+    #[snafu(display("Component '{}' does not produce artifact '{}'", component, artifact))]
+    NoArtifact {
+        component: String,
+        artifact: String,
+    },
+
+    #[snafu(display("{} has not been downloaded (run --fetch?)", component))]
+    NotFetched {
+        component: String,
+    },
+
     #[snafu(display("Failed to read version file {:#?}: {}", path, source))]
     FailedToReadVersion {
         path: std::path::PathBuf,
@@ -55,6 +147,19 @@ pub enum Error {
         version_file: std::path::PathBuf,
     },
 
+    #[snafu(display("The patches recorded in {:#?} are not the first patches \
+            of the library anymore. Please remove {:#?} and fetch again.",
+            version_file, dir))]
+    PatchesMismatch {
+        version_file: std::path::PathBuf,
+        dir: std::path::PathBuf,
+    },
+
+    #[snafu(display("Invalid U-Boot version '{}'. Expected YYYY.MM[-rcN]", version))]
+    InvalidUbootVersion {
+        version: String,
+    },
+
     #[snafu(display("Could not retrieve current directory: {}", source))]
     CwdAccess {
         source: std::io::Error,
@@ -69,10 +174,24 @@ pub enum Error {
         source: std::num::ParseIntError,
     },
 
+    #[snafu(display("Invalid number of versions to keep: {}", source))]
+    InvalidPruneNumber {
+        source: std::num::ParseIntError,
+    },
+
+    #[snafu(display("Invalid timeout: {}", source))]
+    InvalidTimeout {
+        source: std::num::ParseIntError,
+    },
+
     #[snafu(display("A value of 0 jobs is meaningless"))]
     ZeroJob {
     },
 
+    #[snafu(display("A boot limit of 0 is meaningless: an upgraded slot must be tried at least once"))]
+    ZeroBootLimit {
+    },
+
     #[snafu(display("Failed to read file {:#?}: {}", path, source))]
     FailedToRead {
         path: std::path::PathBuf,
@@ -97,6 +216,28 @@ pub enum Error {
         source: toml::de::Error,
     },
 
+    #[snafu(display("Failed to write JSON file {:#?}: {}", path, source))]
+    FailedToSerJson {
+        path: std::path::PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Failed to compute the checksum of {:#?}", path))]
+    ChecksumFailed {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Failed to read JSON file {:#?}: {}", path, source))]
+    FailedToDeserJson {
+        path: std::path::PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("No vulnerability database found. Looked in {:#?}", dirs))]
+    NoVulnDb {
+        dirs: Vec<std::path::PathBuf>,
+    },
+
     #[snafu(display("File {:#?} does not exist", path))]
     FileDoesNotExist {
         path: std::path::PathBuf,
@@ -135,6 +276,24 @@ pub enum Error {
         path: std::path::PathBuf,
     },
 
+    #[snafu(display("Failed to remove directory {:?}: {}", path, source))]
+    RemoveDirError {
+        source: std::io::Error,
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Failed to remove file {:?}: {}", path, source))]
+    RemoveFileError {
+        source: std::io::Error,
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Failed to copy directory {:#?} to {:#?}", from, to))]
+    CopyDirFailed {
+        from: std::path::PathBuf,
+        to: std::path::PathBuf,
+    },
+
     #[snafu(display("Failed to create/open file {:#?}: {}", path, source))]
     CreateFileError {
         source: std::io::Error,
@@ -215,6 +374,220 @@ pub enum Error {
         package: String,
     },
 
+    #[snafu(display("'{}' failed while publishing the repository", proc))]
+    RepoFailed {
+        proc: String,
+    },
+
+    #[snafu(display("Field '{}' is missing from the control file of {:#?}", field, deb))]
+    MissingControlField {
+        field: String,
+        deb: std::path::PathBuf,
+    },
+
+    #[snafu(display("No package to publish (run --debpkg?)"))]
+    NothingToPublish {},
+
+    #[snafu(display("The target does not describe a disk image (no [image] section)"))]
+    NoImageLayout {},
+
+    #[snafu(display("Invalid size '{}'. Expected bytes, optionally suffixed by K, M or G", size))]
+    InvalidSize {
+        size: String,
+    },
+
+    #[snafu(display("Start and size of '{}' must be non-null multiples of {} bytes", partition, sector))]
+    InvalidPartitionSize {
+        partition: String,
+        sector: u64,
+    },
+
+    #[snafu(display("Only the last partition may omit its size, not '{}'", partition))]
+    MissingPartitionSize {
+        partition: String,
+    },
+
+    #[snafu(display("{} overlaps with {} in the disk image", first, second))]
+    LayoutOverlap {
+        first: String,
+        second: String,
+    },
+
+    #[snafu(display("The disk image must be at least {} bytes large to hold its partition table", min))]
+    DiskTooSmall {
+        min: u64,
+    },
+
+    #[snafu(display("{} does not fit in the disk image", what))]
+    LayoutOverflow {
+        what: String,
+    },
+
+    #[snafu(display("The partition table cannot hold more than {} partitions", max))]
+    TooManyPartitions {
+        max: usize,
+    },
+
+    #[snafu(display("No root filesystem tarball for partition '{}' (use --rootfs?)", partition))]
+    MissingRootfs {
+        partition: String,
+    },
+
+    #[snafu(display("The root filesystem of partition '{}' must be ext4", partition))]
+    RootfsNotExt4 {
+        partition: String,
+    },
+
+    #[snafu(display("'{}' failed while creating the disk image", proc))]
+    ImageFailed {
+        proc: String,
+    },
+
+    #[snafu(display("The target does not describe a boot flow (no [boot] section)"))]
+    NoBootFlow {},
+
+    #[snafu(display("The patches applied on {} were not recorded when it was fetched. Fetch it again.", component))]
+    PatchesNotRecorded {
+        component: String,
+    },
+
+    #[snafu(display("Patch {:#?} of {} changed since it was applied. Fetch it again.",
+        patch, component))]
+    PatchChanged {
+        patch: std::path::PathBuf,
+        component: String,
+    },
+
+    #[snafu(display("The target does not describe a QEMU machine (no [qemu] section)"))]
+    NoQemuConfig {},
+
+    #[snafu(display("No QEMU emulator is known for architecture '{}'. Set qemu in [qemu].", arch))]
+    UnsupportedQemuArch {
+        arch: String,
+    },
+
+    #[snafu(display("The boot did not complete within {} seconds. See {:#?}", timeout, log))]
+    BootTimeout {
+        timeout: u64,
+        log: std::path::PathBuf,
+    },
+
+    #[snafu(display("The kernel crashed while booting ('{}'). See {:#?}", line, log))]
+    BootPanic {
+        line: String,
+        log: std::path::PathBuf,
+    },
+
+    #[snafu(display("QEMU exited before the boot completed. See {:#?}", log))]
+    BootAborted {
+        log: std::path::PathBuf,
+    },
+
+    #[snafu(display("No build manifest found at {:#?}. Build the target first.", path))]
+    NoManifest {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("The target does not describe a FIT image (no [fit] section)"))]
+    NoFitConfig {},
+
+    #[snafu(display("mkimage failed to create the FIT image {:#?}", fit))]
+    FitFailed {
+        fit: std::path::PathBuf,
+    },
+
+    #[snafu(display("Legacy U-Boot images are not supported for architecture '{}'", arch))]
+    UnsupportedImageArch {
+        arch: String,
+    },
+
+    #[snafu(display("Invalid value '{}' for U-Boot option {}", value, option))]
+    InvalidUbootOption {
+        option: String,
+        value: String,
+    },
+
+    #[snafu(display("The U-Boot configuration does not define CONFIG_ENV_SIZE"))]
+    NoEnvSize {},
+
+    #[snafu(display("The U-Boot environment does not fit in {} bytes", size))]
+    EnvTooLarge {
+        size: usize,
+    },
+
+    #[snafu(display("Failed to generate the signing key {:#?}", path))]
+    KeyGenFailed {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Signing key {:#?} already exists (use --force to replace it)", path))]
+    SigningKeyExists {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("{:#?} must hold both a private key and its certificate (PEM)", path))]
+    InvalidSigningKey {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Failed to sign module {:#?}", module))]
+    SignFileFailed {
+        module: std::path::PathBuf,
+    },
+
+    #[snafu(display("Failed to list the contents of package {:#?}", package))]
+    ListPackageFailed {
+        package: std::path::PathBuf,
+    },
+
+    #[snafu(display("Package {:#?} contains a private key ({})", package, file))]
+    PrivateKeyInPackage {
+        package: std::path::PathBuf,
+        file: String,
+    },
+
+    #[snafu(display("Failed to sign EFI image {:#?}", path))]
+    EfiSigningFailed {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("The signature of EFI image {:#?} does not verify", path))]
+    EfiVerificationFailed {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Failed to build unified kernel image {:#?}", path))]
+    UkiFailed {
+        path: std::path::PathBuf,
+    },
+
+    #[snafu(display("The builds are not reproducible: {} file(s) differ", count))]
+    NotReproducible {
+        count: usize,
+    },
+
+    #[snafu(display("Failed to create RPM package '{}'", package))]
+    RpmFailed {
+        package: String,
+    },
+
+    #[snafu(display("Offset {} of {:#?} must be a multiple of 512 bytes", offset, file))]
+    InvalidOffset {
+        file: std::path::PathBuf,
+        offset: u64,
+    },
+
+    #[snafu(display("Unknown Linux package '{}'. Expected one of: {}", package, expected))]
+    UnknownLinuxPackage {
+        package: String,
+        expected: String,
+    },
+
+    #[snafu(display("The build of module '{}' produced no kernel object", module))]
+    NoKernelObject {
+        module: String,
+    },
+
     #[snafu(display("We were expected to have created a Debian package at path {:#?}", path))]
     NoPackage {
         path: std::path::PathBuf,
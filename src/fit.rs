@@ -0,0 +1,244 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, OptionExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component::Component;
+use crate::config::{Config, FitConfig};
+use crate::interrupt::Interrupt;
+use crate::linux;
+use crate::uboot;
+
+/// FIT image of a target. When it is signed, U-Boot embeds the public key
+/// in its control device tree, so it refuses unsigned configurations.
+pub struct Fit {
+    cfg: FitConfig,
+    arch: String,
+    target: String,
+    target_name: String,
+    keys_dir: PathBuf,
+    initramfs: Option<PathBuf>,
+    work_dir: PathBuf,
+}
+
+/// Compression of an image, as guessed from its name
+fn compression(path: &PathBuf) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => "gzip",
+        Some("lz4") => "lz4",
+        Some("lzma") => "lzma",
+        _ => "none",
+    }
+}
+
+impl Fit {
+    pub fn is_signed(&self) -> bool {
+        self.cfg.key.is_some()
+    }
+
+    fn hash_nodes(&self, indent: &str) -> String {
+        let mut nodes = String::new();
+        for (index, algo) in self.cfg.hashes.iter().enumerate() {
+            nodes.push_str(&format!("{}hash-{} {{\n{}    algo = \"{}\";\n{}}};\n",
+                indent, index + 1, indent, algo, indent));
+        }
+        nodes
+    }
+
+    /// Compose the signature node of a configuration, if it is signed
+    fn signature_node(&self, images: &[&str], indent: &str) -> String {
+        match &self.cfg.key {
+            Some(key) => {
+                let images: Vec<String> = images.iter().map(|i| format!("\"{}\"", i)).collect();
+                format!("{}signature-1 {{\n{i}    algo = \"{}\";\n{i}    key-name-hint = \"{}\";\n{i}    sign-images = {};\n{i}}};\n",
+                    indent, self.cfg.algo, key, images.join(", "), i = indent)
+            },
+            None => String::new(),
+        }
+    }
+
+    /// Compose an image node of the FIT
+    fn image_node(&self, name: &str, kind: &str, path: &PathBuf, extra: &str) -> String {
+        format!(
+"        {} {{
+            description = \"{}\";
+            data = /incbin/(\"{}\");
+            type = \"{}\";
+            arch = \"{}\";
+            os = \"linux\";
+            compression = \"{}\";
+{}{}        }};
+", name, path.file_name().unwrap().to_str().unwrap(), path.to_str().unwrap(), kind,
+            self.arch, compression(path), extra, self.hash_nodes("            "))
+    }
+
+    /// Render the image tree source (.its). There is one configuration for
+    /// each device tree, the first one being the default.
+    pub fn its(&self, version: &str, kernel: &PathBuf, dtbs: &[PathBuf]) -> String {
+        let description = self.cfg.description.clone().unwrap_or(
+            format!("Linux {} for the {}", version, self.target_name));
+        let mut its = format!("/dts-v1/;\n\n/ {{\n    description = \"{}\";\n    #address-cells = <1>;\n\n    images {{\n",
+            description);
+        its.push_str(&self.image_node("kernel-1", "kernel", kernel, &format!(
+            "            load = <{}>;\n            entry = <{}>;\n", self.cfg.load, self.cfg.entry)));
+        for (index, dtb) in dtbs.iter().enumerate() {
+            its.push_str(&self.image_node(&format!("fdt-{}", index + 1), "flat_dt", dtb, ""));
+        }
+        if let Some(initramfs) = &self.initramfs {
+            its.push_str(&self.image_node("ramdisk-1", "ramdisk", initramfs, ""));
+        }
+        its.push_str("    };\n\n    configurations {\n        default = \"conf-1\";\n");
+
+        let ramdisk = if self.initramfs.is_some() { "            ramdisk = \"ramdisk-1\";\n" } else { "" };
+        let configurations = if dtbs.is_empty() { 1 } else { dtbs.len() };
+        for index in 0..configurations {
+            let mut images = vec!["kernel"];
+            let mut fdt = String::new();
+            if ! dtbs.is_empty() {
+                images.push("fdt");
+                fdt = format!("            fdt = \"fdt-{}\";\n", index + 1);
+            }
+            if self.initramfs.is_some() {
+                images.push("ramdisk");
+            }
+            let description = match dtbs.get(index) {
+                Some(dtb) => dtb.file_name().unwrap().to_str().unwrap().to_string(),
+                None => description.clone(),
+            };
+            its.push_str(&format!("        conf-{} {{\n            description = \"{}\";\n            kernel = \"kernel-1\";\n{}{}{}        }};\n",
+                index + 1, description, fdt, ramdisk, self.signature_node(&images, "            ")));
+        }
+        its.push_str("    };\n};\n");
+        its
+    }
+
+    /// Run mkimage on an image tree source. If the FIT is signed and a
+    /// device tree is provided, the public key is written in it, as required
+    /// by U-Boot. The image is dated at epoch (UNIX timestamp).
+    fn mkimage(&self, mkimage: &PathBuf, its: &PathBuf, itb: &PathBuf,
+               pubkey_dtb: Option<&PathBuf>, epoch: u64) -> Result<()> {
+        let mut cmd = Command::new(mkimage);
+        cmd.env("SOURCE_DATE_EPOCH", epoch.to_string());
+        cmd.arg("-f").arg(its);
+        if self.is_signed() {
+            ensure!(self.keys_dir.is_dir(), error::FileDoesNotExist{ path: self.keys_dir.clone() });
+            cmd.arg("-k").arg(&self.keys_dir);
+            if let Some(dtb) = pubkey_dtb {
+                cmd.arg("-K").arg(dtb).arg("-r");
+            }
+        }
+        let status = cmd
+            .arg(itb)
+            .stdin(Stdio::null())
+            .status()
+            .context(error::ProgFailed{ proc: "mkimage".to_string() })?;
+        ensure!(status.success(), error::FitFailed{ fit: itb.clone() });
+        Ok(())
+    }
+
+    fn write(&self, name: &str, contents: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.work_dir).context(
+            error::CreateDirError{ path: self.work_dir.clone() })?;
+        let mut path = self.work_dir.clone();
+        path.push(name);
+        std::fs::write(&path, contents).context(error::FailedToWrite{ path: path.clone() })?;
+        Ok(path)
+    }
+
+    /// Create a copy of the control device tree of U-Boot that holds the
+    /// public key. mkimage only writes keys when signing, so a FIT holding
+    /// the device tree itself is signed for this purpose.
+    pub fn pubkey_dtb(&self, mkimage: &PathBuf, uboot_dtb: &PathBuf, epoch: u64) -> Result<PathBuf> {
+        let mut dtb = self.work_dir.clone();
+        dtb.push("u-boot-pubkey.dtb");
+        std::fs::create_dir_all(&self.work_dir).context(
+            error::CreateDirError{ path: self.work_dir.clone() })?;
+        std::fs::copy(uboot_dtb, &dtb).context(error::CopyFailed{
+            from: uboot_dtb.clone(), to: dtb.clone() })?;
+
+        let its = format!("/dts-v1/;\n\n/ {{\n    description = \"Public key\";\n    #address-cells = <1>;\n\n    images {{\n{}    }};\n\n    configurations {{\n        default = \"conf-1\";\n        conf-1 {{\n            fdt = \"fdt-1\";\n{}        }};\n    }};\n}};\n",
+            self.image_node("fdt-1", "flat_dt", uboot_dtb, ""),
+            self.signature_node(&["fdt"], "            "));
+        let its = self.write("pubkey.its", &its)?;
+        let mut itb = self.work_dir.clone();
+        itb.push("pubkey.itb");
+        info!("Adding public key '{}' to {:#?}", self.cfg.key.as_ref().unwrap(), dtb);
+        self.mkimage(mkimage, &its, &itb, Some(&dtb), epoch)?;
+        Ok(dtb)
+    }
+
+    /// Build the FIT image of the target, dated with the sources of the
+    /// kernel. Its path is returned.
+    pub fn build(&self, mkimage: &PathBuf, version: &str, kernel: &PathBuf,
+                 dtbs: &[PathBuf], epoch: u64) -> Result<PathBuf> {
+        ensure!(kernel.is_file(), error::FileDoesNotExist{ path: kernel.clone() });
+        for path in dtbs.iter().chain(self.initramfs.iter()) {
+            ensure!(path.is_file(), error::FileDoesNotExist{ path: path.clone() });
+        }
+        let its = self.write(&format!("{}.its", self.target), &self.its(version, kernel, dtbs))?;
+        let mut itb = self.work_dir.clone();
+        itb.push(format!("{}.itb", self.target));
+        info!("Building FIT image {:#?}", itb);
+        self.mkimage(mkimage, &its, &itb, None, epoch)?;
+        Ok(itb)
+    }
+}
+
+/// Create the FIT image of the target, if it declares one
+pub fn new(config: &Config) -> Result<Option<Fit>> {
+    let cfg = match &config.fit {
+        Some(cfg) => cfg.clone(),
+        None => return Ok(None),
+    };
+    let mut keys_dir = config.lib_dir.clone();
+    keys_dir.push(cfg.keys.clone().unwrap_or(PathBuf::from("keys")));
+    let initramfs = cfg.initramfs.as_ref().map(|path| {
+        let mut full = config.lib_dir.clone();
+        full.push(path);
+        full
+    });
+    let mut work_dir = config.build_dir.clone();
+    work_dir.push("fit");
+
+    Ok(Some(Fit {
+        cfg: cfg,
+        arch: config.toolchain.linux_arch.clone(),
+        target: config.target.clone(),
+        target_name: config.target_name.clone(),
+        keys_dir: keys_dir,
+        initramfs: initramfs,
+        work_dir: work_dir,
+    }))
+}
+
+/// Build the FIT image of the target from the Linux build. The mkimage
+/// built with U-Boot is used if there is one. The path to the image is
+/// returned.
+pub fn run(config: &Config, interrupt: Interrupt) -> Result<PathBuf> {
+    let fit = new(config)?.context(error::NoFitConfig{})?;
+    let name = config.first_component("linux").context(error::NoComponent{
+        component: "linux".to_string() })?;
+    let mut linux = linux::new(config, &name, interrupt.clone())?;
+    let version = linux.fetched_version()?.context(error::LinuxNotFetched{})?;
+
+    let artifacts = linux.artifacts();
+    let kernel = artifacts.iter().find(|a| a.name == "kernel")
+        .context(error::NoArtifact{ component: name.clone(), artifact: "kernel".to_string() })?;
+    ensure!(kernel.path.is_file(), error::FileDoesNotExist{ path: kernel.path.clone() });
+    let mut dtbs = Vec::new();
+    for artifact in artifacts.iter().filter(|a| a.name != "kernel") {
+        ensure!(artifact.path.is_file(), error::FileDoesNotExist{ path: artifact.path.clone() });
+        dtbs.push(artifact.path.clone());
+    }
+
+    let mkimage = match config.first_component("uboot") {
+        Some(name) => uboot::new(config, &name, interrupt)?.mkimage(),
+        None => PathBuf::from("mkimage"),
+    };
+    fit.build(&mkimage, &version, &kernel.path, &dtbs, linux.source_date_epoch())
+}
@@ -0,0 +1,222 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::component::{Component, Artifact, Input, MakeInvocation, Provenance};
+use crate::config::{Config, MakeConfig};
+use crate::source;
+use crate::source::Source;
+use crate::toolchain;
+use crate::toolchain::Toolchain;
+use crate::interrupt::Interrupt;
+
+/// Component that mktcb knows nothing about, besides what its TOML
+/// description says: where to retrieve it, and how to run make on it.
+pub struct Generic {
+    name: String,
+    source: Source,
+    build_dir: PathBuf,
+    /// Make variable through which the build directory is passed, if the
+    /// component can be built out of its source tree
+    build_dir_var: Option<String>,
+    /// Toolchain of the component, if it is not the one of the target
+    toolchain: Option<Toolchain>,
+    arch: Option<String>,
+    cross_compile: bool,
+    target: String,
+    vars: Vec<(String, String)>,
+    artifacts: Vec<Artifact>,
+    jobs: usize,
+    /// Components whose artifacts are consumed by this one
+    dependencies: Vec<Input>,
+    /// Artifacts produced by other components, passed as make variables
+    inputs: Vec<(String, PathBuf)>,
+}
+
+impl Component for Generic {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        self.source.fetch()?;
+        Ok(())
+    }
+
+    fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>> {
+        let toolchain = self.toolchain.as_ref().unwrap_or(toolchain);
+        if self.cross_compile {
+            toolchain.fetch()?;
+        }
+        self.source.ensure_fetched()?;
+
+        let mut make_cmd = Command::new("make");
+        make_cmd
+            .arg("-C").arg(self.source.source_dir.clone())
+            .arg(format!("-j{}", self.jobs));
+        if let Some(var) = &self.build_dir_var {
+            make_cmd.arg(format!("{}={}", var, self.build_dir.to_str().unwrap()));
+        }
+        if let Some(arch) = &self.arch {
+            make_cmd.arg(format!("ARCH={}", arch));
+        }
+        if self.cross_compile {
+            make_cmd.arg(format!("CROSS_COMPILE={}", toolchain.cross_compile));
+        }
+        for (var, value) in &self.vars {
+            make_cmd.arg(format!("{}={}", var, value));
+        }
+        component::add_inputs(&mut make_cmd, &self.inputs)?;
+        Ok(vec![component::run_make(&mut make_cmd, make_target)?])
+    }
+
+    fn fetched_version(&mut self) -> Result<Option<String>> {
+        self.source.fetched_version()
+    }
+
+    fn new_patches(&self) -> Result<Vec<PathBuf>> {
+        self.source.new_patches()
+    }
+
+    fn build_dir(&self) -> &PathBuf {
+        &self.build_dir
+    }
+
+    fn patches_dir(&self) -> &PathBuf {
+        &self.source.patches_dir
+    }
+
+    fn provenance(&self) -> Result<Provenance> {
+        self.source.provenance()
+    }
+
+    fn default_target(&self) -> &str {
+        &self.target
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        vec![self.source.version_file.clone()]
+    }
+
+    fn version_file(&self) -> Option<&PathBuf> {
+        Some(&self.source.version_file)
+    }
+
+    fn artifacts(&self) -> Vec<Artifact> {
+        self.artifacts.clone()
+    }
+
+    fn inputs(&self) -> Vec<Input> {
+        self.dependencies.clone()
+    }
+
+    fn add_input(&mut self, var: &str, path: PathBuf) {
+        self.inputs.push((var.to_string(), path));
+    }
+}
+
+fn make_patches_dir(base_dir: &PathBuf, name: &str, version: &str) -> PathBuf {
+    let mut path = base_dir.clone();
+    path.push("patches");
+    path.push(name);
+    path.push(version);
+    path
+}
+
+pub fn new(config: &Config, name: &str, kind: &str, interrupt: Interrupt) -> Result<Generic> {
+    let cfg: MakeConfig = config.defined_component(name, kind)?;
+    let version = cfg.version.clone();
+    let expand = |s: &str| s.replace("{version}", &version);
+
+    // Components of kind "make" are named after themselves
+    let kind = if kind == "make" { name } else { kind };
+    let dir = match &cfg.dir {
+        Some(dir) => expand(dir),
+        None => format!("{}-{}", kind, version),
+    };
+
+    let download_dir = component::get_dir(&config.download_dir, name, kind);
+    let source_dir = {
+        let mut path = download_dir.clone();
+        path.push(&dir);
+        path
+    };
+    let build_dir = match &cfg.build_dir_var {
+        Some(_) => {
+            let mut path = component::get_dir(&config.build_dir, name, kind);
+            path.push(&dir);
+            path
+        },
+        None => source_dir.clone(),
+    };
+
+    // The sources are either a git repository, or an archive that is
+    // stored after the directory it contains.
+    let (url, archive, git_rev) = match (&cfg.git, &cfg.url) {
+        (Some(git), _) => {
+            let rev = cfg.rev.as_ref().map(|r| expand(r)).unwrap_or(version.clone());
+            (source::parse_url(name, git)?, PathBuf::from(&dir), Some(rev))
+        },
+        (None, Some(url)) => {
+            let url = source::parse_url(name, &expand(url))?;
+            let archive = format!("{}{}", dir, source::archive_extension(&url)?);
+            (url, PathBuf::from(archive), None)
+        },
+        (None, None) => return error::NoComponentSource{
+            component: name.to_string() }.fail(),
+    };
+
+    let mut v_file = download_dir.clone();
+    v_file.push(format!("{}.version", dir));
+
+    // A dedicated toolchain also provides the architecture
+    let (toolchain, linux_arch) = match &cfg.toolchain {
+        Some(toolchain) => {
+            let toolchain = config.load_toolchain(toolchain)?;
+            (Some(toolchain::from_config(config, &toolchain)?), toolchain.linux_arch)
+        },
+        None => (None, config.toolchain.linux_arch.clone()),
+    };
+
+    let artifacts = cfg.artifacts.iter().map(|a| {
+        let mut path = build_dir.clone();
+        path.push(&a.file);
+        Artifact { name: a.name.clone(), path: path }
+    }).collect();
+    let dependencies = cfg.inputs.iter().map(|i| Input {
+        component: i.component.clone(),
+        artifact: i.artifact.clone(),
+        var: i.var.clone(),
+    }).collect();
+
+    Ok(Generic {
+        name: name.to_string(),
+        source: Source {
+            pretty: cfg.name.clone().unwrap_or(name.to_string()),
+            url: url,
+            archive: archive,
+            git_rev: git_rev,
+            download_dir: download_dir,
+            source_dir: source_dir,
+            version_file: v_file,
+            patches_dir: make_patches_dir(&config.lib_dir, name, &version),
+            version: version.clone(),
+            interrupt: interrupt,
+        },
+        build_dir: build_dir,
+        build_dir_var: cfg.build_dir_var,
+        toolchain: toolchain,
+        arch: if cfg.arch { Some(linux_arch) } else { None },
+        cross_compile: cfg.cross_compile,
+        target: cfg.target.clone().unwrap_or("all".to_string()),
+        vars: cfg.vars.iter().map(|(k, v)| (k.clone(), expand(v))).collect(),
+        artifacts: artifacts,
+        jobs: config.jobs,
+        dependencies: dependencies,
+        inputs: Vec::new(),
+    })
+}
@@ -0,0 +1,386 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::Seek;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, OptionExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::boot;
+use crate::boot::Boot;
+use crate::component::Component;
+use crate::config::{Config, Filesystem, PartitionConfig, PartitionContent, PartitionTable};
+use crate::interrupt::Interrupt;
+use crate::linux;
+use crate::partition;
+use crate::partition::{Partition, SECTOR};
+use crate::uboot;
+use crate::uboot::EnvLocation;
+
+const MIB: u64 = 1024 * 1024;
+
+/// File written raw on the disk, outside of any partition
+struct Raw {
+    path: PathBuf,
+    offset: u64,
+    size: u64,
+}
+
+/// Parse a size, expressed in bytes and optionally suffixed by K, M or G
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1024),
+        Some('M') | Some('m') => (&size[..size.len() - 1], MIB),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1024 * MIB),
+        _ => (size, 1),
+    };
+    let number: u64 = number.trim().parse().ok().context(error::InvalidSize{
+        size: size.to_string() })?;
+    Ok(number * unit)
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+/// Retrieve the files that the U-Boot components of the target write raw
+/// on the boot device. They must have been built.
+fn raw_files(config: &Config, interrupt: &Interrupt) -> Result<Vec<Raw>> {
+    let mut raws = Vec::new();
+    for name in config.components.keys() {
+        if config.component_kind(name)? != "uboot" {
+            continue;
+        }
+        let uboot = uboot::new(config, name, interrupt.clone())?;
+        for (path, offset) in uboot.raw_artifacts() {
+            ensure!(path.is_file(), error::FileDoesNotExist{ path: path.clone() });
+            let size = std::fs::metadata(&path)
+                .context(error::FailedToRead{ path: path.clone() })?
+                .len();
+            raws.push(Raw { path: path, offset: offset, size: size });
+        }
+    }
+    Ok(raws)
+}
+
+/// Name of a partition, as used in messages, labels, and GPT entries
+fn partition_name(cfg: &PartitionConfig, index: usize) -> String {
+    match (&cfg.label, cfg.content) {
+        (Some(label), _) => label.clone(),
+        (None, PartitionContent::Boot) => "boot".to_string(),
+        (None, PartitionContent::Rootfs) => "rootfs".to_string(),
+        (None, PartitionContent::Empty) => format!("part{}", index + 1),
+    }
+}
+
+/// Place the partitions on the disk. Partitions without a start begin at the
+/// first MiB boundary after what precedes them, and the last one may take
+/// the remaining space.
+fn place(config: &[PartitionConfig], table: PartitionTable, disk_size: u64,
+         raws: &[Raw]) -> Result<Vec<Partition>> {
+    let reserved = partition::reserved(table, disk_size);
+    let usable_end = match table {
+        PartitionTable::Mbr => disk_size,
+        PartitionTable::Gpt => reserved[1].0,
+    };
+    let mut used: Vec<(String, (u64, u64))> = reserved.iter()
+        .map(|range| ("the partition table".to_string(), *range))
+        .collect();
+    for raw in raws {
+        let range = (raw.offset, raw.offset + raw.size);
+        let what = format!("{:#?}", raw.path);
+        ensure!(range.1 <= disk_size, error::LayoutOverflow{ what: what.clone() });
+        if let Some((other, _)) = used.iter().find(|(_, r)| overlaps(*r, range)) {
+            return error::LayoutOverlap{ first: what, second: other.clone() }.fail();
+        }
+        used.push((what, range));
+    }
+
+    let mut cursor = used.iter().map(|(_, r)| r.1).filter(|end| *end < usable_end).max().unwrap_or(0);
+    let mut partitions = Vec::new();
+    for (index, cfg) in config.iter().enumerate() {
+        let name = partition_name(cfg, index);
+        let start = match &cfg.start {
+            Some(start) => parse_size(start)?,
+            None => align_up(cursor, MIB),
+        };
+        let size = match &cfg.size {
+            Some(size) => parse_size(size)?,
+            None => {
+                ensure!(index == config.len() - 1, error::MissingPartitionSize{
+                    partition: name.clone() });
+                ensure!(start < usable_end, error::LayoutOverflow{ what: name.clone() });
+                (usable_end - start) / SECTOR * SECTOR
+            },
+        };
+        ensure!(start % SECTOR == 0 && size % SECTOR == 0 && size > 0, error::InvalidPartitionSize{
+            partition: name.clone(), sector: SECTOR });
+        let range = (start, start + size);
+        ensure!(range.1 <= usable_end, error::LayoutOverflow{ what: name.clone() });
+        if let Some((other, _)) = used.iter().find(|(_, r)| overlaps(*r, range)) {
+            return error::LayoutOverlap{ first: name, second: other.clone() }.fail();
+        }
+        used.push((name.clone(), range));
+        cursor = range.1;
+
+        partitions.push(Partition {
+            name: name,
+            start: start,
+            size: size,
+            filesystem: cfg.filesystem,
+            bootable: cfg.bootable,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Copy a file in a directory, under a given name
+fn copy_file(from: &PathBuf, dir: &PathBuf, name: &str) -> Result<()> {
+    std::fs::create_dir_all(dir).context(error::CreateDirError{ path: dir.clone() })?;
+    ensure!(from.is_file(), error::FileDoesNotExist{ path: from.clone() });
+    let mut to = dir.clone();
+    to.push(name);
+    std::fs::copy(from, &to).context(error::CopyFailed{ from: from.clone(), to: to.clone() })?;
+    Ok(())
+}
+
+fn file_name(path: &PathBuf) -> Result<String> {
+    Ok(path.file_name()
+        .context(error::IllFormedPath{ path: path.clone() })?
+        .to_str().unwrap().to_string())
+}
+
+/// Gather the files of a boot partition: the kernel image at its root, and
+/// the device trees in dtbs/. With a boot flow, the kernel is named as the
+/// boot script expects, and the boot script and environment file (if any)
+/// are added.
+fn stage_boot(config: &Config, interrupt: &Interrupt, staging: &PathBuf,
+              boot: Option<&Boot>, env_file: Option<&(PathBuf, String)>) -> Result<()> {
+    let name = config.first_component("linux")
+        .context(error::NoComponent{ component: "linux".to_string() })?;
+    let mut linux = linux::new(config, &name, interrupt.clone())?;
+    let version = linux.fetched_version()?.context(error::LinuxNotFetched{})?;
+    let mut dtbs = staging.clone();
+    dtbs.push("dtbs");
+    for artifact in linux.artifacts() {
+        if artifact.name == "kernel" {
+            let name = match boot {
+                Some(boot) => boot.kernel(&version),
+                None => file_name(&artifact.path)?,
+            };
+            copy_file(&artifact.path, staging, &name)?;
+        } else {
+            copy_file(&artifact.path, &dtbs, &file_name(&artifact.path)?)?;
+        }
+    }
+    if let Some(boot) = boot {
+        copy_file(&boot.write_script(&version, linux.source_date_epoch())?, staging, "boot.scr")?;
+    }
+    if let Some((path, name)) = env_file {
+        copy_file(path, staging, name)?;
+    }
+    Ok(())
+}
+
+fn run(cmd: &mut Command, proc: &str) -> Result<()> {
+    let status = cmd
+        .stdin(Stdio::null())
+        .status()
+        .context(error::ProgFailed{ proc: proc.to_string() })?;
+    ensure!(status.success(), error::ImageFailed{ proc: proc.to_string() });
+    Ok(())
+}
+
+/// Arguments of mkfs.ext4 to create a filesystem populated with the
+/// contents of a directory
+fn mkfs_ext4_args(label: &str, dir: &PathBuf, file: &PathBuf, size: u64) -> Vec<String> {
+    vec![
+        "-q".to_string(), "-F".to_string(),
+        "-L".to_string(), label.to_string(),
+        "-d".to_string(), dir.to_str().unwrap().to_string(),
+        file.to_str().unwrap().to_string(),
+        format!("{}k", size / 1024),
+    ]
+}
+
+/// Create the filesystem of a partition in its own file, without mounting
+/// anything. A root filesystem is unpacked and packed under fakeroot, so
+/// the ownership of its files is preserved.
+fn make_filesystem(part: &Partition, fs: Filesystem, staging: &PathBuf,
+                   rootfs: Option<&PathBuf>, file: &PathBuf) -> Result<()> {
+    info!("Creating the {:?} filesystem of partition {}", fs, part.name);
+    if file.exists() {
+        std::fs::remove_file(file).context(error::RemoveFileError{ path: file.clone() })?;
+    }
+    match (fs, rootfs) {
+        (Filesystem::Ext4, Some(tarball)) => run(Command::new("fakeroot")
+            .arg("--")
+            .arg("sh").arg("-c")
+            .arg("tar -xpf \"$1\" -C \"$2\" && shift 2 && exec mkfs.ext4 \"$@\"")
+            .arg("sh").arg(tarball).arg(staging)
+            .args(mkfs_ext4_args(&part.name, staging, file, part.size)), "fakeroot"),
+        (Filesystem::Ext4, None) => run(Command::new("mkfs.ext4")
+            .arg("-E").arg("root_owner=0:0")
+            .args(mkfs_ext4_args(&part.name, staging, file, part.size)), "mkfs.ext4"),
+        (Filesystem::Fat, Some(_)) => error::RootfsNotExt4{
+            partition: part.name.clone() }.fail(),
+        (Filesystem::Fat, None) => {
+            run(Command::new("mkfs.vfat")
+                .arg("-C")
+                .arg("-n").arg(&part.name)
+                .arg(file)
+                .arg(format!("{}", part.size / 1024)), "mkfs.vfat")?;
+            let entries = std::fs::read_dir(staging).context(
+                error::DirIterFailed{ dir: staging.clone() })?;
+            for entry in entries {
+                let path = entry.context(error::DirIterFailed{ dir: staging.clone() })?.path();
+                run(Command::new("mcopy")
+                    .env("MTOOLS_SKIP_CHECK", "1")
+                    .arg("-s")
+                    .arg("-i").arg(file)
+                    .arg(&path)
+                    .arg("::/"), "mcopy")?;
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Copy a file in the disk image, at a given offset
+fn write_into(image: &mut std::fs::File, image_path: &PathBuf, from: &PathBuf, offset: u64) -> Result<()> {
+    let mut input = std::fs::File::open(from).context(error::FailedToRead{ path: from.clone() })?;
+    image.seek(std::io::SeekFrom::Start(offset))
+        .context(error::FailedToWrite{ path: image_path.clone() })?;
+    std::io::copy(&mut input, image).context(error::FailedToWrite{ path: image_path.clone() })?;
+    Ok(())
+}
+
+/// Assemble the disk image of the target, as described by its [image]
+/// section, from what the Linux and U-Boot builds produced. Everything is
+/// done in userspace: filesystems are created in files that are then
+/// copied in the image. rootfs overrides the root filesystem tarball of
+/// the layout. The path to the image is returned.
+pub fn make(config: &Config, interrupt: Interrupt, output: Option<PathBuf>,
+            rootfs: Option<PathBuf>) -> Result<PathBuf> {
+    let layout = config.image.as_ref().context(error::NoImageLayout{})?;
+    let disk_size = parse_size(&layout.size)?;
+    ensure!(disk_size % SECTOR == 0 && disk_size > 0, error::InvalidPartitionSize{
+        partition: "image".to_string(), sector: SECTOR });
+    let min = partition::min_disk_size(layout.table);
+    ensure!(disk_size >= min, error::DiskTooSmall{ min: min });
+
+    let mut raws = raw_files(config, &interrupt)?;
+
+    // The environment of U-Boot goes either in the boot partition, or at a
+    // fixed offset of the disk
+    let boot = boot::new(config)?;
+    let mut env_file = None;
+    if let (Some(boot), Some(name)) = (&boot, config.first_component("uboot")) {
+        let uboot = uboot::new(config, &name, interrupt.clone())?;
+        let env = uboot.env_image(&boot.env())?;
+        match uboot.env_location()? {
+            EnvLocation::File(name) => env_file = Some((env, name)),
+            EnvLocation::Raw(offsets) => for offset in offsets {
+                let size = std::fs::metadata(&env)
+                    .context(error::FailedToRead{ path: env.clone() })?
+                    .len();
+                raws.push(Raw { path: env.clone(), offset: offset, size: size });
+            },
+            EnvLocation::Unknown => warn!("U-Boot does not store its environment on the disk. \
+                It is not part of the image."),
+        }
+    }
+    let partitions = place(&layout.partitions, layout.table, disk_size, &raws)?;
+
+    let mut work_dir = config.build_dir.clone();
+    work_dir.push("images");
+    work_dir.push(&config.target);
+    if work_dir.exists() {
+        std::fs::remove_dir_all(&work_dir).context(
+            error::RemoveDirError{ path: work_dir.clone() })?;
+    }
+
+    // Each filesystem is created in its own file
+    let mut filesystems = Vec::new();
+    for (index, (part, cfg)) in partitions.iter().zip(&layout.partitions).enumerate() {
+        let fs = match cfg.filesystem {
+            Some(fs) => fs,
+            None => continue,
+        };
+        let mut staging = work_dir.clone();
+        staging.push(format!("p{}", index + 1));
+        std::fs::create_dir_all(&staging).context(
+            error::CreateDirError{ path: staging.clone() })?;
+
+        let tarball = match cfg.content {
+            PartitionContent::Rootfs => {
+                let tarball = match (&rootfs, &cfg.rootfs) {
+                    (Some(tarball), _) => tarball.clone(),
+                    (None, Some(tarball)) => {
+                        let mut path = config.lib_dir.clone();
+                        path.push(tarball);
+                        path
+                    },
+                    (None, None) => return error::MissingRootfs{
+                        partition: part.name.clone() }.fail(),
+                };
+                ensure!(tarball.is_file(), error::FileDoesNotExist{ path: tarball.clone() });
+                Some(tarball)
+            },
+            PartitionContent::Boot => {
+                stage_boot(config, &interrupt, &staging, boot.as_ref(), env_file.as_ref())?;
+                None
+            },
+            PartitionContent::Empty => None,
+        };
+        for file in &cfg.files {
+            let mut path = config.lib_dir.clone();
+            path.push(file);
+            copy_file(&path, &staging, &file_name(&path)?)?;
+        }
+
+        let mut file = work_dir.clone();
+        file.push(format!("p{}.img", index + 1));
+        make_filesystem(part, fs, &staging, tarball.as_ref(), &file)?;
+        let size = std::fs::metadata(&file)
+            .context(error::FailedToRead{ path: file.clone() })?
+            .len();
+        ensure!(size <= part.size, error::LayoutOverflow{ what: format!("{:#?}", file) });
+        filesystems.push((file, part.start));
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let mut path = config.build_dir.clone();
+            path.push("images");
+            path.push(format!("{}.img", config.target));
+            path
+        },
+    };
+    info!("Assembling disk image {:#?}", output);
+    {
+        let mut image = std::fs::File::create(&output).context(
+            error::CreateFileError{ path: output.clone() })?;
+        image.set_len(disk_size).context(error::FailedToWrite{ path: output.clone() })?;
+        for raw in &raws {
+            write_into(&mut image, &output, &raw.path, raw.offset)?;
+        }
+        for (file, start) in &filesystems {
+            write_into(&mut image, &output, file, *start)?;
+        }
+    }
+    partition::write(layout.table, &output, disk_size, &partitions, &config.target)?;
+    Ok(output)
+}
@@ -8,6 +8,7 @@ use crate::error;
 use snafu::{ResultExt};
 use log::*;
 
+#[derive(Clone)]
 pub struct Interrupt {
     must_stop: Arc<AtomicBool>,
     locked: Arc<AtomicBool>,
@@ -13,14 +13,34 @@ use snafu::{ResultExt, ensure};
 
 use crate::error::Result;
 use crate::error;
+use crate::boot;
+use crate::boot::Boot;
+use crate::changes;
+use crate::debian;
 use crate::download;
 use crate::decompress;
+use crate::efi;
+use crate::efi::Efi;
 use crate::toolchain::Toolchain;
-use crate::config::Config;
+use crate::config;
+use crate::config::{Config, LinuxConfig, RevisionScheme};
+use crate::component;
+use crate::component::{Component, Artifact, MakeInvocation, Packages, Provenance};
 use crate::interrupt::Interrupt;
+use crate::modules;
+use crate::modules::Module;
+use crate::packaging;
+use crate::packaging::{Packaging, Package, Metadata};
 use crate::patch;
+use crate::reproducible;
+use crate::rpm;
+use crate::signing;
+use crate::signing::SigningKey;
 use crate::util;
+use crate::version_file;
+use crate::version_file::VersionFile;
 
+#[derive(Clone, Copy, PartialEq)]
 struct Version {
     maj: usize,
     min: usize,
@@ -33,9 +53,17 @@ impl std::fmt::Display for Version {
     }
 }
 
+/// Version of the packages produced by bindeb-pkg, when the packaging of
+/// the target uses a fixed revision scheme
+const KDEB_PKGVERSION: &str = "1";
+
 pub struct Linux {
     version: Version,
+    /// Contents of the version file, that records the library patches
+    /// applied on the sources
+    recorded: VersionFile,
     version_file: PathBuf,
+    changes_file: PathBuf,
     download_dir: PathBuf,
     source_dir: PathBuf,
     patches_dir: PathBuf,
@@ -47,9 +75,27 @@ pub struct Linux {
     target: String,
     interrupt: Interrupt,
     arch: String,
-    name: String,
+    target_name: String,
+    component: String,
     debian_arch: String,
+    rpm_arch: String,
     jobs: usize,
+    /// Out-of-tree modules, built against the kernel
+    modules: Vec<Module>,
+    /// Packages of bindeb-pkg to be shipped. None means all of them.
+    packages: Option<Vec<String>>,
+    dtbs: Vec<PathBuf>,
+    /// Kernel image, relative to arch/<arch>/boot
+    image: String,
+    /// Boot flow, whose script is shipped in the meta-package
+    boot: Option<Boot>,
+    /// Key signing the modules, when the kernel signs them
+    signing_key: SigningKey,
+    /// Whether the out-of-tree modules are signed as well
+    sign_modules: bool,
+    /// Signer of the kernel image for EFI secure boot
+    efi: Option<Efi>,
+    packaging: Packaging,
 }
 
 impl Linux {
@@ -58,20 +104,18 @@ impl Linux {
     /// to take place
     fn load_version(&mut self) -> Result<()> {
         ensure!(self.version_file.exists(), error::LinuxNotFetched{});
-        let data = util::read_file(&self.version_file)?;
-        self.version = make_version(&data)?;
+        self.recorded = version_file::load(&self.version_file)?;
+        self.version = make_version(&self.recorded.version)?;
         Ok(())
     }
 
-    /// Dump the current Linux version in the version file.
+    /// Dump the current Linux version in the version file, along with the
+    /// library patches that were applied so far.
     /// This allows for successive calls to mktcb to keep track of the next
     /// updates of the Linux kernel.
-    fn write_version(&self) -> Result<()> {
-        let mut file = std::fs::File::create(&self.version_file).context(
-            error::CreateFileError{path: self.version_file.clone()})?;
-        write!(file, "{}", self.version)
-            .context(error::FailedToWrite{path: self.version_file.clone()})?;
-        Ok(())
+    fn write_version(&mut self) -> Result<()> {
+        self.recorded.version = self.version.to_string();
+        version_file::write(&self.version_file, &self.recorded)
     }
 
     /// Depending on whether the micro is 0 or not, the patch file does not
@@ -80,14 +124,19 @@ impl Linux {
     /// This function returns the URL pointing to the expected patch file
     /// allowing to bump the version.
     fn get_next_patch_url(&self) -> Result<(url::Url, String)> {
-        if self.version.mic == 0 {
+        self.get_patch_url_from(&self.version)
+    }
+
+    /// Same as get_next_patch_url(), but from an arbitrary version
+    fn get_patch_url_from(&self, version: &Version) -> Result<(url::Url, String)> {
+        if version.mic == 0 {
             let file = format!("patch-{}.{}.{}.xz",
-                self.version.maj, self.version.min, self.version.mic + 1);
+                version.maj, version.min, version.mic + 1);
             let url = self.base_url.join(&file).context(error::InvalidLinuxURL{})?;
             Ok((url, file))
         } else {
             let file = format!("patch-{}-{}.xz",
-                self.version, self.version.mic + 1);
+                version, version.mic + 1);
             let url = self.base_url.join("incr/")
                 .context(error::InvalidLinuxURL{})
                 .and_then(|u| {
@@ -97,24 +146,144 @@ impl Linux {
         }
     }
 
-    /// Retrieve the path to the debian package containing the
-    /// linux-image.
-    /// Upon success, the file is guaranteed to be valid.
-    fn get_linux_image_deb_pkg(&self) -> Result<PathBuf> {
-        // Debian packages (images) have the following form:
-        //     ../linux-image-5.4.38_1_armhf.deb
-        // relative to the linux build directory.
-        // 5.4.38 is obviously the version, and 1 is the debian revision, that
-        // is enforced via a make variable
-        let base = format!("linux-image-{}_1_{}.deb",
-            self.version, self.debian_arch);
+    /// Compose the path to a debian package produced by bindeb-pkg. It has
+    /// the following form:
+    ///     ../linux-image-5.4.38_1_armhf.deb
+    /// relative to the linux build directory.
+    /// 5.4.38 is obviously the version, and 1 is the package version, that
+    /// is enforced via a make variable (image_version).
+    fn get_deb_pkg(&self, package: &str, image_version: &packaging::Version) -> PathBuf {
         let mut path = self.build_dir.clone();
         path.pop();
-        path.push(base);
+        path.push(format!("{}_{}_{}.deb", package,
+            image_version.without_epoch(), self.debian_arch));
+        path
+    }
+
+    /// Remove the debian packages that a previous run of bindeb-pkg left.
+    /// Otherwise, a package that the current configuration does not produce
+    /// anymore would still be picked up.
+    fn remove_deb_pkgs(&self, image_version: &packaging::Version) -> Result<()> {
+        for (_, package) in bindeb_packages(&self.version) {
+            let path = self.get_deb_pkg(&package, image_version);
+            if path.exists() {
+                std::fs::remove_file(&path).context(error::RemoveFileError{ path: path.clone() })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Retrieve the paths to the debian packages produced by bindeb-pkg,
+    /// that the target ships.
+    /// Upon success, the files are guaranteed to be valid.
+    fn get_deb_pkgs(&self, image_version: &packaging::Version) -> Result<Vec<PathBuf>> {
+        let mut result = Vec::new();
+        for (kind, package) in bindeb_packages(&self.version) {
+            let path = self.get_deb_pkg(&package, image_version);
+
+            // Packages explicitly selected by the target must exist. By
+            // default, only the image is mandatory: the other ones depend
+            // on the kernel configuration.
+            let required = match &self.packages {
+                Some(packages) => packages.iter().any(|p| p == kind),
+                None => kind == "image",
+            };
+            if required {
+                ensure!(path.is_file(), error::NoPackage{ path: path.clone()});
+            } else if self.packages.is_some() || ! path.is_file() {
+                continue;
+            }
+            result.push(path);
+        }
+        Ok(result)
+    }
+
+    /// Name of the meta-package, that depends on the latest kernel of the
+    /// X.Y series
+    fn get_meta_package(&self) -> String {
+        format!("linux-image-{}.{}-{}", self.version.maj, self.version.min, self.target)
+    }
+
+    /// Metadata of the meta-package, whatever the package format
+    fn get_meta_metadata(&self, package: &str, build: u64) -> Result<Metadata> {
+        let mut meta = self.packaging.metadata(&Package {
+            name: package,
+            version: &self.version.to_string(),
+            summary: format!("Linux kernel, version {}.{}.z for {}",
+                self.version.maj, self.version.min, self.target_name),
+            section: "custom/kernel",
+            priority: "required",
+        }, build)?;
+        meta.description.push("This is a meta-package allowing to manage updates of the Linux kernel".to_string());
+        meta.description.push(format!("for the {}", self.target_name));
+        Ok(meta)
+    }
 
-        // Check that the debian package exist before returning its path
-        ensure!(path.is_file(), error::NoPackage{ path: path.clone()});
-        Ok(path)
+    /// Build a package containing the device trees of the board, so they can
+    /// be updated independently from the kernel. They are installed in
+    /// /boot/dtbs.
+    fn dtbs_debpkg(&self, build: u64) -> Result<PathBuf> {
+        let package = format!("linux-dtbs-{}", self.target);
+        let deb_dir = debian::prepare(&self.pkg_dir, &package)?;
+
+        let mut dest = deb_dir.clone();
+        dest.push("boot");
+        dest.push("dtbs");
+        std::fs::create_dir_all(&dest).context(
+            error::CreateDirError{ path: dest.clone() })?;
+        for dtb in &self.dtbs {
+            let from = self.get_dtb(dtb);
+            let mut to = dest.clone();
+            to.push(from.file_name().unwrap());
+            std::fs::copy(&from, &to).context(error::CopyFailed{
+                from: from.clone(),
+                to: to.clone(),
+            })?;
+        }
+
+        let version = self.version.to_string();
+        let mut meta = self.packaging.metadata(&Package {
+            name: &package,
+            version: &version,
+            summary: format!("Device trees of the {}", self.target_name),
+            section: "custom/kernel",
+            priority: "optional",
+        }, build)?;
+        meta.description.push(format!("Device trees built from Linux {}", self.version));
+        let control = debian::control(&meta, &self.debian_arch);
+        debian::write_file(&deb_dir, "DEBIAN/control", &control, false)?;
+        debian::build(&self.pkg_dir, &package, self.source_date_epoch())
+    }
+
+    /// Retrieve the path to a device tree, as produced by the build
+    fn get_dtb(&self, dtb: &PathBuf) -> PathBuf {
+        let mut path = self.get_boot_dir();
+        path.push("dts");
+        path.push(dtb);
+        path
+    }
+
+    /// Sign the kernel image for EFI secure boot, and build the unified
+    /// kernel image, if the target requests them
+    fn efi_images(&self) -> Result<Option<efi::Images>> {
+        let efi = match &self.efi {
+            Some(efi) => efi,
+            None => return Ok(None),
+        };
+        let mut kernel = self.get_boot_dir();
+        kernel.push(&self.image);
+        let dtb = efi.dtb().or(self.dtbs.first()).map(|dtb| self.get_dtb(dtb));
+        Ok(Some(efi.build(&self.version.to_string(), &kernel, dtb, self.source_date_epoch())?))
+    }
+
+    /// Compose the path to the directory in which the build produces the
+    /// kernel images
+    fn get_boot_dir(&self) -> PathBuf {
+        let mut path = self.build_dir.clone();
+        path.push("arch");
+        path.push(&self.arch);
+        path.push("boot");
+        path
     }
 
     /// Download the whole source tree of the Linux kernel. They will
@@ -137,33 +306,51 @@ impl Linux {
         // We now have the full source tree. They MAY be patched. If a signal
         // happens between patching and writing the version, the whole source
         // tree will get corrupted (we cannot possibly know, without great manual
-        // effort in which state it was left).
-        // So, prevent SIGINT to destroy the directory.
-        self.interrupt.lock();
+        // effort in which state it was left). So, SIGINT is held off meanwhile.
+        // The files of the archive are dated at the release
+        let epoch = reproducible::tree_epoch(&self.source_dir)?;
         self.reconfigure()?;
-        // We have just downloaded the sources. Apply patches, if any.
-        self.apply_patches()?;
-        // Finally, store the version
-        self.write_version()
+        version_file::patch_and_record(&self.version_file, &self.version.to_string(), epoch,
+            &self.interrupt, &self.get_version_patches_dir(&self.version),
+            &self.patches_dir, &self.source_dir)?;
+        self.load_version()
     }
 
     /// Go over the patches for a given version of Linux, if they exist, and
     /// apply them to the source tree.
     /// NOTE: this function is called when the lock for patches is taken.
     /// Don't lock!!
-    fn apply_patches(&self) -> Result<()> {
-        let mut try_path = self.patches_dir.clone();
-        try_path.push(if self.version.mic == 0 {
-            format!("{}.{}", self.version.maj, self.version.min)
+    fn apply_patches(&mut self) -> Result<()> {
+        let dir = self.get_version_patches_dir(&self.version);
+        self.recorded.apply_all(&dir, &self.patches_dir, &self.source_dir)
+    }
+
+    /// List the library patches of every version of Linux up to the current
+    /// one
+    fn library_patches(&self) -> Result<Vec<PathBuf>> {
+        let mut patches = Vec::new();
+        for mic in 0..=self.version.mic {
+            let dir = self.get_version_patches_dir(&Version { mic: mic, ..self.version });
+            patches.extend(patch::list_patches_in(&dir)?);
+        }
+        Ok(patches)
+    }
+
+    /// Compose the path to the directory holding the library patches of a
+    /// given version of Linux
+    fn get_version_patches_dir(&self, version: &Version) -> PathBuf {
+        let mut path = self.patches_dir.clone();
+        path.push(if version.mic == 0 {
+            format!("{}.{}", version.maj, version.min)
         } else {
-            format!("{}", self.version)
+            format!("{}", version)
         });
-
-        patch::apply_patches_in(&try_path, &self.source_dir)
+        path
     }
 
 
-    /// Generate the command to call make in Linux' sources
+    /// Generate the command to call make in Linux' sources. The build is
+    /// dated with the sources, so that it can be reproduced.
     fn get_make_cmd(&self, toolchain: &Toolchain) -> Command {
         let mut make_cmd = Command::new("make");
         make_cmd
@@ -172,19 +359,177 @@ impl Linux {
             .arg(format!("O={}", self.build_dir.to_str().unwrap()))
             .arg(format!("ARCH={}", self.arch))
             .arg(format!("CROSS_COMPILE={}", toolchain.cross_compile));
+        reproducible::set_env(&mut make_cmd, self.source_date_epoch());
         make_cmd
     }
 
-    pub fn fetch(&mut self) -> Result<()> {
+    /// Retrieve where the sources of the out-of-tree modules come from, by
+    /// module name and version
+    pub fn module_provenances(&self) -> Result<Vec<(String, String, Provenance)>> {
+        let mut provenances = Vec::new();
+        for module in &self.modules {
+            provenances.push((module.name.clone(), module.version.clone(), module.provenance()?));
+        }
+        Ok(provenances)
+    }
+
+    /// Build the out-of-tree modules against the kernel. The invocations of
+    /// make are returned.
+    fn make_modules(&self, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>> {
+        let mut invocations = Vec::new();
+        for module in &self.modules {
+            info!("Building out-of-tree module {}", module.name);
+            invocations.push(module.make(self.get_make_cmd(toolchain))?);
+        }
+        Ok(invocations)
+    }
+
+    /// Summarize the changes brought by the incremental patches that are
+    /// available on kernel.org but have not been fetched yet. The summary is
+    /// printed and recorded in the changes file, so it can be integrated to
+    /// the changelog of the Debian meta-package once the sources are fetched.
+    pub fn changes(&mut self) -> Result<()> {
+        self.load_version()?;
+        let from = self.version;
+        let mut version = self.version;
+        let mut changes = changes::Changes::default();
+
+        loop {
+            let (url, file) = self.get_patch_url_from(&version)?;
+            if ! download::check(&mut self.http_handle, &url)? {
+                break;
+            }
+            version.mic += 1;
+            info!("Inspecting changes of version {}", version);
+
+            let mut path = self.download_dir.clone();
+            path.push(file);
+            download::to_file(&mut self.http_handle, &url, &path)?;
+            let diff_file = decompress::xz(&path)?;
+            changes.add_diff(&diff_file)?;
+
+            // kernel.org also publishes the list of commits that compose
+            // each stable release.
+            let file = format!("ChangeLog-{}", version);
+            let url = self.base_url.join(&file).context(error::InvalidLinuxURL{})?;
+            if download::check(&mut self.http_handle, &url)? {
+                let mut path = self.download_dir.clone();
+                path.push(file);
+                download::to_file(&mut self.http_handle, &url, &path)?;
+                changes.add_changelog(&path)?;
+            }
+        }
+
+        if version == from {
+            info!("No changes: {} is the last version", from);
+            return Ok(());
+        }
+
+        // Compose the summary of the changes. The first line holds the
+        // versions, so the summary can be matched against the version of
+        // the sources when building the Debian meta-package.
+        let mut summary = String::new();
+        let added: usize = changes.files.values().map(|s| s.added).sum();
+        let removed: usize = changes.files.values().map(|s| s.removed).sum();
+        let compiled: Vec<&String> = changes.files.keys()
+            .filter(|f| changes::is_compiled(&self.build_dir, f))
+            .collect();
+        summary.push_str(&format!("{} {}\n", from, version));
+        summary.push_str(&format!("Linux {} -> {}: {} commits, {} files changed, +{} -{}\n",
+            from, version, changes.subjects.len(), changes.files.len(), added, removed));
+        summary.push_str(&format!("{} of the modified files are compiled for the {}\n",
+            compiled.len(), self.target_name));
+        summary.push_str("Subsystems:\n");
+        for (subsystem, (count, stat)) in changes.subsystems() {
+            summary.push_str(&format!("  {:<32} {:>5} files  +{:<6} -{}\n",
+                subsystem, count, stat.added, stat.removed));
+        }
+        if ! compiled.is_empty() {
+            summary.push_str("Compiled files:\n");
+            for file in compiled {
+                summary.push_str(&format!("  {}\n", file));
+            }
+        }
+        if ! changes.subjects.is_empty() {
+            summary.push_str("Commits:\n");
+            for subject in &changes.subjects {
+                summary.push_str(&format!("  {}\n", subject));
+            }
+        }
+
+        // Skip the first line, which is not meant for humans
+        for line in summary.lines().skip(1) {
+            println!("{}", line);
+        }
+        let mut file = std::fs::File::create(&self.changes_file).context(
+            error::CreateFileError{path: self.changes_file.clone()})?;
+        file.write_all(summary.as_bytes())
+            .context(error::FailedToWrite{path: self.changes_file.clone()})?;
+        Ok(())
+    }
+
+    /// If the changes recorded by changes() lead to the current version of
+    /// the sources, write them as the Debian changelog of the meta-package.
+    fn write_changelog(&self, deb_dir: &PathBuf, meta: &Metadata) -> Result<()> {
+        let package = &meta.package;
+        if ! self.changes_file.exists() {
+            return Ok(());
+        }
+        let summary = util::read_file(&self.changes_file)?;
+        let mut lines = summary.lines();
+        let versions: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+        if versions.len() != 2 || versions[1] != self.version.to_string() {
+            debug!("Changes file {:#?} does not describe version {}",
+                self.changes_file, self.version);
+            return Ok(());
+        }
+
+        let mut changelog = format!("{} ({}) unstable; urgency=medium\n\n",
+            package, meta.version);
+        for line in lines {
+            if line.starts_with(' ') {
+                changelog.push_str(&format!("    {}\n", line.trim()));
+            } else {
+                changelog.push_str(&format!("  * {}\n", line));
+            }
+        }
+        changelog.push_str(&format!("\n -- {}  {}\n",
+            meta.maintainer, util::rfc2822(self.source_date_epoch())));
+
+        let name = format!("usr/share/doc/{}/changelog.Debian", package);
+        debian::write_file(deb_dir, &name, &changelog, false)?;
+        let mut path = deb_dir.clone();
+        path.push(name);
+
+        // Debian policy requires changelogs to be compressed
+        let status = Command::new("gzip")
+            .arg("-9nf")
+            .arg(&path)
+            .stdin(Stdio::null())
+            .status()
+            .context(error::ProgFailed{ proc: "gzip".to_string() })?;
+        ensure!(status.success(), error::DebFailed{package: package.to_string()});
+        Ok(())
+    }
+}
+
+impl Component for Linux {
+    fn name(&self) -> &str {
+        &self.component
+    }
+
+    fn fetch(&mut self) -> Result<()> {
         if ! self.version_file.exists() {
-            ensure!(! self.source_dir.exists(), error::CorruptedSourceDir{
-                dir: self.source_dir.clone(),
-                version_file: self.version_file.clone(),
-            });
+            version_file::ensure_not_corrupted(&self.version_file, &self.source_dir)?;
             info!("File {:#?} not found. Downloading Linux archive...", self.version_file);
             self.download_archive()?;
         } else {
             self.load_version()?;
+            if self.recorded.patches.is_none() {
+                let library = self.library_patches()?;
+                self.recorded.assume(&self.version_file, &library, &self.patches_dir)?;
+                self.write_version()?;
+            }
         }
 
         // And now, we will apply all patches that were released since the
@@ -199,6 +544,7 @@ impl Linux {
                 let mut path = self.download_dir.clone();
                 path.push(file);
                 download::to_file(&mut self.http_handle, &url, &path)?;
+                let released = download::last_modified(&mut self.http_handle);
 
                 // Decompress the downloaded file to get the actual diff.
                 let diff_file = decompress::xz(&path)?;
@@ -206,7 +552,7 @@ impl Linux {
                     // From this point, we will modify the sources. So make
                     // sure that interruptions will not leave the source tree
                     // in a corrupted state.
-                    self.interrupt.lock();
+                    let _guard = self.interrupt.lock();
                     patch::patch(&self.source_dir, &diff_file)?;
 
                     // We have upgraded to a new version of the Linux kernel.
@@ -214,6 +560,9 @@ impl Linux {
                     // version file.
                     self.version.mic += 1;
                     self.apply_patches()?;
+                    if released.is_some() {
+                        self.recorded.epoch = released;
+                    }
                     self.write_version()?;
                 }
             } else {
@@ -222,20 +571,97 @@ impl Linux {
             }
         }
 
+        for module in &self.modules {
+            module.fetch()?;
+        }
         Ok(())
     }
 
     /// Create a copy of the configuration described by the target (if any)
-    pub fn reconfigure(&self) -> Result<()> {
+    fn reconfigure(&self) -> Result<()> {
         // Copy the configuration to the build dir, if any.
         util::copy_config(&self.config, &self.build_dir)
     }
 
+    /// Retrieve the version of the Linux sources that were fetched, if any
+    fn fetched_version(&mut self) -> Result<Option<String>> {
+        if self.version_file.exists() {
+            self.load_version()?;
+            Ok(Some(self.version.to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn build_dir(&self) -> &PathBuf {
+        &self.build_dir
+    }
+
+    fn patches_dir(&self) -> &PathBuf {
+        &self.patches_dir
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.version_file.clone()];
+        files.extend(self.config.clone());
+        files
+    }
+
+    fn version_file(&self) -> Option<&PathBuf> {
+        Some(&self.version_file)
+    }
+
+    /// The sources are the archive of the first release, upgraded by each
+    /// incremental patch up to the fetched version. The library patches of
+    /// each of these versions were applied along the way, as recorded in
+    /// the version file.
+    fn provenance(&self) -> Result<Provenance> {
+        ensure!(self.version_file.exists(), error::LinuxNotFetched{});
+        let recorded = version_file::load(&self.version_file)?;
+        let fetched = make_version(&recorded.version)?;
+        let archive = format!("linux-{}.{}.tar.xz", fetched.maj, fetched.min);
+        let mut path = self.download_dir.clone();
+        path.push(&archive);
+        let mut downloads = vec![(
+            self.base_url.join(&archive).context(error::InvalidLinuxURL{})?, Some(path))];
+        for mic in 0..fetched.mic {
+            let (url, file) = self.get_patch_url_from(&Version { mic: mic, ..fetched })?;
+            let mut path = self.download_dir.clone();
+            path.push(file);
+            downloads.push((url, Some(path)));
+        }
+
+        Ok(Provenance {
+            downloads: downloads,
+            patches: recorded.applied(&self.patches_dir),
+            ..Provenance::default()
+        })
+    }
+
+    /// The kernel image is named kernel. Device trees are named after their
+    /// file (e.g. sun8i-h3-nanopi-r1.dtb).
+    fn artifacts(&self) -> Vec<Artifact> {
+        let mut kernel = self.get_boot_dir();
+        kernel.push(&self.image);
+        let mut artifacts = vec![Artifact {
+            name: "kernel".to_string(),
+            path: kernel,
+        }];
+        for dtb in &self.dtbs {
+            let path = self.get_dtb(dtb);
+            artifacts.push(Artifact {
+                name: path.file_name().unwrap().to_str().unwrap().to_string(),
+                path: path,
+            });
+        }
+        artifacts
+    }
+
     /// Check if a new update patch is present. If not, there are no updates.
     /// If we cannot find the version file, we *assume* the sources were not
     /// retrieved, so they technically can be updated (going from nothing to
     /// something).
-    pub fn check_update(&mut self) -> Result<bool> {
+    fn check_update(&mut self) -> Result<bool> {
         if self.version_file.exists() {
             self.load_version()?;
             let (url, _) = self.get_next_patch_url()?;
@@ -248,98 +674,161 @@ impl Linux {
     /// Build a Debian meta-package allowing to perform easy upgrades of
     /// the Linux kernel.
     /// Upon success, the path to the created debian package is returned.
-    pub fn debpkg(&mut self, toolchain: &Toolchain) -> Result<Vec<PathBuf>> {
+    fn package(&mut self, toolchain: &Toolchain) -> Result<Packages> {
         toolchain.fetch()?;
         self.load_version()?;
-
-        let make_target = "bindeb-pkg";
-        let status = self.get_make_cmd(toolchain)
-            .arg("KDEB_PKGVERSION=1")
-            .arg("--")
-            .arg(make_target)
-            .status()
-            .context(error::ProgFailed{ proc: "make".to_string() })?;
-        ensure!(status.success(), error::MakeFailed{target: make_target.to_string()});
-
-        let mut package = format!("linux-image-{}.{}-{}",
-                self.version.maj, self.version.min, self.target);
-        // Compose the path to the DEBIAN directory and create it.
-        let mut deb_dir = self.pkg_dir.clone();
-        deb_dir.push(&package);
-        let mut deb = deb_dir.clone();
-        deb.push("DEBIAN");
-        std::fs::create_dir_all(&deb).context(
-            error::CreateDirError{ path: deb.clone() })?;
-        deb.push("control");
+        util::refresh_config(&self.config, &self.build_dir)?;
+        self.signing_key.setup(&self.build_dir)?;
+
+        // With a fixed revision scheme, the packages of bindeb-pkg keep the
+        // version they always had. Otherwise, they follow the scheme.
+        let version = self.version.to_string();
+        let build = self.packaging.next_build(&self.component, &version, "deb")?;
+        let image_version = match self.packaging.scheme() {
+            RevisionScheme::Fixed => packaging::Version {
+                epoch: None,
+                upstream: KDEB_PKGVERSION.to_string(),
+                revision: None,
+            },
+            _ => self.packaging.version(&version, build),
+        };
+
+        let mut make_cmd = self.get_make_cmd(toolchain);
+        make_cmd.arg(format!("KDEB_PKGVERSION={}", image_version));
+        self.remove_deb_pkgs(&image_version)?;
+        let mut make = vec![component::run_make(&mut make_cmd, "bindeb-pkg")?];
+
+        let package = self.get_meta_package();
+        let deb_dir = debian::prepare(&self.pkg_dir, &package)?;
 
         // Create the contents of the DEBIAN/control file. It is automatically
         // generated from the current state of the Linux sources.
-        // Note that this is scoped so that the DEBIAN/control file is EFFECTIVELY
-        // flushed to the filesystem before dpkg-deb tries to read it.
-        {
-            let maintainer = util::getenv("MAINTAINER")?;
-            let control = format!("
-Package: {}
-Architecture: {}
-Maintainer: {}
-Description: Linux kernel, version {}.{}.z for {}
- This is a meta-package allowing to manage updates of the Linux kernel
- for the {}
-Depends: linux-image-{}
-Version: {}
-Section: custom/kernel
-Priority: required
-",
-                package,
-                self.debian_arch,
-                maintainer,
-                self.version.maj, self.version.min, self.name,
-                self.name,
-                self.version,
-                self.version);
-            let mut file = std::fs::File::create(&deb)
-                .context(error::CreateFileError{path: deb.clone()})?;
-            file.write_all(control.as_bytes())
-                .context(error::FailedToWrite{
-                    path: deb.clone(),
-                })?;
+        let mut meta = self.get_meta_metadata(&package, build)?;
+        meta.depends.insert(0, format!("linux-image-{} (= {})", self.version, image_version));
+        let control = debian::control(&meta, &self.debian_arch);
+        debian::write_file(&deb_dir, "DEBIAN/control", &control, false)?;
+
+        self.write_changelog(&deb_dir, &meta)?;
+
+        // The boot script loads the kernel of this very version, so it is
+        // upgraded along with it
+        if let Some(boot) = &self.boot {
+            let scr = boot.write_script(&version, self.source_date_epoch())?;
+            let mut to = deb_dir.clone();
+            to.push("boot");
+            std::fs::create_dir_all(&to).context(error::CreateDirError{ path: to.clone() })?;
+            to.push("boot.scr");
+            std::fs::copy(&scr, &to).context(error::CopyFailed{ from: scr.clone(), to: to.clone() })?;
+        }
+
+        // Signed images are shipped along the ones of linux-image, which
+        // bindeb-pkg produces. The unified kernel image goes to the EFI
+        // system partition.
+        if let Some(images) = self.efi_images()? {
+            let mut files = vec![(images.kernel, "boot".to_string())];
+            if let Some(uki) = images.uki {
+                files.push((uki, "boot/efi/EFI/Linux".to_string()));
+            }
+            for (file, dir) in files {
+                let mut to = deb_dir.clone();
+                to.push(dir);
+                std::fs::create_dir_all(&to).context(error::CreateDirError{ path: to.clone() })?;
+                to.push(file.file_name().unwrap());
+                std::fs::copy(&file, &to).context(error::CopyFailed{ from: file.clone(), to: to.clone() })?;
+            }
         }
 
         // Run dpkg-deb to create the meta-package
-        let status = Command::new("dpkg-deb")
-            .arg("--build")
-            .arg(&package)
-            .current_dir(&self.pkg_dir)
-            .stdin(Stdio::null())
-            .status()
-            .context(error::ProgFailed{ proc: "dpkg-deb".to_string() })?;
-        ensure!(status.success(), error::DebFailed{package: package});
+        let result = debian::build(&self.pkg_dir, &package, self.source_date_epoch())?;
 
-        // Finally, return the path to the debian file. Hoping that it
-        // was indeed created.
-        let mut result = self.pkg_dir.clone();
-        package.push_str(".deb");
-        result.push(package);
-        ensure!(result.is_file(), error::NoPackage{path:result.clone()});
+        let mut packages = self.get_deb_pkgs(&image_version)?;
+        packages.push(result);
+        if ! self.dtbs.is_empty() {
+            packages.push(self.dtbs_debpkg(build)?);
+        }
 
-        let image = self.get_linux_image_deb_pkg()?;
-        Ok(vec![
-            image,
-            result,
-        ])
+        // Out-of-tree modules are built against the kernel that was just
+        // packaged, and depend on its package
+        make.extend(self.make_modules(toolchain)?);
+        let signer = if self.sign_modules && ! self.modules.is_empty() {
+            Some(self.signing_key.signer(&self.build_dir)?)
+        } else {
+            None
+        };
+        let image_version = image_version.to_string();
+        let kernel = modules::Kernel {
+            release: &version,
+            image_version: &image_version,
+            debian_arch: &self.debian_arch,
+            signer: signer.as_ref(),
+            epoch: self.source_date_epoch(),
+        };
+        for module in &self.modules {
+            packages.push(module.debpkg(&self.pkg_dir, &kernel, &self.packaging, build)?);
+        }
+        self.signing_key.check_packages(&packages)?;
+        Ok(Packages {
+            files: packages,
+            make: make,
+        })
     }
 
-    pub fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<()> {
+    /// Build the kernel RPM packages with binrpm-pkg, and a meta-package
+    /// allowing to perform easy upgrades of the Linux kernel.
+    /// Upon success, the paths to the created packages are returned.
+    fn rpm_package(&mut self, toolchain: &Toolchain) -> Result<Packages> {
         toolchain.fetch()?;
         self.load_version()?;
-        let status = self.get_make_cmd(toolchain)
-            .arg("--")
-            .arg(make_target)
-            .status()
-            .context(error::ProgFailed{ proc: "make".to_string() })?;
-        ensure!(status.success(), error::MakeFailed{
-            target: make_target.to_string() });
-        Ok(())
+        util::refresh_config(&self.config, &self.build_dir)?;
+        self.signing_key.setup(&self.build_dir)?;
+
+        // Packages are built in a dedicated rpmbuild tree, rather than in
+        // the one of the user. Packages of previous builds are removed, so
+        // that only the ones of this build are returned.
+        let mut topdir = self.pkg_dir.clone();
+        topdir.push("rpmbuild");
+        let mut rpms = topdir.clone();
+        rpms.push("RPMS");
+        if rpms.exists() {
+            std::fs::remove_dir_all(&rpms).context(
+                error::RemoveDirError{ path: rpms.clone() })?;
+        }
+
+        let mut make_cmd = self.get_make_cmd(toolchain);
+        make_cmd.arg(format!("RPMOPTS=--define '_topdir {}'", topdir.to_str().unwrap()));
+        let make = vec![component::run_make(&mut make_cmd, "binrpm-pkg")?];
+        let mut packages = rpm::list(&topdir)?;
+
+        // The kernel package is always named kernel, and its version is the
+        // kernel release
+        let build = self.packaging.next_build(&self.component, &self.version.to_string(), "rpm")?;
+        let package = self.get_meta_package();
+        let mut meta = self.get_meta_metadata(&package, build)?;
+        meta.depends.insert(0, format!("kernel = {}", self.version));
+        packages.push(rpm::build(&topdir, &meta, &self.rpm_arch)?);
+        self.signing_key.check_packages(&packages)?;
+        Ok(Packages {
+            files: packages,
+            make: make,
+        })
+    }
+
+    fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>> {
+        toolchain.fetch()?;
+        self.load_version()?;
+        util::refresh_config(&self.config, &self.build_dir)?;
+        self.signing_key.setup(&self.build_dir)?;
+        let mut invocations = vec![component::run_make(&mut self.get_make_cmd(toolchain), make_target)?];
+
+        // Targets that build the in-tree modules also build the out-of-tree
+        // ones, as they must be re-built along.
+        if make_target == "all" || make_target == "modules" {
+            invocations.extend(self.make_modules(toolchain)?);
+        }
+        if make_target == "all" || make_target == self.image {
+            self.efi_images()?;
+        }
+        Ok(invocations)
     }
 }
 
@@ -370,6 +859,16 @@ fn make_version(str_version: &str) -> Result<Version> {
     })
 }
 
+/// Packages that bindeb-pkg may produce, by kind
+fn bindeb_packages(version: &Version) -> Vec<(&'static str, String)> {
+    vec![
+        ("image", format!("linux-image-{}", version)),
+        ("headers", format!("linux-headers-{}", version)),
+        ("libc-dev", "linux-libc-dev".to_string()),
+        ("dbg", format!("linux-image-{}-dbg", version)),
+    ]
+}
+
 /// Compose a path involving a given Linux version
 fn make_version_dir(base_dir: &PathBuf, version: &Version) -> PathBuf {
     let mut path = base_dir.clone();
@@ -377,41 +876,99 @@ fn make_version_dir(base_dir: &PathBuf, version: &Version) -> PathBuf {
     path
 }
 
-fn make_patches_dir(base_dir: &PathBuf) -> PathBuf {
+fn make_patches_dir(base_dir: &PathBuf, name: &str) -> PathBuf {
     let mut path = base_dir.clone();
     path.push("patches");
-    path.push("linux");
+    path.push(name);
     path
 }
 
 
 /// Create a new instance for Linux management
-pub fn new(config: &Config, interrupt: Interrupt) -> Result<Linux> {
-    let version = make_version(&config.linux.version)?;
-    let mut v_file = config.download_dir.clone();
+pub fn new(config: &Config, name: &str, interrupt: Interrupt) -> Result<Linux> {
+    let linux: LinuxConfig = config.component(name)?;
+    let version = make_version(&linux.version)?;
+    let download_dir = component::get_dir(&config.download_dir, name, "linux");
+    let build_dir = component::get_dir(&config.build_dir, name, "linux");
+
+    let mut v_file = download_dir.clone();
     v_file.push(format!("linux-{}.{}.version", version.maj, version.min));
 
     let mut pkg_dir = config.build_dir.clone();
     pkg_dir.push("packages");
 
+    let mut changes_file = download_dir.clone();
+    changes_file.push(format!("linux-{}.{}.changes", version.maj, version.min));
+
+    // Make sure the selected packages are known before building anything
+    if let Some(packages) = &linux.packages {
+        let kinds: Vec<&str> = bindeb_packages(&version).iter().map(|(k, _)| *k).collect();
+        for package in packages {
+            ensure!(kinds.contains(&package.as_str()), error::UnknownLinuxPackage{
+                package: package.clone(),
+                expected: kinds.join(", "),
+            });
+        }
+    }
+
+    // Device trees are installed and shipped under their file name, which
+    // must then designate a single one of them
+    for (index, dtb) in linux.dtbs.iter().enumerate() {
+        if let Some(other) = linux.dtbs[..index].iter()
+            .find(|other| other.file_name() == dtb.file_name()) {
+            return error::DuplicateDtb{ first: other.clone(), second: dtb.clone() }.fail();
+        }
+    }
+
+    let mut modules = Vec::new();
+    for module in &linux.modules {
+        modules.push(modules::new(config, module, interrupt.clone())?);
+    }
+
+    let image = match (&linux.image, config.toolchain.linux_arch.as_str()) {
+        (Some(image), _) => image.clone(),
+        (None, "arm") => "zImage".to_string(),
+        (None, "x86") | (None, "x86_64") => "bzImage".to_string(),
+        (None, _) => "Image".to_string(),
+    };
+
     let url = format!("https://cdn.kernel.org/pub/linux/kernel/v{}.x/",
         version.maj);
     Ok(Linux {
-        download_dir: config.download_dir.clone(),
-        source_dir: make_version_dir(&config.download_dir, &version),
-        build_dir: make_version_dir(&config.build_dir, &version),
+        source_dir: make_version_dir(&download_dir, &version),
+        build_dir: make_version_dir(&build_dir, &version),
+        download_dir: download_dir,
         pkg_dir: pkg_dir,
-        patches_dir: make_patches_dir(&config.lib_dir),
-        config: config.linux.config.clone(),
+        patches_dir: make_patches_dir(&config.lib_dir, name),
+        config: config::make_config_path(&config.lib_dir, name,
+            &linux.version, &linux.config)?,
+        recorded: VersionFile {
+            version: version.to_string(),
+            epoch: None,
+            patches: None,
+        },
         version: version,
         version_file: v_file,
+        changes_file: changes_file,
         base_url: Url::parse(&url).context(error::InvalidLinuxURL{})?,
         http_handle: curl::easy::Easy::new(),
         jobs: config.jobs,
         arch: config.toolchain.linux_arch.clone(),
         debian_arch: config.toolchain.debian_arch.clone(),
+        rpm_arch: config.toolchain.rpm_arch.clone()
+            .unwrap_or(rpm::arch(&config.toolchain.debian_arch)),
         target: config.target.clone(),
-        name: config.target_name.clone(),
+        target_name: config.target_name.clone(),
+        component: name.to_string(),
         interrupt: interrupt,
+        modules: modules,
+        packages: linux.packages,
+        dtbs: linux.dtbs,
+        image: image,
+        boot: boot::new(config)?,
+        signing_key: signing::new(config),
+        sign_modules: linux.sign_modules,
+        efi: efi::new(config, &linux.efi),
+        packaging: packaging::new(config),
     })
 }
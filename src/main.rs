@@ -1,82 +1,241 @@
 /* This is part of mktcb - which is under the MIT License ********************/
 
+mod boot;
+mod build;
+mod changes;
+mod component;
+mod compliance;
 mod config;
+mod cve;
+mod debian;
 mod decompress;
 mod download;
+mod efi;
 mod error;
+mod fit;
+mod generic;
+mod image;
 mod interrupt;
 mod linux;
 mod logging;
+mod manifest;
+mod modules;
+mod packaging;
+mod partition;
+mod optee;
 mod patch;
+mod qemu;
+mod repo;
+mod reproducible;
+mod rpm;
+mod sbom;
+mod signing;
+mod source;
+mod tfa;
 mod toolchain;
 mod uboot;
 mod util;
+mod version_file;
 
 // Traits ---------------------------------------------------------------------
 use std::io::Write;
 // ----------------------------------------------------------------------------
 
-use snafu::{ResultExt};
+use snafu::{OptionExt, ResultExt};
 use clap::{Arg, App, SubCommand};
 use crate::error::Result;
 use log::*;
 
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 
+/// Write the paths to the packages that were built in a file, one by line
+fn write_packages(file: &str, packages: &[PathBuf]) -> Result<()> {
+    let path = PathBuf::from(file);
+    let mut file = std::fs::File::create(&path)
+        .context(error::CreateFileError{path: path.clone()})?;
+    for pkg in packages {
+        let val = pkg.to_str().unwrap();
+        writeln!(file, "{}", val)
+            .context(error::FailedToWrite{path:path.clone()})?;
+    }
+    Ok(())
+}
+
+/// Perform the operations requested on the command-line on a component
+/// Retrieve the name of the first component of a given kind, which the
+/// subcommand of this kind operates on
+fn component_of_kind(config: &config::Config, kind: &str) -> Result<String> {
+    config.first_component(kind).context(error::NoComponent{
+        component: kind.to_string() })
+}
+
+fn run_component(agent: &mut dyn component::Component, config: &config::Config,
+                 matches: &clap::ArgMatches) -> Result<()> {
+    if matches.is_present("check-update") {
+        if agent.check_update()? {
+            info!("A new version of {} is available", agent.name());
+        } else {
+            std::process::exit(100);
+        }
+    }
+    if matches.is_present("fetch") {
+        agent.fetch()?;
+    }
+    if matches.is_present("reconfigure") {
+        agent.reconfigure()?;
+    }
+    if matches.is_present("debpkg") {
+        let toolchain = toolchain::new(&config)?;
+        let started = SystemTime::now();
+        let result = agent.package(&toolchain)?;
+        manifest::record(&config, agent, "debpkg", None, started, &result.make, &result.files)?;
+        write_packages(matches.value_of("debpkg").unwrap(), &result.files)?;
+
+        // Also record them, so they can be published later on
+        let record = repo::record_path(&config, agent.name());
+        write_packages(record.to_str().unwrap(), &result.files)?;
+    }
+    if matches.is_present("rpmpkg") {
+        let toolchain = toolchain::new(&config)?;
+        let started = SystemTime::now();
+        let result = agent.rpm_package(&toolchain)?;
+        manifest::record(&config, agent, "rpmpkg", None, started, &result.make, &result.files)?;
+        write_packages(matches.value_of("rpmpkg").unwrap(), &result.files)?;
+    }
+    if matches.occurrences_of("make") != 0 {
+        // Retrive the make target to be run. It is a required argument,
+        // so we can safely unwrap().
+        let target = matches.value_of("make").unwrap();
+
+        let toolchain = toolchain::new(&config)?;
+        let started = SystemTime::now();
+        let make = agent.make(target, &toolchain)?;
+        manifest::record(&config, agent, "make", Some(target), started, &make, &[])?;
+    }
+    Ok(())
+}
+
 fn run(matches: &clap::ArgMatches) -> Result<()> {
     let config = config::new(&matches)?;
     let interrupt = interrupt::get()?;
 
-    if let Some(matches) = matches.subcommand_matches("linux") {
-        let mut agent = linux::new(&config, interrupt)?;
-
-        if matches.is_present("check-update") {
-            if agent.check_update()? {
-                info!("A new version of the Linux kernel is available");
-            } else {
-                std::process::exit(100);
-            }
+    if let Some(matches) = matches.subcommand_matches("build") {
+        build::run(&config, interrupt, matches.is_present("dry-run"))?;
+    } else if let Some(matches) = matches.subcommand_matches("verify-reproducible") {
+        reproducible::verify(&config, interrupt, ! matches.is_present("no-packages"))?;
+    } else if let Some(matches) = matches.subcommand_matches("sbom") {
+        let formats = match matches.value_of("format") {
+            Some("spdx") => vec![sbom::Format::Spdx],
+            Some("cyclonedx") => vec![sbom::Format::CycloneDx],
+            _ => vec![sbom::Format::Spdx, sbom::Format::CycloneDx],
+        };
+        for path in sbom::run(&config, interrupt, &formats)? {
+            println!("{}", path.to_str().unwrap());
         }
-        if matches.is_present("fetch") {
-            agent.fetch()?;
+    } else if let Some(matches) = matches.subcommand_matches("compliance") {
+        let bundle = compliance::run(&config, interrupt,
+            matches.value_of("output").map(PathBuf::from))?;
+        println!("{}", bundle.to_str().unwrap());
+    } else if let Some(matches) = matches.subcommand_matches("linux") {
+        let name = component_of_kind(&config, "linux")?;
+        let mut agent = linux::new(&config, &name, interrupt)?;
+        if matches.subcommand_matches("changes").is_some() {
+            agent.changes()?;
         }
-        if matches.is_present("reconfigure") {
-            agent.reconfigure()?;
+        if let Some(matches) = matches.subcommand_matches("signing-key") {
+            let key = signing::run(&config, matches.value_of("import").map(PathBuf::from),
+                matches.is_present("force"))?;
+            println!("{}", key.to_str().unwrap());
         }
-        if matches.is_present("debpkg") {
-            let toolchain = toolchain::new(&config)?;
-            let result = agent.debpkg(&toolchain)?;
-            let path = PathBuf::from(matches.value_of("debpkg").unwrap());
-            let mut file = std::fs::File::create(&path)
-                .context(error::CreateFileError{path: path.clone()})?;
-            for pkg in result {
-                let val = pkg.to_str().unwrap();
-                writeln!(file, "{}", val)
-                    .context(error::FailedToWrite{path:path.clone()})?;
-            }
+        run_component(&mut agent, &config, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("component") {
+        // The name is a required argument, so we can safely unwrap()
+        let name = matches.value_of("name").unwrap();
+        let mut agent = component::new(name, &config, interrupt)?;
+        run_component(agent.as_mut(), &config, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("uboot") {
+        let name = component_of_kind(&config, "uboot")?;
+        let mut agent = component::new(&name, &config, interrupt)?;
+        run_component(agent.as_mut(), &config, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("tfa") {
+        let name = component_of_kind(&config, "tfa")?;
+        let mut agent = component::new(&name, &config, interrupt)?;
+        run_component(agent.as_mut(), &config, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("optee") {
+        let name = component_of_kind(&config, "optee")?;
+        let mut agent = component::new(&name, &config, interrupt)?;
+        run_component(agent.as_mut(), &config, matches)?;
+    } else if matches.subcommand_matches("boot").is_some() {
+        for path in boot::run(&config, interrupt)? {
+            println!("{}", path.to_str().unwrap());
         }
-        if matches.occurrences_of("make") != 0 {
-            // Retrive the make target to be run. It is a required argument,
-            // so we can safely unwrap().
-            let target = matches.value_of("make").unwrap();
-
-            let toolchain = toolchain::new(&config)?;
-            agent.make(target, &toolchain)?;
+    } else if matches.subcommand_matches("fit").is_some() {
+        println!("{}", fit::run(&config, interrupt)?.to_str().unwrap());
+    } else if let Some(matches) = matches.subcommand_matches("image") {
+        let image = image::make(&config, interrupt,
+            matches.value_of("output").map(PathBuf::from),
+            matches.value_of("rootfs").map(PathBuf::from))?;
+        println!("{}", image.to_str().unwrap());
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        if let Some(matches) = matches.subcommand_matches("boot") {
+            let timeout = match matches.value_of("timeout") {
+                Some(val) => Some(val.parse().context(error::InvalidTimeout{})?),
+                None => None,
+            };
+            let log = qemu::boot(&config, interrupt, timeout)?;
+            println!("{}", log.to_str().unwrap());
         }
-    } else if let Some(matches) = matches.subcommand_matches("uboot") {
-        let agent = uboot::new(&config, interrupt)?;
-        if matches.is_present("fetch") {
-            agent.fetch()?;
+    } else if let Some(matches) = matches.subcommand_matches("repo") {
+        if let Some(matches) = matches.subcommand_matches("publish") {
+            let lists: Vec<PathBuf> = matches.values_of("packages")
+                .map(|values| values.map(PathBuf::from).collect())
+                .unwrap_or_default();
+            let keep = match matches.value_of("prune") {
+                Some(val) => Some(val.parse().context(error::InvalidPruneNumber{})?),
+                None => None,
+            };
+            // The dir argument is required, so we can safely unwrap()
+            repo::publish(&config, &PathBuf::from(matches.value_of("dir").unwrap()),
+                &lists, matches.value_of("suite"), matches.value_of("key"), keep)?;
         }
-        if matches.occurrences_of("make") != 0 {
-            // Retrive the make target to be run. It is a required argument,
-            // so we can safely unwrap().
-            let target = matches.value_of("make").unwrap();
-
-            let toolchain = toolchain::new(&config)?;
-            agent.make(target, &toolchain)?;
+    } else if let Some(matches) = matches.subcommand_matches("cve") {
+        let mut subjects = Vec::new();
+        for (kind, product) in &[
+            ("linux", cve::Product::Linux),
+            ("uboot", cve::Product::Uboot),
+        ] {
+            let name = match config.first_component(kind) {
+                Some(name) => name,
+                None => continue,
+            };
+            let mut agent = component::new(&name, &config, interrupt.clone())?;
+            if let Some(version) = agent.fetched_version()? {
+                subjects.push(cve::Subject {
+                    product: *product,
+                    version: version,
+                    build_dir: agent.build_dir().clone(),
+                    patches_dir: agent.patches_dir().clone(),
+                });
+            } else {
+                warn!("{} was not fetched. Skipping it (run --fetch?)", product);
+            }
         }
+
+        // The vulnerability databases are looked up in the library and in
+        // the download directory, unless explicitly provided.
+        let db_dirs: Vec<PathBuf> = match matches.values_of("db") {
+            Some(values) => values.map(PathBuf::from).collect(),
+            None => {
+                let mut lib_db = config.lib_dir.clone();
+                lib_db.push("cve");
+                let mut dl_db = config.download_dir.clone();
+                dl_db.push("cve");
+                vec![lib_db, dl_db]
+            }
+        };
+        cve::check(&db_dirs, &subjects, matches.is_present("all"))?;
     }
     Ok(())
 }
@@ -117,13 +276,58 @@ fn main() {
             .value_name("JOBS")
             .help("Set the number of parallel jobs to be used")
             .takes_value(true))
+        .arg(Arg::with_name("keys_dir")
+            .short("K")
+            .long("keys-dir")
+            .value_name("DIR")
+            .help("Set the path to the directory holding the private keys \
+                (defaults to ~/.mktcb/keys)")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("build")
+            .about("fetch and build all the components of the target, in the \
+                order of their dependencies. Only out-of-date components are \
+                built")
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Print what would be done, without doing it")))
+        .subcommand(SubCommand::with_name("verify-reproducible")
+            .about("build the target twice, in separate build directories \
+                (reproducible/1 and reproducible/2), and check that both \
+                builds produced the same artifacts and packages")
+            .arg(Arg::with_name("no-packages")
+                .long("no-packages")
+                .help("Only compare the artifacts, without packaging the \
+                    components")))
+        .subcommand(SubCommand::with_name("sbom")
+            .about("write the Software Bill of Materials of the components \
+                that were built, as recorded by the build manifest, in \
+                sbom/ of the build directory")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["spdx", "cyclonedx"])
+                .help("Only write the SBOM in this format (SPDX 2.3 or \
+                    CycloneDX 1.5). Both are written by default")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("compliance")
+            .about("gather the corresponding sources of the components that \
+                were fetched (original archives, applied patches and \
+                configurations) in an archive, for license compliance")
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Path to the archive to be created (defaults to \
+                    compliance/ in the build directory)")
+                .takes_value(true)))
         .subcommand(SubCommand::with_name("linux")
             .about("operations on the Linux kernel")
             .arg(Arg::with_name("make")
                 .long("make")
                 .value_name("TARGET")
                 .default_value("all")
-                .help("Run a make target in the Linux tree")
+                .help("Run a make target in the Linux tree. The 'all' and \
+                    'modules' targets also build the out-of-tree modules")
                 .takes_value(true))
             .arg(Arg::with_name("check-update")
                 .long("check-update")
@@ -136,13 +340,40 @@ fn main() {
                 .long("debpkg")
                 .conflicts_with("make")
                 .help("Build the linux-image Debian package and integrates it to \
+                    a meta-package for easy upgrade. Out-of-tree modules are \
+                    packaged separately. The paths to these packages will be \
+                    made available in the provided file (one by line)")
+                .value_name("FILE")
+                .takes_value(true))
+            .arg(Arg::with_name("rpmpkg")
+                .long("rpmpkg")
+                .conflicts_with("make")
+                .help("Build the kernel RPM packages and integrates them to \
                     a meta-package for easy upgrade. The paths to these packages \
                     will be made available in the provided file (one by line)")
                 .value_name("FILE")
                 .takes_value(true))
             .arg(Arg::with_name("fetch")
                 .long("fetch")
-                .help("Retrieve the latest version of the Linux kernel")))
+                .help("Retrieve the latest version of the Linux kernel"))
+            .subcommand(SubCommand::with_name("changes")
+                .about("Summarize the changes brought by the updates that \
+                    were not fetched yet. The summary will be integrated to the \
+                    changelog of the Debian meta-package"))
+            .subcommand(SubCommand::with_name("signing-key")
+                .about("Generate the key signing the kernel modules of the \
+                    target, in the keys directory. It is otherwise generated on \
+                    the first build of a kernel that signs its modules")
+                .arg(Arg::with_name("import")
+                    .long("import")
+                    .value_name("FILE")
+                    .help("Import an existing key rather than generating one. \
+                        The file must hold both the private key and its \
+                        certificate (PEM)")
+                    .takes_value(true))
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .help("Replace the existing key"))))
         .subcommand(SubCommand::with_name("uboot")
             .about("operations on the U-Boot")
             .arg(Arg::with_name("make")
@@ -151,9 +382,177 @@ fn main() {
                 .default_value("all")
                 .help("Run a make target in the U-Boot tree")
                 .takes_value(true))
+            .arg(Arg::with_name("check-update")
+                .long("check-update")
+                .help("Check whether a newer U-Boot release than the one of the \
+                    target is available. If no update is available, mkctb will \
+                    exit with status 100."))
+            .arg(Arg::with_name("reconfigure")
+                .long("reconfigure")
+                .help("Re-generate the U-Boot .config from the target config"))
+            .arg(Arg::with_name("debpkg")
+                .long("debpkg")
+                .conflicts_with("make")
+                .help("Build the u-boot Debian package, containing the U-Boot \
+                    binaries declared by the target. The path to this package \
+                    will be made available in the provided file")
+                .value_name("FILE")
+                .takes_value(true))
             .arg(Arg::with_name("fetch")
                 .long("fetch")
-                .help("Retrieve U-Boot")))
+                .help("Retrieve U-Boot, or apply the library patches that \
+                    were added since it was retrieved")))
+        .subcommand(SubCommand::with_name("tfa")
+            .about("operations on the Trusted Firmware-A")
+            .arg(Arg::with_name("make")
+                .long("make")
+                .value_name("TARGET")
+                .default_value("bl31")
+                .help("Run a make target in the TF-A tree")
+                .takes_value(true))
+            .arg(Arg::with_name("fetch")
+                .long("fetch")
+                .help("Retrieve TF-A, or apply the library patches that \
+                    were added since it was retrieved")))
+        .subcommand(SubCommand::with_name("optee")
+            .about("operations on the OP-TEE OS")
+            .arg(Arg::with_name("make")
+                .long("make")
+                .value_name("TARGET")
+                .default_value("all")
+                .help("Run a make target in the OP-TEE tree")
+                .takes_value(true))
+            .arg(Arg::with_name("fetch")
+                .long("fetch")
+                .help("Retrieve OP-TEE, or apply the library patches that \
+                    were added since it was retrieved")))
+        .subcommand(SubCommand::with_name("component")
+            .about("operations on any component declared by the target")
+            .arg(Arg::with_name("name")
+                .value_name("NAME")
+                .required(true)
+                .help("Name of the component, as declared by the target \
+                    ([component.<NAME>])"))
+            .arg(Arg::with_name("make")
+                .long("make")
+                .value_name("TARGET")
+                .default_value("all")
+                .help("Run a make target in the tree of the component")
+                .takes_value(true))
+            .arg(Arg::with_name("check-update")
+                .long("check-update")
+                .help("Check whether an update of the component is available. \
+                    If no update is available, mkctb will exit with status 100."))
+            .arg(Arg::with_name("reconfigure")
+                .long("reconfigure")
+                .help("Re-generate the build configuration of the component"))
+            .arg(Arg::with_name("debpkg")
+                .long("debpkg")
+                .conflicts_with("make")
+                .help("Build the Debian packages of the component. Their paths \
+                    will be made available in the provided file (one by line)")
+                .value_name("FILE")
+                .takes_value(true))
+            .arg(Arg::with_name("rpmpkg")
+                .long("rpmpkg")
+                .conflicts_with("make")
+                .help("Build the RPM packages of the component. Their paths \
+                    will be made available in the provided file (one by line)")
+                .value_name("FILE")
+                .takes_value(true))
+            .arg(Arg::with_name("fetch")
+                .long("fetch")
+                .help("Retrieve the sources of the component")))
+        .subcommand(SubCommand::with_name("boot")
+            .about("Render the boot flow of the target as a U-Boot script \
+                (boot.cmd), compile it (boot.scr), and create the U-Boot \
+                environment (uboot.env)"))
+        .subcommand(SubCommand::with_name("fit")
+            .about("Build the FIT image of the target (kernel, device trees, \
+                initramfs), as described by its [fit] section. It is signed \
+                if a key is provided, and U-Boot then embeds the public key"))
+        .subcommand(SubCommand::with_name("image")
+            .about("Assemble the disk image of the target (SD card, eMMC), as \
+                described by its [image] section, from the Linux and U-Boot \
+                builds")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("FILE")
+                .help("Path to the image. Defaults to images/<target>.img in \
+                    the build directory")
+                .takes_value(true))
+            .arg(Arg::with_name("rootfs")
+                .long("rootfs")
+                .value_name("TARBALL")
+                .help("Root filesystem tarball, overriding the one of the \
+                    layout")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("test")
+            .about("smoke tests of the builds")
+            .subcommand(SubCommand::with_name("boot")
+                .about("Boot the kernel that was built (or U-Boot) in QEMU, as \
+                    described by the [qemu] section of the target, and wait \
+                    for its console to tell that the boot succeeded. The \
+                    console output is saved in test/boot.log of the build \
+                    directory")
+                .arg(Arg::with_name("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Time after which the boot is considered failed, \
+                        overriding the one of the target")
+                    .takes_value(true))))
+        .subcommand(SubCommand::with_name("repo")
+            .about("operations on an APT repository")
+            .subcommand(SubCommand::with_name("publish")
+                .about("Publish the Debian packages built for the target in an \
+                    APT repository. Packages of other targets that were \
+                    already published are kept")
+                .arg(Arg::with_name("dir")
+                    .long("dir")
+                    .value_name("DIR")
+                    .required(true)
+                    .help("Root of the APT repository")
+                    .takes_value(true))
+                .arg(Arg::with_name("packages")
+                    .long("packages")
+                    .value_name("FILE")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("File listing packages to be published (one by line), \
+                        as written by --debpkg. Defaults to all the packages \
+                        built for the target")
+                    .takes_value(true))
+                .arg(Arg::with_name("suite")
+                    .long("suite")
+                    .value_name("SUITE")
+                    .help("Suite in which packages are published")
+                    .takes_value(true))
+                .arg(Arg::with_name("key")
+                    .long("key")
+                    .value_name("KEY")
+                    .help("GnuPG key with which the repository is signed")
+                    .takes_value(true))
+                .arg(Arg::with_name("prune")
+                    .long("prune")
+                    .value_name("N")
+                    .help("Only keep the N latest versions of each package")
+                    .takes_value(true))))
+        .subcommand(SubCommand::with_name("cve")
+            .about("list known vulnerabilities affecting the fetched Linux and U-Boot")
+            .arg(Arg::with_name("db")
+                .long("db")
+                .value_name("DIR")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Directory containing a vulnerability database (OSV or \
+                    CVE JSON 5 files). Defaults to cve/ in the library and in \
+                    the download directory")
+                .takes_value(true))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .help("Also list vulnerabilities that are addressed by library \
+                    patches or that concern files which are not built")))
         .get_matches();
 
     if let Err(err) = logging::init(log::LevelFilter::Trace) {
@@ -0,0 +1,261 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde_derive::{Deserialize, Serialize};
+use snafu::ResultExt;
+use log::*;
+
+use crate::error::Result;
+use crate::component;
+use crate::error;
+use crate::component::{Component, MakeInvocation};
+use crate::config::Config;
+use crate::toolchain;
+use crate::util;
+
+/// Name of the manifest, in the build directory
+const MANIFEST: &str = "manifest.json";
+
+/// Variables of the environment of mktcb that may influence the builds
+const ENV_VARS: [&str; 8] = ["PATH", "LANG", "LC_ALL", "TZ", "SOURCE_DATE_EPOCH",
+    "MAKEFLAGS", "HOSTCC", "HOSTCFLAGS"];
+
+/// File recorded by the manifest, with its SHA-256
+#[derive(Serialize, Deserialize)]
+pub struct FileRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+/// Location the sources of a component were retrieved from
+#[derive(Serialize, Deserialize)]
+pub struct SourceRecord {
+    pub url: String,
+    /// Checksum of the downloaded file, if it is still around
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MakeRecord {
+    pub command: Vec<String>,
+    pub env: BTreeMap<String, String>,
+}
+
+/// Operation performed on a component (make, debpkg, rpmpkg)
+#[derive(Serialize, Deserialize)]
+pub struct StepRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// UNIX timestamp of the start of the step
+    pub started: u64,
+    /// Duration of the step, in seconds
+    pub duration: f64,
+    pub make: Vec<MakeRecord>,
+    /// Files produced by the step (e.g. packages)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<FileRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ComponentRecord {
+    pub kind: String,
+    pub version: Option<String>,
+    pub sources: Vec<SourceRecord>,
+    /// Library patches applied on the sources, in order
+    pub patches: Vec<FileRecord>,
+    /// SHA-256 of the .config of the build directory, for Kconfig builds
+    pub config_sha256: Option<String>,
+    /// Steps, by operation and make target (e.g. make:dtbs, debpkg)
+    pub steps: BTreeMap<String, StepRecord>,
+    pub artifacts: Vec<FileRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ToolchainRecord {
+    pub name: String,
+    pub url: String,
+    pub sha256: Option<String>,
+}
+
+/// Manifest of the build directory. It tells how each component was built,
+/// from which sources, and what was produced, for audits.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    pub target: String,
+    pub mktcb_version: String,
+    pub toolchain: Option<ToolchainRecord>,
+    pub env: BTreeMap<String, String>,
+    pub components: BTreeMap<String, ComponentRecord>,
+}
+
+/// Compose the path to the manifest of the build directory
+pub fn path(config: &Config) -> PathBuf {
+    let mut path = config.build_dir.clone();
+    path.push(MANIFEST);
+    path
+}
+
+/// Load the manifest of the build directory, if there is one
+pub fn load(config: &Config) -> Result<Option<Manifest>> {
+    let path = path(config);
+    if ! path.is_file() {
+        return Ok(None);
+    }
+    let data = std::fs::read(&path).context(error::FailedToRead{ path: path.clone() })?;
+    let manifest = serde_json::from_slice(&data).context(
+        error::FailedToDeserJson{ path: path.clone() })?;
+    Ok(Some(manifest))
+}
+
+/// Express a path relatively to a base directory, if it resides in it
+fn relative(path: &PathBuf, base: &PathBuf) -> PathBuf {
+    path.strip_prefix(base).map(|p| p.to_path_buf()).unwrap_or(path.clone())
+}
+
+fn sha256(path: &PathBuf) -> Result<String> {
+    util::checksum("sha256sum", path)
+}
+
+fn file_record(name: Option<String>, path: &PathBuf, base: &PathBuf) -> Result<FileRecord> {
+    Ok(FileRecord {
+        name: name,
+        path: relative(path, base),
+        sha256: sha256(path)?,
+    })
+}
+
+/// Record the sources, configuration and artifacts of a component
+fn component_record(config: &Config, agent: &mut dyn Component) -> Result<ComponentRecord> {
+    let version = agent.fetched_version()?;
+    let provenance = match version {
+        Some(_) => agent.provenance()?,
+        None => component::Provenance::default(),
+    };
+
+    let mut sources = Vec::new();
+    for (url, file) in &provenance.downloads {
+        sources.push(SourceRecord {
+            url: url.to_string(),
+            sha256: match file {
+                Some(file) if file.is_file() => Some(sha256(file)?),
+                _ => None,
+            },
+            commit: provenance.commit.clone(),
+        });
+    }
+    if version.is_some() && provenance.patches.is_none() {
+        warn!("The patches applied on {} were not recorded. Fetch it again to record them.",
+            agent.name());
+    }
+    let mut patches = Vec::new();
+    for patch in provenance.patches.iter().flatten() {
+        // The patch is recorded as it was applied, if it was recorded so
+        patches.push(match &patch.sha256 {
+            Some(sha256) => FileRecord {
+                name: None,
+                path: relative(&patch.path, &config.lib_dir),
+                sha256: sha256.clone(),
+            },
+            None => file_record(None, &patch.path, &config.lib_dir)?,
+        });
+    }
+
+    let mut dot_config = agent.build_dir().clone();
+    dot_config.push(".config");
+    let config_sha256 = if dot_config.is_file() { Some(sha256(&dot_config)?) } else { None };
+
+    let mut artifacts = Vec::new();
+    for artifact in agent.artifacts() {
+        if artifact.path.is_file() {
+            artifacts.push(file_record(Some(artifact.name), &artifact.path, &config.build_dir)?);
+        }
+    }
+
+    Ok(ComponentRecord {
+        kind: config.component_kind(agent.name())?,
+        version: version,
+        sources: sources,
+        patches: patches,
+        config_sha256: config_sha256,
+        steps: BTreeMap::new(),
+        artifacts: artifacts,
+    })
+}
+
+/// Record a step that was performed on a component (make, debpkg, rpmpkg)
+/// in the manifest of the build directory, with the invocations of make it
+/// ran and the files it produced. The path to the manifest is returned.
+pub fn record(config: &Config, agent: &mut dyn Component, step: &str, target: Option<&str>,
+              started: SystemTime, make: &[MakeInvocation], outputs: &[PathBuf]) -> Result<PathBuf> {
+    let duration = started.elapsed().map(|d| d.as_millis() as f64 / 1000.0).unwrap_or(0.0);
+    let make = make.iter()
+        .map(|invocation| MakeRecord {
+            command: invocation.command.clone(),
+            env: invocation.env.clone(),
+        })
+        .collect();
+
+    let mut manifest = match load(config) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => Manifest::default(),
+        Err(err) => {
+            warn!("Discarding the previous manifest: {}", err);
+            Manifest::default()
+        },
+    };
+    manifest.target = config.target.clone();
+    manifest.mktcb_version = env!("CARGO_PKG_VERSION").to_string();
+
+    let toolchain = toolchain::new(config)?;
+    let archive = toolchain.archive()?;
+    manifest.toolchain = Some(ToolchainRecord {
+        name: toolchain.name(),
+        url: toolchain.url().to_string(),
+        sha256: if archive.is_file() { Some(sha256(&archive)?) } else { None },
+    });
+    manifest.env = ENV_VARS.iter()
+        .filter_map(|var| std::env::var(var).ok().map(|value| (var.to_string(), value)))
+        .collect();
+
+    let mut record = component_record(config, agent)?;
+    // Steps that were performed earlier are kept
+    if let Some(previous) = manifest.components.remove(agent.name()) {
+        record.steps = previous.steps;
+    }
+    let mut files = Vec::new();
+    for output in outputs {
+        files.push(file_record(None, output, &config.build_dir)?);
+    }
+    // The same operation may be performed on several make targets, which
+    // are recorded separately
+    let key = match target {
+        Some(target) => format!("{}:{}", step, target),
+        None => step.to_string(),
+    };
+    record.steps.insert(key, StepRecord {
+        target: target.map(|t| t.to_string()),
+        started: started.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        duration: duration,
+        make: make,
+        outputs: files,
+    });
+    manifest.components.insert(agent.name().to_string(), record);
+
+    let path = path(config);
+    std::fs::create_dir_all(&config.build_dir).context(
+        error::CreateDirError{ path: config.build_dir.clone() })?;
+    let data = serde_json::to_string_pretty(&manifest).context(
+        error::FailedToSerJson{ path: path.clone() })?;
+    std::fs::write(&path, format!("{}\n", data)).context(
+        error::FailedToWrite{ path: path.clone() })?;
+    debug!("Manifest {:#?} updated", path);
+    Ok(path)
+}
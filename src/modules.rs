@@ -0,0 +1,287 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::component::{MakeInvocation, Provenance};
+use crate::config::{Config, ModuleConfig};
+use crate::debian;
+use crate::interrupt::Interrupt;
+use crate::packaging::{Packaging, Package};
+use crate::reproducible;
+use crate::signing::ModuleSigner;
+use crate::source;
+use crate::source::Source;
+use crate::util;
+use crate::version_file;
+
+/// Sources of a module that are part of the library
+struct Local {
+    dir: PathBuf,
+    /// Snapshot of the directory, taken on each fetch. The library may
+    /// change afterwards.
+    archive: PathBuf,
+    version_file: PathBuf,
+    interrupt: Interrupt,
+}
+
+/// Where the sources of a module come from
+enum Origin {
+    /// Directory of the library. It is copied in the download directory
+    /// on each fetch, so the library is never modified by the build.
+    Local(Local),
+    Remote(Box<Source>),
+}
+
+/// Kernel that out-of-tree modules are packaged for
+pub struct Kernel<'a> {
+    /// Kernel release (e.g. 5.4.38)
+    pub release: &'a str,
+    /// Version of the linux-image package of the release
+    pub image_version: &'a str,
+    pub debian_arch: &'a str,
+    /// Signer of the kernel objects, if they are signed
+    pub signer: Option<&'a ModuleSigner>,
+    /// Date of the kernel sources, that the packages carry
+    pub epoch: u64,
+}
+
+/// Out-of-tree Linux kernel module
+pub struct Module {
+    pub name: String,
+    pub version: String,
+    origin: Origin,
+    source_dir: PathBuf,
+    patches_dir: PathBuf,
+}
+
+impl Module {
+    pub fn fetch(&self) -> Result<()> {
+        match &self.origin {
+            Origin::Remote(source) => {
+                source.fetch()?;
+            },
+            Origin::Local(local) => {
+                let path = &local.dir;
+                info!("Copying module {} from {:#?}", self.name, path);
+                // Without version file, the copy is not considered fetched
+                if local.version_file.exists() {
+                    std::fs::remove_file(&local.version_file).context(
+                        error::RemoveFileError{ path: local.version_file.clone() })?;
+                }
+                if self.source_dir.exists() {
+                    std::fs::remove_dir_all(&self.source_dir).context(
+                        error::RemoveDirError{ path: self.source_dir.clone() })?;
+                }
+                let parent = self.source_dir.parent().unwrap().to_path_buf();
+                std::fs::create_dir_all(&parent).context(
+                    error::CreateDirError{ path: parent.clone() })?;
+                let status = Command::new("cp")
+                    .stdin(Stdio::null())
+                    .arg("-a")
+                    .arg(path)
+                    .arg(&self.source_dir)
+                    .status()
+                    .context(error::ProgFailed{ proc: "cp".to_string() })?;
+                ensure!(status.success(), error::CopyDirFailed{
+                    from: path.clone(),
+                    to: self.source_dir.clone(),
+                });
+
+                let epoch = reproducible::tree_epoch(path)?;
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                util::tar(&path.parent().unwrap().to_path_buf(), &name, &local.archive, epoch)?;
+                version_file::patch_and_record(&local.version_file, &self.version, epoch,
+                    &local.interrupt, &self.patches_dir, &self.patches_dir, &self.source_dir)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Retrieve where the sources of the module come from. Modules of the
+    /// library are copied again on each fetch, with the patches that the
+    /// library holds at this time: they are the ones of the last fetch.
+    pub fn provenance(&self) -> Result<Provenance> {
+        match &self.origin {
+            Origin::Remote(source) => source.provenance(),
+            Origin::Local(local) => {
+                ensure!(local.version_file.exists(), error::NotFetched{
+                    component: format!("Linux module {}", self.name) });
+                let recorded = version_file::load(&local.version_file)?;
+                Ok(Provenance {
+                    local: Some(local.archive.clone()),
+                    patches: recorded.applied(&self.patches_dir),
+                    ..Provenance::default()
+                })
+            },
+        }
+    }
+
+    /// Build the module with the provided make command, that runs in the
+    /// Linux sources. The invocation of make is returned.
+    pub fn make(&self, mut make_cmd: Command) -> Result<MakeInvocation> {
+        match &self.origin {
+            Origin::Remote(source) => source.ensure_fetched()?,
+            Origin::Local(_) => ensure!(self.source_dir.is_dir(), error::NotFetched{
+                component: format!("Linux module {}", self.name) }),
+        }
+        make_cmd.arg(format!("M={}", self.source_dir.to_str().unwrap()));
+        component::run_make(&mut make_cmd, "modules")
+    }
+
+    /// Retrieve the kernel objects produced by the build
+    fn kernel_objects(&self) -> Result<Vec<PathBuf>> {
+        fn walk(dir: &PathBuf, objects: &mut Vec<PathBuf>) -> Result<()> {
+            let entries = std::fs::read_dir(dir).context(
+                error::DirIterFailed{ dir: dir.clone() })?;
+            for entry in entries {
+                let path = entry.context(error::DirIterFailed{ dir: dir.clone() })?.path();
+                if path.is_dir() {
+                    walk(&path, objects)?;
+                } else if path.extension().map(|e| e == "ko").unwrap_or(false) {
+                    objects.push(path);
+                }
+            }
+            Ok(())
+        }
+        let mut objects = Vec::new();
+        walk(&self.source_dir, &mut objects)?;
+        objects.sort();
+        ensure!(! objects.is_empty(), error::NoKernelObject{
+            module: self.name.clone() });
+        Ok(objects)
+    }
+
+    /// Build a Debian package containing the kernel objects of the module.
+    /// As they can only be loaded by the kernel they were built against, the
+    /// package is named after the kernel release, and depends on the exact
+    /// linux-image package of this release. The kernel objects are signed
+    /// when the kernel provides a signer.
+    pub fn debpkg(&self, pkg_dir: &PathBuf, kernel: &Kernel, packaging: &Packaging,
+                  build: u64) -> Result<PathBuf> {
+        let release = kernel.release;
+        let package = format!("{}-modules-{}", self.name, release);
+        let deb_dir = debian::prepare(pkg_dir, &package)?;
+
+        let mut dest = deb_dir.clone();
+        dest.push("lib");
+        dest.push("modules");
+        dest.push(release);
+        dest.push("extra");
+        dest.push(&self.name);
+        std::fs::create_dir_all(&dest).context(
+            error::CreateDirError{ path: dest.clone() })?;
+        for object in self.kernel_objects()? {
+            let mut to = dest.clone();
+            to.push(object.file_name().unwrap());
+            std::fs::copy(&object, &to).context(error::CopyFailed{
+                from: object.clone(),
+                to: to.clone(),
+            })?;
+            // The copy is signed, so that the build tree keeps the module
+            // as it was built
+            if let Some(signer) = kernel.signer {
+                signer.sign(&to)?;
+            }
+        }
+
+        let mut meta = packaging.metadata(&Package {
+            name: &package,
+            version: &self.version,
+            summary: format!("Out-of-tree module {} for Linux {}", self.name, release),
+            section: "kernel",
+            priority: "optional",
+        }, build)?;
+        meta.depends.insert(0, format!("linux-image-{} (= {})", release, kernel.image_version));
+        let control = debian::control(&meta, kernel.debian_arch);
+        debian::write_file(&deb_dir, "DEBIAN/control", &control, false)?;
+
+        // The modules dependencies must be re-computed when modules are
+        // added or removed
+        let script = format!("#!/bin/sh\nset -e\ndepmod -a {}\n", release);
+        debian::write_file(&deb_dir, "DEBIAN/postinst", &script, true)?;
+        debian::write_file(&deb_dir, "DEBIAN/postrm", &script, true)?;
+
+        debian::build(pkg_dir, &package, kernel.epoch)
+    }
+}
+
+pub fn new(config: &Config, module: &ModuleConfig, interrupt: Interrupt) -> Result<Module> {
+    let version = module.version.clone();
+    let expand = |s: &str| s.replace("{version}", &version);
+    let dir = match &module.dir {
+        Some(dir) => expand(dir),
+        None => format!("{}-{}", module.name, version),
+    };
+
+    let mut download_dir = config.download_dir.clone();
+    download_dir.push("linux-modules");
+    let mut source_dir = download_dir.clone();
+    source_dir.push(&dir);
+    let mut patches_dir = config.lib_dir.clone();
+    patches_dir.push("patches");
+    patches_dir.push("linux-modules");
+    patches_dir.push(&module.name);
+    patches_dir.push(&version);
+
+    let mut v_file = download_dir.clone();
+    v_file.push(format!("{}.version", dir));
+
+    let (url, archive, git_rev) = match (&module.path, &module.git, &module.url) {
+        (Some(path), _, _) => {
+            let mut local = config.lib_dir.clone();
+            local.push(path);
+            ensure!(local.is_dir(), error::FileDoesNotExist{ path: local.clone() });
+            let mut archive = download_dir.clone();
+            archive.push(format!("{}.tar.gz", dir));
+            return Ok(Module {
+                name: module.name.clone(),
+                version: version,
+                origin: Origin::Local(Local {
+                    dir: local,
+                    archive: archive,
+                    version_file: v_file,
+                    interrupt: interrupt,
+                }),
+                source_dir: source_dir,
+                patches_dir: patches_dir,
+            });
+        },
+        (None, Some(git), _) => {
+            let rev = module.rev.as_ref().map(|r| expand(r)).unwrap_or(version.clone());
+            (source::parse_url(&module.name, git)?, PathBuf::from(&dir), Some(rev))
+        },
+        (None, None, Some(url)) => {
+            let url = source::parse_url(&module.name, &expand(url))?;
+            let archive = format!("{}{}", dir, source::archive_extension(&url)?);
+            (url, PathBuf::from(archive), None)
+        },
+        (None, None, None) => return error::NoComponentSource{
+            component: module.name.clone() }.fail(),
+    };
+
+    Ok(Module {
+        name: module.name.clone(),
+        version: version.clone(),
+        origin: Origin::Remote(Box::new(Source {
+            pretty: format!("Linux module {}", module.name),
+            url: url,
+            archive: archive,
+            git_rev: git_rev,
+            download_dir: download_dir,
+            source_dir: source_dir.clone(),
+            version_file: v_file,
+            patches_dir: patches_dir.clone(),
+            version: version,
+            interrupt: interrupt,
+        })),
+        source_dir: source_dir,
+        patches_dir: patches_dir,
+    })
+}
@@ -0,0 +1,176 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use snafu::ResultExt;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::component::{Component, Artifact, MakeInvocation, Provenance};
+use crate::config::{Config, OpteeConfig};
+use crate::source::Source;
+use crate::toolchain;
+use crate::toolchain::Toolchain;
+use crate::interrupt::Interrupt;
+
+pub struct Optee {
+    name: String,
+    source: Source,
+    build_dir: PathBuf,
+    platform: String,
+    arm64: bool,
+    toolchain32: Option<Toolchain>,
+    jobs: usize,
+}
+
+impl Optee {
+    /// Retrieve the path to an image produced by the OP-TEE build
+    fn get_image(&self, name: &str) -> PathBuf {
+        let mut path = self.build_dir.clone();
+        path.push("core");
+        path.push(name);
+        path
+    }
+}
+
+impl Component for Optee {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        self.source.fetch()?;
+        Ok(())
+    }
+
+    fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>> {
+        toolchain.fetch()?;
+        self.source.ensure_fetched()?;
+
+        let mut make_cmd = Command::new("make");
+        make_cmd
+            .arg(format!("O={}", self.build_dir.to_str().unwrap()))
+            .arg(format!("PLATFORM={}", self.platform))
+            .arg("-C").arg(self.source.source_dir.clone())
+            .arg(format!("-j{}", self.jobs));
+
+        // The core is built with the toolchain of the target. When it is
+        // 64-bits, trusted applications may still be 32-bits, which requires
+        // a dedicated toolchain. Without one, only 64-bits TAs are supported.
+        if self.arm64 {
+            make_cmd
+                .arg("CFG_ARM64_core=y")
+                .arg(format!("CROSS_COMPILE64={}", toolchain.cross_compile));
+            if let Some(toolchain32) = &self.toolchain32 {
+                toolchain32.fetch()?;
+                make_cmd.arg(format!("CROSS_COMPILE32={}", toolchain32.cross_compile));
+            } else {
+                make_cmd.arg("CFG_USER_TA_TARGETS=ta_arm64");
+            }
+        } else {
+            make_cmd.arg(format!("CROSS_COMPILE32={}", toolchain.cross_compile));
+        }
+        Ok(vec![component::run_make(&mut make_cmd, make_target)?])
+    }
+
+    fn fetched_version(&mut self) -> Result<Option<String>> {
+        self.source.fetched_version()
+    }
+
+    fn new_patches(&self) -> Result<Vec<PathBuf>> {
+        self.source.new_patches()
+    }
+
+    fn build_dir(&self) -> &PathBuf {
+        &self.build_dir
+    }
+
+    fn patches_dir(&self) -> &PathBuf {
+        &self.source.patches_dir
+    }
+
+    fn provenance(&self) -> Result<Provenance> {
+        self.source.provenance()
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        vec![self.source.version_file.clone()]
+    }
+
+    fn version_file(&self) -> Option<&PathBuf> {
+        Some(&self.source.version_file)
+    }
+
+    /// tee.bin is the image with its header, as expected by U-Boot (TEE=).
+    /// The other ones are expected by TF-A (BL32=, BL32_EXTRA1=, BL32_EXTRA2=)
+    fn artifacts(&self) -> Vec<Artifact> {
+        vec![
+            ("tee", "tee.bin"),
+            ("tee-header", "tee-header_v2.bin"),
+            ("tee-pager", "tee-pager_v2.bin"),
+            ("tee-pageable", "tee-pageable_v2.bin"),
+        ].into_iter().map(|(name, file)| Artifact {
+            name: name.to_string(),
+            path: self.get_image(file),
+        }).collect()
+    }
+}
+
+/// Compose a path involving a given OP-TEE version
+fn make_version_dir(base_dir: &PathBuf, version: &str) -> PathBuf {
+    let mut path = base_dir.clone();
+    path.push(format!("optee_os-{}", version));
+    path
+}
+
+fn make_patches_dir(base_dir: &PathBuf, name: &str, version: &str) -> PathBuf {
+    let mut path = base_dir.clone();
+    path.push("patches");
+    path.push(name);
+    path.push(version);
+    path
+}
+
+pub fn new(config: &Config, name: &str, interrupt: Interrupt) -> Result<Optee> {
+    let optee: OpteeConfig = config.component(name)?;
+    let version = optee.version.clone();
+    let url = format!("https://github.com/OP-TEE/optee_os/archive/{}.tar.gz", version);
+
+    let download_dir = component::get_dir(&config.download_dir, name, "optee");
+    let build_dir = component::get_dir(&config.build_dir, name, "optee");
+
+    // Compose the path to the version file
+    let mut v_file = download_dir.clone();
+    v_file.push(format!("optee_os-{}.version", version));
+
+    let toolchain32 = match &optee.toolchain32 {
+        Some(name) => Some(toolchain::from_config(config, &config.load_toolchain(name)?)?),
+        None => None,
+    };
+
+    Ok(Optee {
+        name: name.to_string(),
+        source: Source {
+            pretty: "OP-TEE".to_string(),
+            url: url::Url::parse(&url).context(error::InvalidOpteeURL{})?,
+            // GitHub archives are named after the tag, but contain a
+            // directory named after the project. Store the archive under
+            // the latter.
+            archive: PathBuf::from(format!("optee_os-{}.tar.gz", version)),
+            git_rev: None,
+            source_dir: make_version_dir(&download_dir, &version),
+            download_dir: download_dir,
+            version_file: v_file,
+            patches_dir: make_patches_dir(&config.lib_dir, name, &version),
+            version: version.clone(),
+            interrupt: interrupt,
+        },
+        build_dir: make_version_dir(&build_dir, &version),
+        platform: optee.platform,
+        arm64: optee.arm64,
+        toolchain32: toolchain32,
+        jobs: config.jobs,
+    })
+}
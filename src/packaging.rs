@@ -0,0 +1,194 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::Write;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+
+use snafu::{ResultExt, OptionExt};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::config::{Config, PackagingConfig, RevisionScheme};
+use crate::util;
+
+/// Description of a package built by mktcb, before the packaging metadata
+/// of the target is applied
+pub struct Package<'a> {
+    pub name: &'a str,
+    /// Upstream version of the packaged software
+    pub version: &'a str,
+    pub summary: String,
+    pub section: &'a str,
+    pub priority: &'a str,
+}
+
+/// Version of a package
+#[derive(Clone)]
+pub struct Version {
+    pub epoch: Option<u32>,
+    /// Upstream version, possibly suffixed by the revision scheme
+    pub upstream: String,
+    pub revision: Option<String>,
+}
+
+impl Version {
+    /// Version without its epoch, as found in the names of package files
+    pub fn without_epoch(&self) -> String {
+        match &self.revision {
+            Some(revision) => format!("{}-{}", self.upstream, revision),
+            None => self.upstream.clone(),
+        }
+    }
+}
+
+/// Versions are displayed as Debian does: [epoch:]upstream[-revision]
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(epoch) = self.epoch {
+            write!(f, "{}:", epoch)?;
+        }
+        write!(f, "{}", self.without_epoch())
+    }
+}
+
+/// Metadata of a package, independent of the package format
+pub struct Metadata {
+    pub package: String,
+    pub version: Version,
+    pub maintainer: String,
+    pub summary: String,
+    /// Lines of the extended description
+    pub description: Vec<String>,
+    pub depends: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub replaces: Vec<String>,
+    pub section: String,
+    pub priority: String,
+}
+
+pub struct Packaging {
+    config: PackagingConfig,
+    target: String,
+    target_name: String,
+    pkg_dir: PathBuf,
+}
+
+impl Packaging {
+    pub fn scheme(&self) -> RevisionScheme {
+        self.config.revision
+    }
+
+    /// With the fixed revision scheme, packages of the same version of a
+    /// component always have the same version, whatever they contain. The
+    /// last version that was packaged in a format (deb, rpm) is recorded,
+    /// so that packaging it again is reported.
+    fn check_fixed(&self, component: &str, version: &str, format: &str) -> Result<()> {
+        let mut path = self.pkg_dir.clone();
+        path.push(format!("{}.{}.packaged", component, format));
+        if path.is_file() && util::read_file(&path)?.trim() == version {
+            warn!("{} {} was already packaged. With the fixed revision scheme, the new \
+                packages have the same version as the previous ones, although their \
+                contents may differ. Set revision to \"build\" or \"target\" in the \
+                packaging configuration to tell them apart.", component, version);
+        }
+        std::fs::create_dir_all(&self.pkg_dir).context(
+            error::CreateDirError{ path: self.pkg_dir.clone() })?;
+        std::fs::write(&path, format!("{}\n", version))
+            .context(error::FailedToWrite{path: path.clone()})
+    }
+
+    /// Retrieve the build number of the next packaging of a component. It
+    /// is incremented each time it is retrieved, so that packages built
+    /// again from the same sources still have different versions.
+    pub fn next_build(&self, component: &str, version: &str, format: &str) -> Result<u64> {
+        if self.config.revision == RevisionScheme::Fixed {
+            self.check_fixed(component, version, format)?;
+            return Ok(0);
+        }
+        let mut path = self.pkg_dir.clone();
+        path.push(format!("{}.build", component));
+        let build = if path.is_file() {
+            let data = util::read_file(&path)?;
+            let last: u64 = data.parse().ok()
+                .context(error::InvalidBuildNumber{ path: path.clone() })?;
+            last + 1
+        } else {
+            std::fs::create_dir_all(&self.pkg_dir).context(
+                error::CreateDirError{ path: self.pkg_dir.clone() })?;
+            1
+        };
+        let mut file = std::fs::File::create(&path).context(
+            error::CreateFileError{path: path.clone()})?;
+        writeln!(file, "{}", build)
+            .context(error::FailedToWrite{path: path.clone()})?;
+        Ok(build)
+    }
+
+    /// Compose the full version of a package from the upstream version
+    pub fn version(&self, upstream: &str, build: u64) -> Version {
+        // Only alphanumerics, '.' and '~' are safe within an upstream
+        // version that is not followed by a revision
+        let target: String = self.target.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '~' { c } else { '.' })
+            .collect();
+        let (upstream, revision) = match self.config.revision {
+            RevisionScheme::Fixed => (upstream.to_string(), None),
+            RevisionScheme::Build => (upstream.to_string(), Some(build.to_string())),
+            RevisionScheme::Target => (format!("{}+{}{}", upstream, target, build), None),
+        };
+        Version {
+            epoch: self.config.epoch,
+            upstream: upstream,
+            revision: revision,
+        }
+    }
+
+    pub fn maintainer(&self) -> Result<String> {
+        match &self.config.maintainer {
+            Some(maintainer) => Ok(maintainer.clone()),
+            None => util::getenv("MAINTAINER"),
+        }
+    }
+
+    /// Apply the packaging metadata of the target to a package
+    pub fn metadata(&self, package: &Package, build: u64) -> Result<Metadata> {
+        let version = self.version(package.version, build);
+        let summary = match &self.config.description {
+            Some(template) => template
+                .replace("{summary}", &package.summary)
+                .replace("{package}", package.name)
+                .replace("{version}", &version.to_string())
+                .replace("{target}", &self.target)
+                .replace("{name}", &self.target_name),
+            None => package.summary.clone(),
+        };
+        Ok(Metadata {
+            package: package.name.to_string(),
+            version: version,
+            maintainer: self.maintainer()?,
+            summary: summary,
+            description: Vec::new(),
+            depends: self.config.depends.clone(),
+            conflicts: self.config.conflicts.clone(),
+            replaces: self.config.replaces.clone(),
+            section: self.config.section.clone()
+                .unwrap_or(package.section.to_string()),
+            priority: self.config.priority.clone()
+                .unwrap_or(package.priority.to_string()),
+        })
+    }
+}
+
+pub fn new(config: &Config) -> Packaging {
+    let mut pkg_dir = config.build_dir.clone();
+    pkg_dir.push("packages");
+    Packaging {
+        config: config.packaging.clone(),
+        target: config.target.clone(),
+        target_name: config.target_name.clone(),
+        pkg_dir: pkg_dir,
+    }
+}
@@ -0,0 +1,269 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::{Seek, Write};
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+
+use snafu::{ResultExt, ensure};
+
+use crate::error::Result;
+use crate::error;
+use crate::config::{Filesystem, PartitionTable};
+use crate::util;
+
+pub const SECTOR: u64 = 512;
+
+/// Number of sectors used by the partition entries of a GPT
+const GPT_ENTRIES_SECTORS: u64 = 32;
+const GPT_ENTRIES: u32 = 128;
+const GPT_ENTRY_SIZE: u32 = 128;
+
+/// Partition of a disk image. Offsets and sizes are in bytes.
+pub struct Partition {
+    pub name: String,
+    pub start: u64,
+    pub size: u64,
+    pub filesystem: Option<Filesystem>,
+    pub bootable: bool,
+}
+
+impl Partition {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// Retrieve the size (in bytes) of the smallest disk that can hold a
+/// partition table. A GPT and its backup need the protective MBR, two
+/// headers and two copies of the partition entries.
+pub fn min_disk_size(table: PartitionTable) -> u64 {
+    match table {
+        PartitionTable::Mbr => SECTOR,
+        PartitionTable::Gpt => (2 * GPT_ENTRIES_SECTORS + 3) * SECTOR,
+    }
+}
+
+/// Retrieve the ranges of the disk (in bytes) that the partition table
+/// occupies. Nothing else may be written there, but the boot code area of
+/// the MBR is left to the bootloader. The disk must be at least
+/// min_disk_size() large.
+pub fn reserved(table: PartitionTable, disk_size: u64) -> Vec<(u64, u64)> {
+    match table {
+        PartitionTable::Mbr => vec![(440, SECTOR)],
+        PartitionTable::Gpt => vec![
+            (440, (2 + GPT_ENTRIES_SECTORS) * SECTOR),
+            (disk_size - (1 + GPT_ENTRIES_SECTORS) * SECTOR, disk_size),
+        ],
+    }
+}
+
+/// Compose a GUID from its textual representation. The first three fields
+/// are stored little-endian.
+fn guid(text: &str) -> [u8; 16] {
+    let hex: Vec<u8> = text.chars()
+        .filter(|c| *c != '-')
+        .collect::<Vec<char>>()
+        .chunks(2)
+        .map(|c| u8::from_str_radix(&c.iter().collect::<String>(), 16).unwrap())
+        .collect();
+    let mut result = [0u8; 16];
+    result.copy_from_slice(&hex);
+    result[0..4].reverse();
+    result[4..6].reverse();
+    result[6..8].reverse();
+    result
+}
+
+/// Derive a (version 4) GUID from a seed, so that building the same image
+/// twice gives the same GUIDs
+fn derived_guid(seed: &str) -> [u8; 16] {
+    let mut result = [0u8; 16];
+    for (i, chunk) in result.chunks_mut(4).enumerate() {
+        let crc = util::crc32(format!("{}:{}", i, seed).as_bytes());
+        chunk.copy_from_slice(&crc.to_le_bytes());
+    }
+    result[7] = (result[7] & 0x0f) | 0x40;
+    result[8] = (result[8] & 0x3f) | 0x80;
+    result
+}
+
+fn mbr_type(partition: &Partition) -> u8 {
+    match partition.filesystem {
+        Some(Filesystem::Fat) => 0x0c,
+        Some(Filesystem::Ext4) => 0x83,
+        None => 0xda,
+    }
+}
+
+fn gpt_type(partition: &Partition) -> [u8; 16] {
+    match partition.filesystem {
+        Some(Filesystem::Fat) => guid("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"),
+        _ => guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+    }
+}
+
+/// Compose an entry of an MBR. CHS addresses are not used, only LBA.
+fn mbr_entry(status: u8, kind: u8, start: u64, sectors: u64) -> [u8; 16] {
+    let mut entry = [0u8; 16];
+    entry[0] = status;
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors.min(0xffff_ffff) as u32).to_le_bytes());
+    entry
+}
+
+/// Write data at some offset of a file
+fn write_at(file: &mut std::fs::File, path: &PathBuf, offset: u64, data: &[u8]) -> Result<()> {
+    file.seek(std::io::SeekFrom::Start(offset))
+        .context(error::FailedToWrite{ path: path.clone() })?;
+    file.write_all(data).context(error::FailedToWrite{ path: path.clone() })
+}
+
+/// Compose the end of an MBR, starting at the disk signature. The boot code
+/// area that precedes it is left untouched.
+fn mbr(entries: &[[u8; 16]], signature: u32) -> Vec<u8> {
+    let mut mbr = vec![0u8; 512 - 440];
+    mbr[0..4].copy_from_slice(&signature.to_le_bytes());
+    for (i, entry) in entries.iter().enumerate() {
+        mbr[6 + i * 16..6 + (i + 1) * 16].copy_from_slice(entry);
+    }
+    mbr[70] = 0x55;
+    mbr[71] = 0xaa;
+    mbr
+}
+
+fn write_mbr(file: &mut std::fs::File, path: &PathBuf, parts: &[Partition], seed: &str) -> Result<()> {
+    ensure!(parts.len() <= 4, error::TooManyPartitions{ max: 4usize });
+    let entries: Vec<[u8; 16]> = parts.iter().map(|p| mbr_entry(
+        if p.bootable { 0x80 } else { 0x00 },
+        mbr_type(p), p.start / SECTOR, p.size / SECTOR)).collect();
+    write_at(file, path, 440, &mbr(&entries, util::crc32(seed.as_bytes())))
+}
+
+/// Compose a GPT header. Its CRC is computed once all the fields are set.
+fn gpt_header(current: u64, backup: u64, last: u64, disk_guid: &[u8; 16],
+              entries_lba: u64, entries_crc: u32) -> Vec<u8> {
+    let mut header = vec![0u8; SECTOR as usize];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&current.to_le_bytes());
+    header[32..40].copy_from_slice(&backup.to_le_bytes());
+    header[40..48].copy_from_slice(&(2 + GPT_ENTRIES_SECTORS).to_le_bytes());
+    header[48..56].copy_from_slice(&(last - 1 - GPT_ENTRIES_SECTORS).to_le_bytes());
+    header[56..72].copy_from_slice(disk_guid);
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&GPT_ENTRIES.to_le_bytes());
+    header[84..88].copy_from_slice(&GPT_ENTRY_SIZE.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = util::crc32(&header[0..92]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn write_gpt(file: &mut std::fs::File, path: &PathBuf, disk_size: u64,
+             parts: &[Partition], seed: &str) -> Result<()> {
+    ensure!(parts.len() <= GPT_ENTRIES as usize, error::TooManyPartitions{
+        max: GPT_ENTRIES as usize });
+    let last = disk_size / SECTOR - 1;
+
+    let mut entries = vec![0u8; (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize];
+    for (i, part) in parts.iter().enumerate() {
+        let entry = &mut entries[i * GPT_ENTRY_SIZE as usize..(i + 1) * GPT_ENTRY_SIZE as usize];
+        entry[0..16].copy_from_slice(&gpt_type(part));
+        entry[16..32].copy_from_slice(&derived_guid(&format!("{}/{}", seed, i)));
+        entry[32..40].copy_from_slice(&(part.start / SECTOR).to_le_bytes());
+        entry[40..48].copy_from_slice(&(part.end() / SECTOR - 1).to_le_bytes());
+        // Bit 2 is the legacy BIOS bootable attribute, that U-Boot honours
+        let attributes: u64 = if part.bootable { 1 << 2 } else { 0 };
+        entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+        for (j, c) in part.name.encode_utf16().take(36).enumerate() {
+            entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    let entries_crc = util::crc32(&entries);
+    let disk_guid = derived_guid(seed);
+
+    // The protective MBR covers the whole disk
+    let protective = mbr_entry(0x00, 0xee, 1, last);
+    write_at(file, path, 440, &mbr(&[protective], 0))?;
+
+    write_at(file, path, SECTOR, &gpt_header(1, last, last, &disk_guid, 2, entries_crc))?;
+    write_at(file, path, 2 * SECTOR, &entries)?;
+    let backup_entries = last - GPT_ENTRIES_SECTORS;
+    write_at(file, path, backup_entries * SECTOR, &entries)?;
+    write_at(file, path, last * SECTOR,
+        &gpt_header(last, 1, last, &disk_guid, backup_entries, entries_crc))
+}
+
+/// Write the partition table of a disk image. The seed makes the
+/// identifiers of the disk and its partitions stable across builds.
+pub fn write(table: PartitionTable, path: &PathBuf, disk_size: u64,
+             parts: &[Partition], seed: &str) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .context(error::FailedToWrite{ path: path.clone() })?;
+    match table {
+        PartitionTable::Mbr => write_mbr(&mut file, path, parts, seed),
+        PartitionTable::Gpt => write_gpt(&mut file, path, disk_size, parts, seed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guid_layout() {
+        assert_eq!(guid("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"), [
+            0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44,
+            0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+        let derived = derived_guid("seed");
+        assert_eq!(derived, derived_guid("seed"));
+        assert_eq!(derived[7] >> 4, 4);
+        assert_eq!(derived[8] >> 6, 2);
+    }
+
+    #[test]
+    fn mbr_layout() {
+        let entry = mbr_entry(0x80, 0x0c, 2048, 4096);
+        assert_eq!(entry, [0x80, 0xfe, 0xff, 0xff, 0x0c, 0xfe, 0xff, 0xff,
+            0x00, 0x08, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00]);
+        let mbr = mbr(&[entry], 0x1234_5678);
+        assert_eq!(mbr.len(), 72);
+        assert_eq!(mbr[0..4], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(mbr[6..22], entry);
+        assert!(mbr[22..70].iter().all(|b| *b == 0));
+        assert_eq!(mbr[70..72], [0x55, 0xaa]);
+    }
+
+    #[test]
+    fn gpt_header_layout() {
+        let disk_guid: Vec<u8> = (1..=16).collect();
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&disk_guid);
+        let entries_crc = util::crc32(&[0u8; (GPT_ENTRIES * GPT_ENTRY_SIZE) as usize]);
+        // Well-known CRC of an empty partition array
+        assert_eq!(entries_crc, 0xab54_d286);
+
+        let header = gpt_header(1, 2047, 2047, &guid, 2, entries_crc);
+        assert_eq!(header.len(), 512);
+        assert_eq!(&header[0..16], b"EFI PART\x00\x00\x01\x00\x5c\x00\x00\x00");
+        assert_eq!(header[16..20], 0x5a82_9c09u32.to_le_bytes());
+        assert_eq!(header[40..48], 34u64.to_le_bytes());
+        assert_eq!(header[48..56], 2014u64.to_le_bytes());
+        assert!(header[92..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn gpt_reserved() {
+        let min = min_disk_size(PartitionTable::Gpt);
+        assert_eq!(reserved(PartitionTable::Gpt, min),
+            vec![(440, 34 * SECTOR), (34 * SECTOR, 67 * SECTOR)]);
+    }
+}
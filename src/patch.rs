@@ -25,7 +25,10 @@ pub fn patch(working_dir: &std::path::PathBuf, diff: &std::path::PathBuf) -> Res
     Ok(())
 }
 
-pub fn apply_patches_in(patches_dir: &PathBuf, source_dir: &PathBuf) -> Result<()> {
+/// List the patches that reside in a directory, in the order they must be
+/// applied (i.e. sorted by name).
+pub fn list_patches_in(patches_dir: &PathBuf) -> Result<Vec<PathBuf>> {
+    let mut patches = Vec::new();
     if patches_dir.is_dir() {
         let dir_iter = std::fs::read_dir(&patches_dir)
             .context(error::DirIterFailed{dir: patches_dir.clone()})?;
@@ -34,9 +37,10 @@ pub fn apply_patches_in(patches_dir: &PathBuf, source_dir: &PathBuf) -> Result<(
                 .context(error::DirIterFailed{dir: patches_dir.clone()})?
                 .path();
             if entry.is_file() {
-                patch(&source_dir, &entry)?;
+                patches.push(entry);
             }
         }
     }
-    Ok(())
+    patches.sort();
+    Ok(patches)
 }
@@ -0,0 +1,170 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::BufRead;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use snafu::{ResultExt, OptionExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component::Component;
+use crate::config::{Config, QemuConfig};
+use crate::interrupt::Interrupt;
+use crate::linux;
+use crate::uboot;
+
+/// Console messages telling that the kernel crashed
+const PANICS: [&str; 2] = ["Kernel panic", "Internal error: Oops"];
+
+/// Select the emulator of the architecture of the toolchain, unless the
+/// target chose one
+fn emulator(config: &Config, qemu: &QemuConfig) -> Result<String> {
+    if let Some(prog) = &qemu.qemu {
+        return Ok(prog.clone());
+    }
+    match config.toolchain.linux_arch.as_str() {
+        "arm" => Ok("qemu-system-arm".to_string()),
+        "arm64" => Ok("qemu-system-aarch64".to_string()),
+        arch => error::UnsupportedQemuArch{ arch: arch.to_string() }.fail(),
+    }
+}
+
+/// Compose the command line of QEMU. The serial console is redirected to
+/// the standard output, and QEMU exits instead of rebooting.
+fn command(config: &Config, interrupt: &Interrupt, qemu: &QemuConfig) -> Result<Command> {
+    let mut cmd = Command::new(emulator(config, qemu)?);
+    cmd.arg("-M").arg(&qemu.machine)
+        .arg("-m").arg(&qemu.memory)
+        .arg("-display").arg("none")
+        .arg("-serial").arg("stdio")
+        .arg("-monitor").arg("none")
+        .arg("-no-reboot");
+    if let Some(cpu) = &qemu.cpu {
+        cmd.arg("-cpu").arg(cpu);
+    }
+
+    if let Some(binary) = &qemu.uboot {
+        let name = config.first_component("uboot")
+            .context(error::NoComponent{ component: "uboot".to_string() })?;
+        let uboot = uboot::new(config, &name, interrupt.clone())?;
+        let mut path = uboot.build_dir().clone();
+        path.push(binary);
+        ensure!(path.is_file(), error::FileDoesNotExist{ path: path.clone() });
+        cmd.arg("-bios").arg(path);
+    } else {
+        let name = config.first_component("linux")
+            .context(error::NoComponent{ component: "linux".to_string() })?;
+        let linux = linux::new(config, &name, interrupt.clone())?;
+        let artifacts = linux.artifacts();
+        let kernel = artifacts.iter().find(|a| a.name == "kernel")
+            .context(error::NoArtifact{ component: name.clone(), artifact: "kernel".to_string() })?;
+        ensure!(kernel.path.is_file(), error::FileDoesNotExist{ path: kernel.path.clone() });
+        cmd.arg("-kernel").arg(&kernel.path);
+        if let Some(dtb) = &qemu.dtb {
+            let dtb = artifacts.iter().find(|a| &a.name == dtb)
+                .context(error::NoArtifact{ component: name.clone(), artifact: dtb.clone() })?;
+            ensure!(dtb.path.is_file(), error::FileDoesNotExist{ path: dtb.path.clone() });
+            cmd.arg("-dtb").arg(&dtb.path);
+        }
+        if let Some(initramfs) = &qemu.initramfs {
+            let mut path = config.lib_dir.clone();
+            path.push(initramfs);
+            ensure!(path.is_file(), error::FileDoesNotExist{ path: path.clone() });
+            cmd.arg("-initrd").arg(path);
+        }
+        if ! qemu.append.is_empty() {
+            cmd.arg("-append").arg(&qemu.append);
+        }
+    }
+
+    if let Some(interface) = &qemu.drive {
+        let mut image = config.build_dir.clone();
+        image.push("images");
+        image.push(format!("{}.img", config.target));
+        if image.is_file() {
+            cmd.arg("-drive").arg(format!("if={},format=raw,snapshot=on,file={}",
+                interface, image.display()));
+        } else {
+            warn!("No disk image at {:#?}. Booting without it.", image);
+        }
+    }
+    cmd.args(&qemu.args);
+    Ok(cmd)
+}
+
+/// Boot the kernel that was built (or U-Boot) in QEMU, and watch its serial
+/// console until the configured pattern shows up. The boot fails if the
+/// kernel crashes, if QEMU exits, or on timeout. The console output is
+/// saved in test/boot.log of the build directory, whose path is returned.
+pub fn boot(config: &Config, interrupt: Interrupt, timeout: Option<u64>) -> Result<PathBuf> {
+    let qemu = config.qemu.as_ref().context(error::NoQemuConfig{})?;
+    let timeout = timeout.unwrap_or(qemu.timeout);
+    let mut cmd = command(config, &interrupt, qemu)?;
+
+    let mut log_path = config.build_dir.clone();
+    log_path.push("test");
+    std::fs::create_dir_all(&log_path).context(error::CreateDirError{ path: log_path.clone() })?;
+    log_path.push("boot.log");
+
+    debug!("Running {:?}", cmd);
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(error::ProgFailed{ proc: emulator(config, qemu)? })?;
+
+    // Lines of the console are read by a separate thread, so that the wait
+    // can be bounded. The channel is closed when QEMU exits.
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reader = std::io::BufReader::new(stdout);
+        let mut line = Vec::new();
+        while let Ok(n) = reader.read_until(b'\n', &mut line) {
+            if n == 0 || sender.send(String::from_utf8_lossy(&line).to_string()).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+
+    info!("Booting in QEMU ({}), waiting up to {}s for '{}'", qemu.machine, timeout, qemu.pattern);
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut log = String::new();
+    let mut result = None;
+    while result.is_none() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(left) {
+            Ok(line) => {
+                trace!("{}", line.trim_end());
+                log.push_str(&line);
+                if line.contains(&qemu.pattern) {
+                    result = Some(Ok(()));
+                } else if let Some(panic) = PANICS.iter().find(|p| line.contains(*p)) {
+                    result = Some(error::BootPanic{
+                        line: panic.to_string(), log: log_path.clone() }.fail());
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                result = Some(error::BootTimeout{ timeout: timeout, log: log_path.clone() }.fail());
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                result = Some(error::BootAborted{ log: log_path.clone() }.fail());
+            },
+        }
+    }
+    // QEMU may already have exited
+    let _ = child.kill();
+    let _ = child.wait();
+
+    std::fs::write(&log_path, log).context(error::FailedToWrite{ path: log_path.clone() })?;
+    result.unwrap()?;
+    Ok(log_path)
+}
@@ -0,0 +1,313 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, OptionExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::config::{Config, RepositoryConfig};
+use crate::util;
+
+/// Package of the pool of the repository
+struct PoolEntry {
+    package: String,
+    version: String,
+    arch: String,
+    path: PathBuf,
+}
+
+/// Compose the path to the file in which the Debian packages of a component
+/// are recorded, each time they are built
+pub fn record_path(config: &Config, component: &str) -> PathBuf {
+    let mut path = config.build_dir.clone();
+    path.push("packages");
+    path.push(format!("{}.debs", component));
+    path
+}
+
+/// Run a program and retrieve its standard output
+fn output(cmd: &mut Command, proc: &str) -> Result<String> {
+    let out = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .context(error::ProgFailed{ proc: proc.to_string() })?;
+    ensure!(out.status.success(), error::RepoFailed{ proc: proc.to_string() });
+    String::from_utf8(out.stdout).context(error::FailedToDecodeUTF8{})
+}
+
+fn file_size(path: &PathBuf) -> Result<u64> {
+    Ok(std::fs::metadata(path)
+        .context(error::FailedToRead{ path: path.clone() })?
+        .len())
+}
+
+/// Retrieve the control file of a Debian package
+fn control(deb: &PathBuf) -> Result<String> {
+    output(Command::new("dpkg-deb").arg("-f").arg(deb), "dpkg-deb")
+}
+
+/// Retrieve the value of a (single-line) field of a control file
+fn field(control: &str, name: &str, deb: &PathBuf) -> Result<String> {
+    let prefix = format!("{}:", name);
+    control.lines()
+        .find(|line| line.starts_with(&prefix))
+        .map(|line| line[prefix.len()..].trim().to_string())
+        .context(error::MissingControlField{ field: name.to_string(), deb: deb.clone() })
+}
+
+fn pool_entry(deb: &PathBuf) -> Result<PoolEntry> {
+    let control = control(deb)?;
+    Ok(PoolEntry {
+        package: field(&control, "Package", deb)?,
+        version: field(&control, "Version", deb)?,
+        arch: field(&control, "Architecture", deb)?,
+        path: deb.clone(),
+    })
+}
+
+/// Tell whether a Debian version is older than another one
+fn version_lt(a: &str, b: &str) -> Result<bool> {
+    let status = Command::new("dpkg")
+        .arg("--compare-versions").arg(a).arg("lt").arg(b)
+        .stdin(Stdio::null())
+        .status()
+        .context(error::ProgFailed{ proc: "dpkg".to_string() })?;
+    Ok(status.success())
+}
+
+/// Retrieve the packages listed in files written by --debpkg. Without any
+/// file, the packages that were built for the target are retrieved.
+fn collect(config: &Config, lists: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let lists = if lists.is_empty() {
+        config.components.keys()
+            .map(|name| record_path(config, name))
+            .filter(|path| path.is_file())
+            .collect()
+    } else {
+        lists.to_vec()
+    };
+    let mut packages = Vec::new();
+    for list in &lists {
+        for line in util::read_file(list)?.lines() {
+            let path = PathBuf::from(line.trim());
+            ensure!(path.is_file(), error::FileDoesNotExist{ path: path.clone() });
+            packages.push(path);
+        }
+    }
+    Ok(packages)
+}
+
+/// Copy a package in the pool, under the name Debian tools give it
+fn add_to_pool(pool: &PathBuf, deb: &PathBuf) -> Result<()> {
+    let entry = pool_entry(deb)?;
+    let version = match entry.version.find(':') {
+        Some(index) => &entry.version[index + 1..],
+        None => &entry.version,
+    };
+    let mut to = pool.clone();
+    to.push(format!("{}_{}_{}.deb", entry.package, version, entry.arch));
+    if to.exists() {
+        warn!("{:#?} is already in the repository. Replacing it.", to);
+    }
+    info!("Publishing {} {} ({})", entry.package, entry.version, entry.arch);
+    std::fs::copy(deb, &to).context(error::CopyFailed{ from: deb.clone(), to: to.clone() })?;
+    Ok(())
+}
+
+/// Retrieve the packages of the pool, by package name and architecture.
+/// Versions are sorted from the oldest to the newest.
+fn read_pool(pool: &PathBuf) -> Result<BTreeMap<(String, String), Vec<PoolEntry>>> {
+    let mut result: BTreeMap<(String, String), Vec<PoolEntry>> = BTreeMap::new();
+    let entries = std::fs::read_dir(pool).context(error::DirIterFailed{ dir: pool.clone() })?;
+    for entry in entries {
+        let path = entry.context(error::DirIterFailed{ dir: pool.clone() })?.path();
+        if path.extension().map(|e| e == "deb").unwrap_or(false) {
+            let entry = pool_entry(&path)?;
+            result.entry((entry.package.clone(), entry.arch.clone()))
+                .or_default()
+                .push(entry);
+        }
+    }
+    for versions in result.values_mut() {
+        // Insertion sort, as comparing versions requires dpkg
+        let mut sorted: Vec<PoolEntry> = Vec::new();
+        for entry in versions.drain(..) {
+            let mut index = sorted.len();
+            while index > 0 && version_lt(&entry.version, &sorted[index - 1].version)? {
+                index -= 1;
+            }
+            sorted.insert(index, entry);
+        }
+        *versions = sorted;
+    }
+    Ok(result)
+}
+
+/// Remove the oldest versions of each package, so that only keep of them
+/// remain
+fn prune(packages: &mut BTreeMap<(String, String), Vec<PoolEntry>>, keep: usize) -> Result<()> {
+    for versions in packages.values_mut() {
+        while versions.len() > keep {
+            let entry = versions.remove(0);
+            info!("Pruning {} {} ({})", entry.package, entry.version, entry.arch);
+            std::fs::remove_file(&entry.path).context(
+                error::RemoveFileError{ path: entry.path.clone() })?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the Packages index of an architecture, and its compressed version.
+/// Packages of architecture all are listed in all indices.
+fn write_packages(dir: &PathBuf, index_dir: &PathBuf, arch: &str,
+                  packages: &BTreeMap<(String, String), Vec<PoolEntry>>) -> Result<Vec<PathBuf>> {
+    let mut index = String::new();
+    for ((_, pkg_arch), versions) in packages {
+        if pkg_arch != arch && pkg_arch != "all" {
+            continue;
+        }
+        for entry in versions {
+            index.push_str(control(&entry.path)?.trim_end());
+            index.push_str(&format!("\nFilename: {}\nSize: {}\nMD5sum: {}\nSHA256: {}\n\n",
+                entry.path.strip_prefix(dir).unwrap().to_str().unwrap(),
+                file_size(&entry.path)?,
+                util::checksum("md5sum", &entry.path)?,
+                util::checksum("sha256sum", &entry.path)?));
+        }
+    }
+
+    std::fs::create_dir_all(index_dir).context(
+        error::CreateDirError{ path: index_dir.clone() })?;
+    let mut path = index_dir.clone();
+    path.push("Packages");
+    std::fs::write(&path, index).context(error::FailedToWrite{ path: path.clone() })?;
+
+    let mut gz = index_dir.clone();
+    gz.push("Packages.gz");
+    let file = std::fs::File::create(&gz).context(error::CreateFileError{ path: gz.clone() })?;
+    let status = Command::new("gzip")
+        .arg("-9nc")
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stdout(file)
+        .status()
+        .context(error::ProgFailed{ proc: "gzip".to_string() })?;
+    ensure!(status.success(), error::RepoFailed{ proc: "gzip".to_string() });
+    Ok(vec![path, gz])
+}
+
+/// Write the Release file of a suite, that lists the checksums of all the
+/// indices
+fn write_release(suite_dir: &PathBuf, cfg: &RepositoryConfig, archs: &BTreeSet<String>,
+                 indices: &[PathBuf]) -> Result<PathBuf> {
+    let mut release = String::new();
+    if let Some(origin) = &cfg.origin {
+        release.push_str(&format!("Origin: {}\n", origin));
+    }
+    if let Some(label) = &cfg.label {
+        release.push_str(&format!("Label: {}\n", label));
+    }
+    release.push_str(&format!("Suite: {}\nCodename: {}\nDate: {}\n",
+        cfg.suite, cfg.suite, util::rfc2822(util::now())));
+    release.push_str(&format!("Architectures: {}\nComponents: {}\n",
+        archs.iter().cloned().collect::<Vec<String>>().join(" "), cfg.component));
+    for (name, prog) in &[("MD5Sum", "md5sum"), ("SHA256", "sha256sum")] {
+        release.push_str(&format!("{}:\n", name));
+        for index in indices {
+            release.push_str(&format!(" {} {} {}\n",
+                util::checksum(prog, index)?,
+                file_size(index)?,
+                index.strip_prefix(suite_dir).unwrap().to_str().unwrap()));
+        }
+    }
+
+    let mut path = suite_dir.clone();
+    path.push("Release");
+    std::fs::write(&path, release).context(error::FailedToWrite{ path: path.clone() })?;
+    Ok(path)
+}
+
+/// Sign the Release file, both inline (InRelease) and detached (Release.gpg)
+fn sign(release: &PathBuf, key: &str) -> Result<()> {
+    let dir = release.parent().unwrap().to_path_buf();
+    for (args, out) in &[
+        (&["--clearsign"][..], "InRelease"),
+        (&["--detach-sign", "--armor"][..], "Release.gpg"),
+    ] {
+        let mut path = dir.clone();
+        path.push(out);
+        let status = Command::new("gpg")
+            .arg("--batch").arg("--yes")
+            .arg("--local-user").arg(key)
+            .args(args.iter())
+            .arg("--output").arg(&path)
+            .arg(release)
+            .stdin(Stdio::null())
+            .status()
+            .context(error::ProgFailed{ proc: "gpg".to_string() })?;
+        ensure!(status.success(), error::RepoFailed{ proc: "gpg".to_string() });
+    }
+    Ok(())
+}
+
+/// Publish Debian packages in the APT repository at dir. Packages already
+/// published (e.g. for other targets) are kept, and the indices of the suite
+/// are re-generated.
+pub fn publish(config: &Config, dir: &PathBuf, lists: &[PathBuf],
+               suite: Option<&str>, key: Option<&str>, keep: Option<usize>) -> Result<()> {
+    let cfg = RepositoryConfig {
+        suite: suite.map(|s| s.to_string()).unwrap_or(config.repository.suite.clone()),
+        key: key.map(|k| k.to_string()).or(config.repository.key.clone()),
+        keep: keep.or(config.repository.keep),
+        component: config.repository.component.clone(),
+        origin: config.repository.origin.clone(),
+        label: config.repository.label.clone(),
+    };
+
+    let packages = collect(config, lists)?;
+    ensure!(! packages.is_empty(), error::NothingToPublish{});
+
+    let mut pool = dir.clone();
+    pool.push("pool");
+    pool.push(&cfg.suite);
+    std::fs::create_dir_all(&pool).context(error::CreateDirError{ path: pool.clone() })?;
+    for deb in &packages {
+        add_to_pool(&pool, deb)?;
+    }
+
+    let mut published = read_pool(&pool)?;
+    if let Some(keep) = cfg.keep {
+        prune(&mut published, keep)?;
+    }
+
+    // The architecture of the target is always indexed, even if it only
+    // received packages of architecture all
+    let mut archs: BTreeSet<String> = published.keys()
+        .map(|(_, arch)| arch.clone())
+        .filter(|arch| arch != "all")
+        .collect();
+    archs.insert(config.toolchain.debian_arch.clone());
+
+    let mut suite_dir = dir.clone();
+    suite_dir.push("dists");
+    suite_dir.push(&cfg.suite);
+    let mut indices = Vec::new();
+    for arch in &archs {
+        let mut index_dir = suite_dir.clone();
+        index_dir.push(&cfg.component);
+        index_dir.push(format!("binary-{}", arch));
+        indices.extend(write_packages(dir, &index_dir, arch, &published)?);
+    }
+    let release = write_release(&suite_dir, &cfg, &archs, &indices)?;
+
+    match &cfg.key {
+        Some(key) => sign(&release, key)?,
+        None => warn!("No signing key configured. The repository is not signed."),
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use snafu::{ResultExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::build;
+use crate::component;
+use crate::config::Config;
+use crate::interrupt::Interrupt;
+use crate::manifest;
+use crate::toolchain;
+use crate::util;
+use crate::version_file;
+
+/// User and host that builds record, instead of the actual ones
+const BUILD_USER: &str = "mktcb";
+const BUILD_HOST: &str = "mktcb";
+
+/// Retrieve the last time a file changed (UNIX timestamp)
+fn mtime(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+}
+
+/// Retrieve the date of some sources (UNIX timestamp), as recorded in their
+/// version file when they were fetched. SOURCE_DATE_EPOCH takes precedence.
+/// Version files written by older versions of mktcb do not record it: the
+/// last time they changed is used instead, which only holds on the machine
+/// that fetched the sources.
+pub fn source_date_epoch(version_file: Option<&PathBuf>) -> u64 {
+    if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|v| v.parse().ok()) {
+        return epoch;
+    }
+    let path = match version_file {
+        Some(path) if path.exists() => path,
+        _ => return util::now(),
+    };
+    match version_file::load(path).ok().and_then(|recorded| recorded.epoch) {
+        Some(epoch) => epoch,
+        None => {
+            debug!("{:#?} does not record the date of the sources", path);
+            std::fs::metadata(path).ok().as_ref().and_then(mtime).unwrap_or_else(util::now)
+        },
+    }
+}
+
+/// Retrieve the last time a file of a source tree changed (UNIX timestamp).
+/// Archives keep the dates of their files, so for sources that were just
+/// unpacked, this is the date of their release.
+pub fn tree_epoch(dir: &PathBuf) -> Result<u64> {
+    let mut epoch = 0;
+    let entries = std::fs::read_dir(dir).context(error::DirIterFailed{ dir: dir.clone() })?;
+    for entry in entries {
+        let path = entry.context(error::DirIterFailed{ dir: dir.clone() })?.path();
+        let metadata = std::fs::symlink_metadata(&path).context(
+            error::DirIterFailed{ dir: dir.clone() })?;
+        let date = if metadata.is_dir() {
+            tree_epoch(&path)?
+        } else {
+            mtime(&metadata).unwrap_or(0)
+        };
+        epoch = epoch.max(date);
+    }
+    Ok(epoch)
+}
+
+/// Set the environment of a build (Linux, U-Boot), so that its output does
+/// not depend on when, where and by whom it is built
+pub fn set_env(cmd: &mut Command, epoch: u64) {
+    cmd
+        .env("SOURCE_DATE_EPOCH", epoch.to_string())
+        .env("KBUILD_BUILD_TIMESTAMP", util::rfc2822(epoch))
+        .env("KBUILD_BUILD_USER", BUILD_USER)
+        .env("KBUILD_BUILD_HOST", BUILD_HOST)
+        .env("KBUILD_BUILD_VERSION", "1")
+        .env("TZ", "UTC");
+}
+
+/// Build all the components of the target in a build directory, and package
+/// them if requested. The files they produced are returned, relative to the
+/// build directory.
+fn build_in(config: &Config, build_dir: &PathBuf, interrupt: Interrupt,
+            packages: bool) -> Result<Vec<PathBuf>> {
+    if build_dir.exists() {
+        std::fs::remove_dir_all(build_dir).context(
+            error::RemoveDirError{ path: build_dir.clone() })?;
+    }
+    let mut config = config.clone();
+    config.build_dir = build_dir.clone();
+    build::run(&config, interrupt.clone(), false)?;
+
+    let toolchain = toolchain::new(&config)?;
+    let mut files = Vec::new();
+    for name in config.components.keys() {
+        let mut agent = component::new(name, &config, interrupt.clone())?;
+        files.extend(agent.artifacts().into_iter().map(|a| a.path));
+        if packages {
+            let started = std::time::SystemTime::now();
+            match agent.package(&toolchain) {
+                Ok(result) => {
+                    manifest::record(&config, agent.as_mut(), "debpkg", None, started,
+                        &result.make, &result.files)?;
+                    files.extend(result.files);
+                },
+                Err(error::Error::NotSupported{ .. }) => debug!("{} cannot be packaged", name),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(files.iter()
+        .filter_map(|path| path.strip_prefix(build_dir).ok())
+        .map(|path| path.to_path_buf())
+        .collect())
+}
+
+/// Build the target twice, in separate build directories, and make sure
+/// both builds produced the same artifacts (and packages). The files that
+/// differ are reported.
+pub fn verify(config: &Config, interrupt: Interrupt, packages: bool) -> Result<()> {
+    // A file that only one of the builds produced is a difference as well,
+    // so the files of both are compared
+    let mut dirs = Vec::new();
+    let mut files = std::collections::BTreeSet::new();
+    for pass in 1..=2 {
+        let mut dir = config.build_dir.clone();
+        dir.push("reproducible");
+        dir.push(pass.to_string());
+        info!("Building the target in {:#?} (pass {}/2)", dir, pass);
+        files.extend(build_in(config, &dir, interrupt.clone(), packages)?);
+        dirs.push(dir);
+    }
+
+    let mut differences = 0;
+    for file in &files {
+        let read = |dir: &PathBuf| {
+            let mut path = dir.clone();
+            path.push(file);
+            std::fs::read(&path).ok()
+        };
+        match (read(&dirs[0]), read(&dirs[1])) {
+            (Some(first), Some(second)) if first == second => println!("identical  {}", file.display()),
+            (Some(_), Some(_)) => {
+                println!("DIFFERENT  {}", file.display());
+                differences += 1;
+            },
+            _ => {
+                println!("MISSING    {}", file.display());
+                differences += 1;
+            },
+        }
+    }
+    ensure!(differences == 0, error::NotReproducible{ count: differences as usize });
+    Ok(())
+}
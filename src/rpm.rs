@@ -0,0 +1,118 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::Write;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, ensure};
+
+use crate::error::Result;
+use crate::error;
+use crate::packaging::Metadata;
+
+/// Retrieve the RPM architecture matching a Debian architecture, for
+/// toolchains that do not provide it
+pub fn arch(debian_arch: &str) -> String {
+    match debian_arch {
+        "armhf" => "armv7hl",
+        "armel" => "armv5tel",
+        "arm64" => "aarch64",
+        "amd64" => "x86_64",
+        "i386" => "i686",
+        "ppc64el" => "ppc64le",
+        other => other,
+    }.to_string()
+}
+
+/// Generate the spec file of a package that contains no file, such as a
+/// meta-package
+pub fn spec(meta: &Metadata) -> String {
+    let mut spec = format!("Name: {}\nVersion: {}\nRelease: {}\n",
+        meta.package, meta.version.upstream,
+        meta.version.revision.as_deref().unwrap_or("1"));
+    if let Some(epoch) = meta.version.epoch {
+        spec.push_str(&format!("Epoch: {}\n", epoch));
+    }
+    spec.push_str(&format!("Summary: {}\nLicense: GPL-2.0\nGroup: {}\nPackager: {}\n",
+        meta.summary, meta.section, meta.maintainer));
+    for (field, values) in &[
+        ("Requires", &meta.depends),
+        ("Conflicts", &meta.conflicts),
+        ("Obsoletes", &meta.replaces),
+    ] {
+        for value in values.iter() {
+            spec.push_str(&format!("{}: {}\n", field, value));
+        }
+    }
+    spec.push_str("\n%description\n");
+    for line in &meta.description {
+        spec.push_str(&format!("{}\n", line));
+    }
+    spec.push_str("\n%files\n");
+    spec
+}
+
+/// Retrieve all the RPM packages that were built in the rpmbuild tree
+/// topdir
+pub fn list(topdir: &PathBuf) -> Result<Vec<PathBuf>> {
+    fn walk(dir: &PathBuf, packages: &mut Vec<PathBuf>) -> Result<()> {
+        let entries = std::fs::read_dir(dir).context(
+            error::DirIterFailed{ dir: dir.clone() })?;
+        for entry in entries {
+            let path = entry.context(error::DirIterFailed{ dir: dir.clone() })?.path();
+            if path.is_dir() {
+                walk(&path, packages)?;
+            } else if path.extension().map(|e| e == "rpm").unwrap_or(false) {
+                packages.push(path);
+            }
+        }
+        Ok(())
+    }
+    let mut packages = Vec::new();
+    let mut rpms = topdir.clone();
+    rpms.push("RPMS");
+    if rpms.is_dir() {
+        walk(&rpms, &mut packages)?;
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+/// Run rpmbuild on the spec of a package without files. The spec and the
+/// package are created in the rpmbuild tree topdir.
+/// Upon success, the path to the created package is returned.
+pub fn build(topdir: &PathBuf, meta: &Metadata, arch: &str) -> Result<PathBuf> {
+    let mut specs = topdir.clone();
+    specs.push("SPECS");
+    std::fs::create_dir_all(&specs).context(
+        error::CreateDirError{ path: specs.clone() })?;
+    let mut spec_file = specs.clone();
+    spec_file.push(format!("{}.spec", meta.package));
+    {
+        let mut file = std::fs::File::create(&spec_file)
+            .context(error::CreateFileError{path: spec_file.clone()})?;
+        file.write_all(spec(meta).as_bytes())
+            .context(error::FailedToWrite{path: spec_file.clone()})?;
+    }
+
+    let status = Command::new("rpmbuild")
+        .arg("--define").arg(format!("_topdir {}", topdir.to_str().unwrap()))
+        .arg("--target").arg(arch)
+        .arg("-bb")
+        .arg(&spec_file)
+        .stdin(Stdio::null())
+        .status()
+        .context(error::ProgFailed{ proc: "rpmbuild".to_string() })?;
+    ensure!(status.success(), error::RpmFailed{package: meta.package.clone()});
+
+    let mut result = topdir.clone();
+    result.push("RPMS");
+    result.push(arch);
+    result.push(format!("{}-{}-{}.{}.rpm", meta.package, meta.version.upstream,
+        meta.version.revision.as_deref().unwrap_or("1"), arch));
+    ensure!(result.is_file(), error::NoPackage{path:result.clone()});
+    Ok(result)
+}
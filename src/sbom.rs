@@ -0,0 +1,510 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use serde_json::{json, Value};
+use snafu::{ResultExt, OptionExt};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::config::Config;
+use crate::interrupt::Interrupt;
+use crate::manifest;
+use crate::manifest::{Manifest, ComponentRecord};
+use crate::util;
+
+/// Tag through which source files declare their license
+const SPDX_TAG: &str = "SPDX-License-Identifier:";
+
+/// SBOM formats that mktcb emits
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Spdx,
+    CycloneDx,
+}
+
+/// What is known of a component, besides what the manifest records
+struct Licenses {
+    /// License of the project, as a whole
+    declared: Option<&'static str>,
+    /// Licenses of the files that were actually compiled
+    concluded: Option<String>,
+}
+
+/// License of the components mktcb knows about
+fn declared_license(kind: &str) -> Option<&'static str> {
+    match kind {
+        "linux" => Some("GPL-2.0-only"),
+        "uboot" => Some("GPL-2.0-or-later"),
+        "tfa" => Some("BSD-3-Clause"),
+        "optee" => Some("BSD-2-Clause"),
+        _ => None,
+    }
+}
+
+/// Retrieve the license expression declared by a source file, in its first
+/// lines
+fn file_license(path: &PathBuf) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]).to_string();
+    head.lines()
+        .take(5)
+        .find_map(|line| line.find(SPDX_TAG).map(|index| line[index + SPDX_TAG.len()..].to_string()))
+        .map(|tag| tag.trim().trim_end_matches("*/").trim().to_string())
+        .filter(|tag| ! tag.is_empty())
+}
+
+/// Collect the licenses of the sources that Kbuild compiled in a build
+/// directory. Kbuild records the source of each object in a .<object>.cmd
+/// file, as source_<object> := <source>. Symbolic links (e.g. to the source
+/// tree) are not followed.
+fn collect_licenses(dir: &PathBuf, build_dir: &PathBuf, licenses: &mut BTreeSet<String>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).context(error::DirIterFailed{ dir: dir.clone() })?;
+    for entry in entries {
+        let entry = entry.context(error::DirIterFailed{ dir: dir.clone() })?;
+        let file_type = entry.file_type().context(error::DirIterFailed{ dir: dir.clone() })?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if file_type.is_dir() {
+            collect_licenses(&path, build_dir, licenses)?;
+        } else if file_type.is_file() && name.starts_with('.') && name.ends_with(".o.cmd") {
+            let cmd = String::from_utf8_lossy(&std::fs::read(&path)
+                .context(error::FailedToRead{ path: path.clone() })?).to_string();
+            let source = cmd.lines()
+                .find(|line| line.starts_with("source_"))
+                .and_then(|line| line.split_once(":="))
+                .map(|(_, source)| source.trim().to_string());
+            if let Some(source) = source {
+                // Relative sources are relative to the build directory, in
+                // which Kbuild runs
+                let mut path = build_dir.clone();
+                path.push(source);
+                if let Some(license) = file_license(&path) {
+                    licenses.insert(license);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compose the license expression covering all the files that were
+/// compiled in the build directory of a component, if Kbuild built it
+fn compiled_license(build_dir: &PathBuf) -> Result<Option<String>> {
+    let mut licenses = BTreeSet::new();
+    if build_dir.is_dir() {
+        collect_licenses(build_dir, build_dir, &mut licenses)?;
+    }
+    if licenses.is_empty() {
+        return Ok(None);
+    }
+    let terms: Vec<String> = licenses.iter()
+        .map(|l| if l.contains(' ') { format!("({})", l) } else { l.clone() })
+        .collect();
+    Ok(Some(terms.join(" AND ")))
+}
+
+/// Make a string suitable for an SPDX identifier: only letters, numbers,
+/// dots and dashes are allowed. As other characters are replaced, different
+/// names may give the same identifier: an index is then appended to the
+/// identifiers that are already taken (ids).
+fn spdx_id(ids: &mut BTreeSet<String>, kind: &str, name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '-' })
+        .collect();
+    let base = format!("SPDXRef-{}-{}", kind, name);
+    let mut id = base.clone();
+    let mut index = 1;
+    while ! ids.insert(id.clone()) {
+        index += 1;
+        id = format!("{}-{}", base, index);
+    }
+    id
+}
+
+fn file_name(path: &PathBuf) -> String {
+    path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// Compute the SHA-1 of a file, if it is still around. SPDX requires it
+/// for files.
+fn sha1(path: &PathBuf) -> Result<Option<String>> {
+    if path.is_file() {
+        Ok(Some(util::checksum("sha1sum", path)?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn spdx_checksums(sha1: Option<String>, sha256: &str) -> Value {
+    let mut checksums = Vec::new();
+    if let Some(sha1) = sha1 {
+        checksums.push(json!({ "algorithm": "SHA1", "checksumValue": sha1 }));
+    }
+    checksums.push(json!({ "algorithm": "SHA256", "checksumValue": sha256 }));
+    Value::Array(checksums)
+}
+
+fn relationship(element: &str, kind: &str, related: &str) -> Value {
+    json!({
+        "spdxElementId": element,
+        "relationshipType": kind,
+        "relatedSpdxElement": related,
+    })
+}
+
+/// Location from which the sources of a component can be retrieved, in the
+/// SPDX syntax
+fn download_location(record: &ComponentRecord) -> String {
+    match record.sources.first() {
+        Some(source) => match &source.commit {
+            Some(commit) => format!("git+{}@{}", source.url, commit),
+            None => source.url.clone(),
+        },
+        None => "NOASSERTION".to_string(),
+    }
+}
+
+/// Render the SPDX 2.3 document of the build
+fn spdx(config: &Config, manifest: &Manifest, licenses: &[Licenses],
+        serial: &str, created: &str) -> Result<Value> {
+    let mut packages = Vec::new();
+    let mut files = Vec::new();
+    let mut relationships = Vec::new();
+    let mut ids = BTreeSet::new();
+
+    let toolchain_id = spdx_id(&mut ids, "Package", "toolchain");
+    if let Some(toolchain) = &manifest.toolchain {
+        let mut package = json!({
+            "SPDXID": toolchain_id,
+            "name": toolchain.name,
+            "downloadLocation": toolchain.url,
+            "filesAnalyzed": false,
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": "NOASSERTION",
+            "copyrightText": "NOASSERTION",
+            "primaryPackagePurpose": "INSTALL",
+        });
+        if let Some(sha256) = &toolchain.sha256 {
+            package["checksums"] = json!([{ "algorithm": "SHA256", "checksumValue": sha256 }]);
+        }
+        packages.push(package);
+    }
+
+    for ((name, record), licenses) in manifest.components.iter().zip(licenses) {
+        let id = spdx_id(&mut ids, "Package", name);
+        let concluded = licenses.concluded.clone().unwrap_or("NOASSERTION".to_string());
+        let mut package = json!({
+            "SPDXID": id,
+            "name": name,
+            "versionInfo": record.version.clone().unwrap_or("NOASSERTION".to_string()),
+            "downloadLocation": download_location(record),
+            "filesAnalyzed": false,
+            "licenseConcluded": concluded,
+            "licenseDeclared": licenses.declared.unwrap_or("NOASSERTION"),
+            "copyrightText": "NOASSERTION",
+            "primaryPackagePurpose": "FIRMWARE",
+            "comment": format!("Component of kind '{}'", record.kind),
+        });
+        if licenses.concluded.is_some() {
+            package["licenseComments"] = json!(
+                "Concluded from the SPDX tags of the files that were compiled");
+        }
+        if let Some(sha256) = record.sources.first().and_then(|s| s.sha256.as_ref()) {
+            package["checksums"] = json!([{ "algorithm": "SHA256", "checksumValue": sha256 }]);
+        }
+        if record.sources.len() > 1 {
+            let upgrades: Vec<&str> = record.sources[1..].iter().map(|s| s.url.as_str()).collect();
+            package["sourceInfo"] = json!(format!("Upgraded with {}", upgrades.join(", ")));
+        }
+        packages.push(package);
+        relationships.push(relationship("SPDXRef-DOCUMENT", "DESCRIBES", &id));
+        if manifest.toolchain.is_some() {
+            relationships.push(relationship(&toolchain_id, "BUILD_TOOL_OF", &id));
+        }
+
+        // Library patches are modifications of the sources
+        for (index, patch) in record.patches.iter().enumerate() {
+            let mut path = config.lib_dir.clone();
+            path.push(&patch.path);
+            let file_id = spdx_id(&mut ids, "File", &format!("{}-patch-{}", name, index + 1));
+            files.push(json!({
+                "SPDXID": file_id,
+                "fileName": format!("./{}", patch.path.to_string_lossy()),
+                "fileTypes": ["SOURCE"],
+                "checksums": spdx_checksums(sha1(&path)?, &patch.sha256),
+                "licenseConcluded": "NOASSERTION",
+                "copyrightText": "NOASSERTION",
+            }));
+            relationships.push(relationship(&file_id, "PATCH_APPLIED", &id));
+        }
+
+        for artifact in &record.artifacts {
+            let mut path = config.build_dir.clone();
+            path.push(&artifact.path);
+            let file_id = spdx_id(&mut ids, "File", &format!("{}-{}",
+                name, artifact.name.clone().unwrap_or(file_name(&artifact.path))));
+            files.push(json!({
+                "SPDXID": file_id,
+                "fileName": format!("./{}", artifact.path.to_string_lossy()),
+                "fileTypes": ["BINARY"],
+                "checksums": spdx_checksums(sha1(&path)?, &artifact.sha256),
+                "licenseConcluded": concluded,
+                "copyrightText": "NOASSERTION",
+            }));
+            relationships.push(relationship(&file_id, "GENERATED_FROM", &id));
+        }
+
+        for step in record.steps.values() {
+            for output in &step.outputs {
+                let package_id = spdx_id(&mut ids, "Package",
+                    &format!("{}-{}", name, file_name(&output.path)));
+                packages.push(json!({
+                    "SPDXID": package_id,
+                    "name": file_name(&output.path),
+                    "packageFileName": output.path.to_string_lossy(),
+                    "versionInfo": record.version.clone().unwrap_or("NOASSERTION".to_string()),
+                    "downloadLocation": "NOASSERTION",
+                    "filesAnalyzed": false,
+                    "checksums": [{ "algorithm": "SHA256", "checksumValue": output.sha256 }],
+                    "licenseConcluded": concluded,
+                    "licenseDeclared": "NOASSERTION",
+                    "copyrightText": "NOASSERTION",
+                    "primaryPackagePurpose": "INSTALL",
+                }));
+                relationships.push(relationship(&package_id, "GENERATED_FROM", &id));
+            }
+        }
+    }
+
+    Ok(json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": format!("{}-{}", config.target, serial),
+        "documentNamespace": format!("http://spdx.org/spdxdocs/mktcb-{}-{}", config.target, serial),
+        "creationInfo": {
+            "created": created,
+            "creators": [format!("Tool: mktcb-{}", manifest.mktcb_version)],
+        },
+        "packages": packages,
+        "files": files,
+        "relationships": relationships,
+    }))
+}
+
+/// Render the CycloneDX 1.5 document of the build
+fn cyclonedx(config: &Config, manifest: &Manifest, licenses: &[Licenses],
+             serial: &str, created: &str) -> Result<Value> {
+    let mut components = Vec::new();
+    let mut dependencies = Vec::new();
+    let target_ref = format!("target:{}", config.target);
+
+    if let Some(toolchain) = &manifest.toolchain {
+        let mut component = json!({
+            "type": "application",
+            "bom-ref": "toolchain",
+            "name": toolchain.name,
+            "scope": "excluded",
+            "description": "Toolchain the components were built with",
+            "externalReferences": [{ "type": "distribution", "url": toolchain.url }],
+        });
+        if let Some(sha256) = &toolchain.sha256 {
+            component["hashes"] = json!([{ "alg": "SHA-256", "content": sha256 }]);
+        }
+        components.push(component);
+    }
+
+    for ((name, record), licenses) in manifest.components.iter().zip(licenses) {
+        let mut component = json!({
+            "type": if record.kind == "linux" { "operating-system" } else { "firmware" },
+            "bom-ref": name,
+            "name": name,
+            "description": format!("Component of kind '{}'", record.kind),
+        });
+        if let Some(version) = &record.version {
+            component["version"] = json!(version);
+        }
+        let license = licenses.concluded.clone().or(licenses.declared.map(|l| l.to_string()));
+        if let Some(license) = license {
+            component["licenses"] = json!([{ "expression": license }]);
+        }
+        if let Some(sha256) = record.sources.first().and_then(|s| s.sha256.as_ref()) {
+            component["hashes"] = json!([{ "alg": "SHA-256", "content": sha256 }]);
+        }
+        let references: Vec<Value> = record.sources.iter()
+            .map(|s| json!({
+                "type": if s.commit.is_some() { "vcs" } else { "distribution" },
+                "url": s.url,
+            }))
+            .collect();
+        component["externalReferences"] = Value::Array(references);
+
+        // Library patches are recorded in the pedigree, with their contents
+        let mut patches = Vec::new();
+        for patch in &record.patches {
+            let mut path = config.lib_dir.clone();
+            path.push(&patch.path);
+            let mut entry = json!({ "type": "unofficial" });
+            match std::fs::read(&path) {
+                Ok(contents) => entry["diff"] = json!({ "text": {
+                    "contentType": "text/x-diff",
+                    "content": String::from_utf8_lossy(&contents),
+                }}),
+                Err(_) => warn!("Patch {:#?} is gone. Its contents are not part of the SBOM.", path),
+            }
+            patches.push(entry);
+        }
+        let commits: Vec<Value> = record.sources.iter()
+            .filter_map(|s| s.commit.as_ref().map(|c| json!({ "uid": c, "url": s.url })))
+            .collect();
+        if ! patches.is_empty() || ! commits.is_empty() {
+            component["pedigree"] = json!({ "patches": patches, "commits": commits });
+        }
+
+        let artifacts: Vec<Value> = record.artifacts.iter()
+            .map(|artifact| json!({
+                "type": "file",
+                "bom-ref": format!("{}:{}", name, artifact.path.to_string_lossy()),
+                "name": artifact.path.to_string_lossy(),
+                "hashes": [{ "alg": "SHA-256", "content": artifact.sha256 }],
+            }))
+            .collect();
+        if ! artifacts.is_empty() {
+            component["components"] = Value::Array(artifacts);
+        }
+        components.push(component);
+
+        let mut depends_on = Vec::new();
+        if manifest.toolchain.is_some() {
+            depends_on.push("toolchain".to_string());
+        }
+        dependencies.push(json!({ "ref": name, "dependsOn": depends_on }));
+
+        for step in record.steps.values() {
+            for output in &step.outputs {
+                let package_ref = format!("{}:{}", name, output.path.to_string_lossy());
+                let mut package = json!({
+                    "type": "file",
+                    "bom-ref": package_ref,
+                    "name": file_name(&output.path),
+                    "hashes": [{ "alg": "SHA-256", "content": output.sha256 }],
+                });
+                if let Some(version) = &record.version {
+                    package["version"] = json!(version);
+                }
+                components.push(package);
+                dependencies.push(json!({ "ref": package_ref, "dependsOn": [name] }));
+            }
+        }
+    }
+    let names: Vec<&String> = manifest.components.keys().collect();
+    dependencies.push(json!({ "ref": target_ref, "dependsOn": names }));
+
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", serial),
+        "version": 1,
+        "metadata": {
+            "timestamp": created,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": "mktcb",
+                    "version": manifest.mktcb_version,
+                }],
+            },
+            "component": {
+                "type": "firmware",
+                "bom-ref": target_ref,
+                "name": config.target,
+            },
+        },
+        "components": components,
+        "dependencies": dependencies,
+    }))
+}
+
+/// Derive a UUID from the manifest, so that the same build always gives
+/// the same documents
+fn serial_number(manifest: &PathBuf) -> Result<String> {
+    let hash = util::checksum("sha256sum", manifest)?;
+    let hash = hash.as_bytes();
+    let variant = ['8', '9', 'a', 'b'][(hash[16] as usize) % 4];
+    Ok(format!("{}-{}-4{}-{}{}-{}",
+        String::from_utf8_lossy(&hash[0..8]),
+        String::from_utf8_lossy(&hash[8..12]),
+        String::from_utf8_lossy(&hash[13..16]),
+        variant,
+        String::from_utf8_lossy(&hash[17..20]),
+        String::from_utf8_lossy(&hash[20..32])))
+}
+
+/// Write the SBOMs of the build, as described by its manifest, in the
+/// requested formats. Their paths are returned.
+pub fn run(config: &Config, interrupt: Interrupt, formats: &[Format]) -> Result<Vec<PathBuf>> {
+    let manifest_path = manifest::path(config);
+    let manifest = manifest::load(config)?.context(error::NoManifest{
+        path: manifest_path.clone() })?;
+    for name in config.components.keys() {
+        if ! manifest.components.contains_key(name) {
+            warn!("{} was not built. It is not part of the SBOM.", name);
+        }
+    }
+
+    let mut licenses = Vec::new();
+    for (name, record) in &manifest.components {
+        let build_dir = match config.components.get(name) {
+            Some(_) => component::new(name, config, interrupt.clone())?.build_dir().clone(),
+            None => PathBuf::new(),
+        };
+        debug!("Collecting the licenses of the files compiled in {:#?}", build_dir);
+        licenses.push(Licenses {
+            declared: declared_license(&record.kind),
+            concluded: compiled_license(&build_dir)?,
+        });
+    }
+
+    let serial = serial_number(&manifest_path)?;
+    let created = util::iso8601(std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(util::now));
+
+    let mut dir = config.build_dir.clone();
+    dir.push("sbom");
+    std::fs::create_dir_all(&dir).context(error::CreateDirError{ path: dir.clone() })?;
+    let mut paths = Vec::new();
+    for format in formats {
+        let (document, extension) = match format {
+            Format::Spdx => (spdx(config, &manifest, &licenses, &serial, &created)?, "spdx.json"),
+            Format::CycloneDx => (cyclonedx(config, &manifest, &licenses, &serial, &created)?, "cdx.json"),
+        };
+        let mut path = dir.clone();
+        path.push(format!("{}.{}", config.target, extension));
+        let data = serde_json::to_string_pretty(&document).context(
+            error::FailedToSerJson{ path: path.clone() })?;
+        std::fs::write(&path, format!("{}\n", data)).context(
+            error::FailedToWrite{ path: path.clone() })?;
+        info!("SBOM written to {:#?}", path);
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_spdx_ids() {
+        let mut ids = BTreeSet::new();
+        assert_eq!(spdx_id(&mut ids, "Package", "u-boot_spl"), "SPDXRef-Package-u-boot-spl");
+        assert_eq!(spdx_id(&mut ids, "Package", "u-boot-spl"), "SPDXRef-Package-u-boot-spl-2");
+        assert_eq!(spdx_id(&mut ids, "Package", "u-boot spl"), "SPDXRef-Package-u-boot-spl-3");
+        assert_eq!(spdx_id(&mut ids, "File", "u-boot-spl"), "SPDXRef-File-u-boot-spl");
+    }
+}
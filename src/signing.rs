@@ -0,0 +1,243 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::config::Config;
+use crate::util;
+
+/// File name of the key the kernel generates when none is provided. It is
+/// a throwaway key, as each build directory gets its own one.
+const THROWAWAY_KEY: &str = "signing_key.pem";
+
+/// OpenSSL configuration used to generate module signing keys. It is the
+/// one of the kernel (certs/x509.genkey), but for the subject.
+const GENKEY: &str = "[ req ]
+default_bits = 4096
+distinguished_name = req_distinguished_name
+prompt = no
+string_mask = utf8only
+x509_extensions = myexts
+
+[ req_distinguished_name ]
+CN = {name} module signing key
+
+[ myexts ]
+basicConstraints=critical,CA:FALSE
+keyUsage=digitalSignature
+subjectKeyIdentifier=hash
+authorityKeyIdentifier=keyid
+";
+
+/// Key signing the kernel modules of a target. The private key and its
+/// certificate are held in a single PEM file, as CONFIG_MODULE_SIG_KEY
+/// expects it. It is stored in the keys directory, so it outlives the build
+/// directory and never ends up in an artifact.
+pub struct SigningKey {
+    pub path: PathBuf,
+    target_name: String,
+}
+
+/// Signs out-of-tree modules with sign-file, as built by the kernel
+pub struct ModuleSigner {
+    sign_file: PathBuf,
+    hash: String,
+    key: PathBuf,
+    cert: PathBuf,
+}
+
+/// Tell whether PEM data hold both a private key and a certificate
+fn is_key_pair(pem: &str) -> bool {
+    pem.contains("PRIVATE KEY-----") && pem.contains("-----BEGIN CERTIFICATE-----")
+}
+
+impl SigningKey {
+    pub fn exists(&self) -> bool {
+        self.path.is_file()
+    }
+
+    /// Create an empty key file, that only its owner may access, before any
+    /// secret is written in it
+    fn create(&self) -> Result<()> {
+        let dir = self.path.parent().unwrap().to_path_buf();
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .context(error::CreateDirError{ path: dir.clone() })?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)
+            .context(error::CreateFileError{ path: self.path.clone() })?;
+        // The mode only applies to files that did not exist yet
+        std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o600))
+            .context(error::FailedToWrite{ path: self.path.clone() })
+    }
+
+    /// Generate a new key, valid for 100 years, as the kernel does
+    pub fn generate(&self) -> Result<()> {
+        self.create()?;
+        let mut genkey = self.path.clone();
+        genkey.set_extension("genkey");
+        std::fs::write(&genkey, GENKEY.replace("{name}", &self.target_name))
+            .context(error::FailedToWrite{ path: genkey.clone() })?;
+
+        info!("Generating module signing key {:#?}", self.path);
+        let status = Command::new("openssl")
+            .arg("req").arg("-new").arg("-nodes").arg("-utf8")
+            .arg("-sha512").arg("-days").arg("36500")
+            .arg("-batch").arg("-x509")
+            .arg("-config").arg(&genkey)
+            .arg("-outform").arg("PEM")
+            .arg("-out").arg(&self.path)
+            .arg("-keyout").arg(&self.path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context(error::ProgFailed{ proc: "openssl".to_string() })?;
+        std::fs::remove_file(&genkey).context(error::RemoveFileError{ path: genkey.clone() })?;
+        ensure!(status.success() && is_key_pair(&util::read_file(&self.path)?),
+            error::KeyGenFailed{ path: self.path.clone() });
+        Ok(())
+    }
+
+    /// Import an existing key. The file must hold both the private key and
+    /// its certificate.
+    pub fn import(&self, from: &PathBuf) -> Result<()> {
+        let pem = util::read_file(from)?;
+        ensure!(is_key_pair(&pem), error::InvalidSigningKey{ path: from.clone() });
+        self.create()?;
+        info!("Importing module signing key {:#?} as {:#?}", from, self.path);
+        std::fs::write(&self.path, format!("{}\n", pem))
+            .context(error::FailedToWrite{ path: self.path.clone() })
+    }
+
+    /// Make the kernel of a build directory sign its modules with this key,
+    /// rather than with a throwaway one. Nothing is done if the kernel does
+    /// not sign its modules. The key is generated if needed.
+    pub fn setup(&self, build_dir: &PathBuf) -> Result<()> {
+        let mut config = build_dir.clone();
+        config.push(".config");
+        if ! config.is_file() ||
+            util::config_value(build_dir, "CONFIG_MODULE_SIG")?.as_deref() != Some("y") {
+            return Ok(());
+        }
+        if ! self.exists() {
+            self.generate()?;
+        }
+        if util::set_config_value(build_dir, "CONFIG_MODULE_SIG_KEY", self.path.to_str().unwrap())? {
+            info!("Modules will be signed with {:#?}", self.path);
+        }
+
+        let mut throwaway = build_dir.clone();
+        throwaway.push("certs");
+        throwaway.push(THROWAWAY_KEY);
+        if throwaway.exists() {
+            warn!("Removing {:#?}, generated by a previous build", throwaway);
+            std::fs::remove_file(&throwaway).context(
+                error::RemoveFileError{ path: throwaway.clone() })?;
+        }
+        Ok(())
+    }
+
+    /// Retrieve a signer of out-of-tree modules, for a kernel that was built
+    /// with this key
+    pub fn signer(&self, build_dir: &PathBuf) -> Result<ModuleSigner> {
+        ensure!(self.exists(), error::FileDoesNotExist{ path: self.path.clone() });
+        let mut sign_file = build_dir.clone();
+        sign_file.push("scripts");
+        sign_file.push("sign-file");
+        ensure!(sign_file.is_file(), error::FileDoesNotExist{ path: sign_file.clone() });
+        let mut cert = build_dir.clone();
+        cert.push("certs");
+        cert.push("signing_key.x509");
+        ensure!(cert.is_file(), error::FileDoesNotExist{ path: cert.clone() });
+
+        Ok(ModuleSigner {
+            sign_file: sign_file,
+            hash: util::config_value(build_dir, "CONFIG_MODULE_SIG_HASH")?
+                .unwrap_or("sha512".to_string()),
+            key: self.path.clone(),
+            cert: cert,
+        })
+    }
+
+    /// Make sure that no package contains a private key: neither this one,
+    /// nor a throwaway one
+    pub fn check_packages(&self, packages: &[PathBuf]) -> Result<()> {
+        let name = self.path.file_name().unwrap().to_str().unwrap();
+        for package in packages {
+            let (prog, list) = match package.extension().and_then(|e| e.to_str()) {
+                Some("rpm") => ("rpm", "-qlp"),
+                _ => ("dpkg-deb", "-c"),
+            };
+            let out = Command::new(prog)
+                .arg(list)
+                .arg(package)
+                .stdin(Stdio::null())
+                .stderr(Stdio::inherit())
+                .output()
+                .context(error::ProgFailed{ proc: prog.to_string() })?;
+            ensure!(out.status.success(), error::ListPackageFailed{ package: package.clone() });
+            let listing = String::from_utf8_lossy(&out.stdout);
+            for file in &[name, THROWAWAY_KEY] {
+                ensure!(! listing.lines().any(|line| line.ends_with(&format!("/{}", file))),
+                    error::PrivateKeyInPackage{ package: package.clone(), file: file.to_string() });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ModuleSigner {
+    /// Sign a kernel module in place
+    pub fn sign(&self, module: &PathBuf) -> Result<()> {
+        let status = Command::new(&self.sign_file)
+            .arg(&self.hash)
+            .arg(&self.key)
+            .arg(&self.cert)
+            .arg(module)
+            .stdin(Stdio::null())
+            .status()
+            .context(error::ProgFailed{ proc: "sign-file".to_string() })?;
+        ensure!(status.success(), error::SignFileFailed{ module: module.clone() });
+        Ok(())
+    }
+}
+
+/// Retrieve the module signing key of the target
+pub fn new(config: &Config) -> SigningKey {
+    let mut path = config.keys_dir.clone();
+    path.push(&config.target);
+    path.push("module-signing.pem");
+    SigningKey {
+        path: path,
+        target_name: config.target_name.clone(),
+    }
+}
+
+/// Generate the module signing key of the target, or import an existing
+/// one. An existing key is only replaced when forced to. The path to the
+/// key is returned.
+pub fn run(config: &Config, import: Option<PathBuf>, force: bool) -> Result<PathBuf> {
+    let key = new(config);
+    ensure!(force || ! key.exists(), error::SigningKeyExists{ path: key.path.clone() });
+    match import {
+        Some(from) => key.import(&from)?,
+        None => key.generate()?,
+    }
+    Ok(key.path)
+}
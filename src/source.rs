@@ -0,0 +1,252 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use snafu::{ResultExt, OptionExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::component::Provenance;
+use crate::download;
+use crate::patch;
+use crate::reproducible;
+use crate::util;
+use crate::interrupt::Interrupt;
+use crate::version_file;
+
+/// Sources of a component that are retrieved as an archive, at the version
+/// pinned by the target, and on which the library patches are applied.
+pub struct Source {
+    /// Pretty name of the component, for messages
+    pub pretty: String,
+    pub version: String,
+    pub url: url::Url,
+    /// Name under which the archive is stored in the download directory.
+    /// Once unpacked, it must give source_dir.
+    pub archive: PathBuf,
+    /// When set, url is a git repository of which this revision (tag,
+    /// branch or commit) is checked out, instead of an archive.
+    pub git_rev: Option<String>,
+    pub download_dir: PathBuf,
+    pub source_dir: PathBuf,
+    pub version_file: PathBuf,
+    pub patches_dir: PathBuf,
+    pub interrupt: Interrupt,
+}
+
+impl Source {
+    /// Retrieve a single revision of a git repository in source_dir.
+    /// The history is not needed to build, so it is not fetched.
+    fn git_clone(&self, rev: &str) -> Result<()> {
+        std::fs::create_dir_all(&self.source_dir).context(
+            error::CreateDirError{ path: self.source_dir.clone() })?;
+        info!("Cloning revision {} of {}", rev, self.url);
+        let commands: [&[&str]; 3] = [
+            &["init", "-q"],
+            &["fetch", "-q", "--depth", "1", self.url.as_str(), rev],
+            &["checkout", "-q", "FETCH_HEAD"],
+        ];
+        for args in &commands {
+            let status = Command::new("git")
+                .stdin(Stdio::null())
+                .arg("-C").arg(&self.source_dir)
+                .args(args.iter())
+                .status()
+                .context(error::ProgFailed{ proc: "git".to_string() })?;
+            ensure!(status.success(), error::GitFailed{
+                url: self.url.to_string(),
+                rev: rev.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Retrieve the date of the commit that was checked out
+    fn git_date(&self) -> Result<u64> {
+        let out = Command::new("git")
+            .stdin(Stdio::null())
+            .arg("-C").arg(&self.source_dir)
+            .arg("log").arg("-1").arg("--format=%ct")
+            .output()
+            .context(error::ProgFailed{ proc: "git".to_string() })?;
+        let date = String::from_utf8_lossy(&out.stdout).trim().parse().ok();
+        date.filter(|_| out.status.success()).context(error::GitFailed{
+            url: self.url.to_string(),
+            rev: self.git_rev.clone().unwrap_or_default(),
+        })
+    }
+
+    fn download(&self) -> Result<()> {
+        // The sources are dated at their commit, or at their release for
+        // archives, before they are patched
+        let epoch = if let Some(rev) = &self.git_rev {
+            // Do not leave a partial clone behind, or the download directory
+            // would be considered corrupted.
+            match self.git_clone(rev).and_then(|_| self.git_date()) {
+                Ok(date) => date,
+                Err(err) => {
+                    let _ = std::fs::remove_dir_all(&self.source_dir);
+                    return Err(err);
+                },
+            }
+        } else {
+            let mut http_handle = curl::easy::Easy::new();
+            download::to_unpacked_dir_as(&mut http_handle, &self.url,
+                &self.download_dir, &self.archive, &self.source_dir)?;
+            reproducible::tree_epoch(&self.source_dir)?
+        };
+
+        // Apply patches on the working directory and then write the version.
+        // A sigint may not interrupt this...
+        version_file::patch_and_record(&self.version_file, &self.version, epoch, &self.interrupt,
+            &self.patches_dir, &self.patches_dir, &self.source_dir)
+    }
+
+    /// List the library patches that were added since the sources were
+    /// retrieved. Patches that were already applied cannot be reverted, so
+    /// if they changed, the sources must be fetched again.
+    pub fn new_patches(&self) -> Result<Vec<PathBuf>> {
+        if ! self.is_fetched() {
+            return Ok(Vec::new());
+        }
+        let recorded = version_file::load(&self.version_file)?;
+        let library = patch::list_patches_in(&self.patches_dir)?;
+        let applied = match recorded.applied(&self.patches_dir) {
+            Some(applied) => applied,
+            // Sources of older versions of mktcb are assumed up-to-date
+            None => return Ok(Vec::new()),
+        };
+
+        let mut unchanged = true;
+        for patch in &applied {
+            if let Some(sha256) = &patch.sha256 {
+                unchanged &= patch.path.is_file() &&
+                    &util::checksum("sha256sum", &patch.path)? == sha256;
+            }
+        }
+        let paths: Vec<PathBuf> = applied.iter().map(|p| p.path.clone()).collect();
+        ensure!(unchanged && library.starts_with(&paths), error::PatchesMismatch{
+            version_file: self.version_file.clone(),
+            dir: self.source_dir.clone(),
+        });
+        Ok(library[applied.len()..].to_vec())
+    }
+
+    /// The sources were already retrieved. Apply the library patches that
+    /// were added since then.
+    fn update_patches(&self) -> Result<()> {
+        let mut recorded = version_file::load(&self.version_file)?;
+        if recorded.applied(&self.patches_dir).is_none() {
+            let library = patch::list_patches_in(&self.patches_dir)?;
+            recorded.assume(&self.version_file, &library, &self.patches_dir)?;
+            return version_file::write(&self.version_file, &recorded);
+        }
+
+        let new_patches = self.new_patches()?;
+        if new_patches.is_empty() {
+            info!("{} {} is up-to-date with the library patches", self.pretty, self.version);
+            return Ok(());
+        }
+
+        let _guard = self.interrupt.lock();
+        for entry in &new_patches {
+            info!("Applying new patch {:#?}", entry);
+            recorded.apply(entry, &self.patches_dir, &self.source_dir)?;
+        }
+        version_file::write(&self.version_file, &recorded)
+    }
+
+    /// Retrieve the sources if they were not already, or apply the library
+    /// patches that were added since they were.
+    /// Returns whether the sources were downloaded.
+    pub fn fetch(&self) -> Result<bool> {
+        if ! self.version_file.exists() {
+            version_file::ensure_not_corrupted(&self.version_file, &self.source_dir)?;
+            self.download()?;
+            Ok(true)
+        } else {
+            self.update_patches()?;
+            Ok(false)
+        }
+    }
+
+    pub fn is_fetched(&self) -> bool {
+        self.version_file.exists()
+    }
+
+    /// Make sure the sources were retrieved before operating on them
+    pub fn ensure_fetched(&self) -> Result<()> {
+        ensure!(self.is_fetched(), error::NotFetched{
+            component: self.pretty.clone() });
+        Ok(())
+    }
+
+    /// Retrieve the version of the sources that were fetched, if any
+    pub fn fetched_version(&self) -> Result<Option<String>> {
+        if self.is_fetched() {
+            Ok(Some(version_file::load(&self.version_file)?.version))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Retrieve the commit that was checked out, for git sources
+    fn git_commit(&self) -> Result<Option<String>> {
+        if self.git_rev.is_none() || ! self.source_dir.is_dir() {
+            return Ok(None);
+        }
+        let out = Command::new("git")
+            .stdin(Stdio::null())
+            .arg("-C").arg(&self.source_dir)
+            .arg("rev-parse").arg("HEAD")
+            .output()
+            .context(error::ProgFailed{ proc: "git".to_string() })?;
+        ensure!(out.status.success(), error::GitFailed{
+            url: self.url.to_string(),
+            rev: self.git_rev.clone().unwrap(),
+        });
+        Ok(Some(String::from_utf8_lossy(&out.stdout).trim().to_string()))
+    }
+
+    /// Retrieve where the sources come from, and the patches that were
+    /// applied on them, as recorded in the version file. The library is
+    /// not looked at, as it may have changed since.
+    pub fn provenance(&self) -> Result<Provenance> {
+        self.ensure_fetched()?;
+        let archive = match self.git_rev {
+            Some(_) => None,
+            None => {
+                let mut path = self.download_dir.clone();
+                path.push(&self.archive);
+                Some(path)
+            },
+        };
+        let patches = version_file::load(&self.version_file)?.applied(&self.patches_dir);
+        let commit = self.git_commit()?;
+        Ok(Provenance {
+            downloads: vec![(self.url.clone(), archive)],
+            git_dir: commit.as_ref().map(|_| self.source_dir.clone()),
+            commit: commit,
+            local: None,
+            patches: patches,
+        })
+    }
+}
+
+/// Retrieve the extension of an archive (e.g. .tar.gz) from its URL, so it
+/// can be stored under another name, and still be unpacked
+pub fn archive_extension(url: &url::Url) -> Result<String> {
+    let file = util::url_last(url)?;
+    let file = file.to_str().unwrap();
+    let index = file.rfind(".tar").context(error::UnknownArchiveFormat{
+        url: url.clone() })?;
+    Ok(file[index..].to_string())
+}
+
+/// Parse the URL of the sources of a component
+pub fn parse_url(name: &str, url: &str) -> Result<url::Url> {
+    url::Url::parse(url).context(error::InvalidComponentURL{
+        component: name.to_string() })
+}
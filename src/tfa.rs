@@ -0,0 +1,181 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use snafu::ResultExt;
+
+use crate::error::Result;
+use crate::error;
+use crate::component;
+use crate::component::{Component, Artifact, Input, MakeInvocation, Provenance};
+use crate::config::{Config, TfaConfig};
+use crate::source::Source;
+use crate::toolchain::Toolchain;
+use crate::interrupt::Interrupt;
+
+pub struct Tfa {
+    name: String,
+    source: Source,
+    build_dir: PathBuf,
+    platform: String,
+    debug: bool,
+    jobs: usize,
+    /// Components whose artifacts are embedded in TF-A
+    dependencies: Vec<Input>,
+    /// Images produced by other components, passed as make variables
+    inputs: Vec<(String, PathBuf)>,
+}
+
+impl Tfa {
+    /// Retrieve the path to the BL31 image, as produced by the build
+    pub fn bl31(&self) -> PathBuf {
+        let mut path = self.build_dir.clone();
+        path.push(&self.platform);
+        path.push(if self.debug { "debug" } else { "release" });
+        path.push("bl31.bin");
+        path
+    }
+}
+
+impl Component for Tfa {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        self.source.fetch()?;
+        Ok(())
+    }
+
+    fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>> {
+        toolchain.fetch()?;
+        self.source.ensure_fetched()?;
+        let mut make_cmd = Command::new("make");
+        make_cmd
+            .arg(format!("BUILD_BASE={}", self.build_dir.to_str().unwrap()))
+            .arg(format!("PLAT={}", self.platform))
+            .arg(format!("DEBUG={}", if self.debug { 1 } else { 0 }))
+            .arg(format!("CROSS_COMPILE={}", toolchain.cross_compile))
+            .arg("-C").arg(self.source.source_dir.clone())
+            .arg(format!("-j{}", self.jobs));
+        component::add_inputs(&mut make_cmd, &self.inputs)?;
+        // A BL32 requires its dispatcher. The only one we support is OP-TEE's.
+        if self.inputs.iter().any(|(var, _)| var == "BL32") {
+            make_cmd.arg("SPD=opteed");
+        }
+        Ok(vec![component::run_make(&mut make_cmd, make_target)?])
+    }
+
+    fn fetched_version(&mut self) -> Result<Option<String>> {
+        self.source.fetched_version()
+    }
+
+    fn new_patches(&self) -> Result<Vec<PathBuf>> {
+        self.source.new_patches()
+    }
+
+    fn build_dir(&self) -> &PathBuf {
+        &self.build_dir
+    }
+
+    fn patches_dir(&self) -> &PathBuf {
+        &self.source.patches_dir
+    }
+
+    fn provenance(&self) -> Result<Provenance> {
+        self.source.provenance()
+    }
+
+    fn default_target(&self) -> &str {
+        "bl31"
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        vec![self.source.version_file.clone()]
+    }
+
+    fn version_file(&self) -> Option<&PathBuf> {
+        Some(&self.source.version_file)
+    }
+
+    fn artifacts(&self) -> Vec<Artifact> {
+        vec![Artifact { name: "bl31".to_string(), path: self.bl31() }]
+    }
+
+    fn inputs(&self) -> Vec<Input> {
+        self.dependencies.clone()
+    }
+
+    /// Provide an image built by another component to TF-A (e.g. BL32 for
+    /// OP-TEE), as the make variable var.
+    fn add_input(&mut self, var: &str, path: PathBuf) {
+        self.inputs.push((var.to_string(), path));
+    }
+}
+
+/// Compose a path involving a given TF-A version
+fn make_version_dir(base_dir: &PathBuf, version: &str) -> PathBuf {
+    let mut path = base_dir.clone();
+    path.push(format!("trusted-firmware-a-{}", version));
+    path
+}
+
+fn make_patches_dir(base_dir: &PathBuf, name: &str, version: &str) -> PathBuf {
+    let mut path = base_dir.clone();
+    path.push("patches");
+    path.push(name);
+    path.push(version);
+    path
+}
+
+pub fn new(config: &Config, name: &str, interrupt: Interrupt) -> Result<Tfa> {
+    let tfa: TfaConfig = config.component(name)?;
+    let version = tfa.version.clone();
+    let url = format!("https://git.trustedfirmware.org/TF-A/trusted-firmware-a.git/snapshot/trusted-firmware-a-{}.tar.gz", version);
+
+    let download_dir = component::get_dir(&config.download_dir, name, "tfa");
+    let build_dir = component::get_dir(&config.build_dir, name, "tfa");
+
+    // Compose the path to the version file
+    let mut v_file = download_dir.clone();
+    v_file.push(format!("trusted-firmware-a-{}.version", version));
+
+    // If the target uses OP-TEE, TF-A embeds it as BL32
+    let mut dependencies = Vec::new();
+    if let Some(optee) = config.first_component("optee") {
+        for (artifact, var) in &[
+            ("tee-header", "BL32"),
+            ("tee-pager", "BL32_EXTRA1"),
+            ("tee-pageable", "BL32_EXTRA2"),
+        ] {
+            dependencies.push(Input {
+                component: optee.clone(),
+                artifact: artifact.to_string(),
+                var: var.to_string(),
+            });
+        }
+    }
+
+    Ok(Tfa {
+        name: name.to_string(),
+        source: Source {
+            pretty: "TF-A".to_string(),
+            url: url::Url::parse(&url).context(error::InvalidTfaURL{})?,
+            archive: PathBuf::from(format!("trusted-firmware-a-{}.tar.gz", version)),
+            git_rev: None,
+            source_dir: make_version_dir(&download_dir, &version),
+            download_dir: download_dir,
+            version_file: v_file,
+            patches_dir: make_patches_dir(&config.lib_dir, name, &version),
+            version: version.clone(),
+            interrupt: interrupt,
+        },
+        build_dir: make_version_dir(&build_dir, &version),
+        platform: tfa.platform,
+        debug: tfa.debug,
+        jobs: config.jobs,
+        dependencies: dependencies,
+        inputs: Vec::new(),
+    })
+}
@@ -3,7 +3,7 @@
 use snafu::ResultExt;
 use crate::error::Result;
 use crate::error;
-use crate::config::Config;
+use crate::config::{Config, ToolchainConfig};
 use crate::download;
 use crate::util;
 
@@ -18,10 +18,31 @@ pub struct Toolchain {
 }
 
 impl Toolchain {
+    pub fn is_fetched(&self) -> bool {
+        self.target_dir.is_dir()
+    }
+
+    pub fn url(&self) -> &url::Url {
+        &self.url
+    }
+
+    /// Name of the toolchain, as given by its archive (e.g.
+    /// gcc-linaro-7.5.0-2019.12-x86_64_arm-linux-gnueabihf)
+    pub fn name(&self) -> String {
+        self.target_dir.file_name().unwrap().to_str().unwrap().to_string()
+    }
+
+    /// Retrieve the path to the archive the toolchain was downloaded as
+    pub fn archive(&self) -> Result<PathBuf> {
+        let mut path = self.download_dir.clone();
+        path.push(util::url_last(&self.url)?);
+        Ok(path)
+    }
+
     pub fn fetch(&self) -> Result<()> {
         // If the directory containing the toolchain does not exist, download
         // and decompress it. Otherwise, skip this part!
-        if ! self.target_dir.is_dir() {
+        if ! self.is_fetched() {
             info!("Downloading toolchain from {:#?}", self.url);
             let mut http_handle = curl::easy::Easy::new();
             download::to_unpacked_dir(
@@ -32,7 +53,12 @@ impl Toolchain {
 }
 
 pub fn new(config: &Config) -> Result<Toolchain> {
-    let url = url::Url::parse(&config.toolchain.url)
+    from_config(config, &config.toolchain)
+}
+
+/// Create a toolchain that is not the main toolchain of the target
+pub fn from_config(config: &Config, toolchain: &ToolchainConfig) -> Result<Toolchain> {
+    let url = url::Url::parse(&toolchain.url)
         .context(error::InvalidToolchainURL{})?;
 
     // Compose the path to the tar archive to be downloaded
@@ -48,7 +74,7 @@ pub fn new(config: &Config) -> Result<Toolchain> {
 
     // Finally, compose the full cross-compile variable.
     let mut cc = untar_dir.clone();
-    cc.push(config.toolchain.cross_compile.clone());
+    cc.push(toolchain.cross_compile.clone());
 
     Ok(Toolchain {
         cross_compile: cc.to_str().unwrap().to_string(),
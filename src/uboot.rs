@@ -1,91 +1,460 @@
 /* This is part of mktcb - which is under the MIT License ********************/
 
-// Traits ---------------------------------------------------------------------
-use std::io::Write;
-// ----------------------------------------------------------------------------
-
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::process::Command;
 
-use snafu::{ResultExt, ensure};
+use snafu::{ResultExt, OptionExt, ensure};
 
 use crate::error::Result;
 use crate::error;
-use crate::config::Config;
+use crate::component;
+use crate::component::{Component, Input, MakeInvocation, Packages, Provenance};
+use crate::config;
+use crate::config::{Config, UbootConfig, ArtifactConfig};
+use crate::debian;
 use crate::download;
-use crate::patch;
+use crate::fit;
+use crate::fit::Fit;
+use crate::packaging;
+use crate::packaging::{Packaging, Package};
+use crate::reproducible;
+use crate::source::Source;
 use crate::util;
 use crate::toolchain::Toolchain;
 use crate::interrupt::Interrupt;
+use log::*;
+
+/// Where U-Boot reads its environment from
+pub enum EnvLocation {
+    /// File of the boot partition
+    File(String),
+    /// Offsets of the boot device (two of them for redundant environments)
+    Raw(Vec<u64>),
+    Unknown,
+}
 
 pub struct Uboot {
-    download_dir: PathBuf,
-    source_dir: PathBuf,
+    name: String,
+    source: Source,
     build_dir: PathBuf,
-    patches_dir: PathBuf,
-    version: String,
-    version_file: PathBuf,
     config: Option<PathBuf>,
-    url: url::Url,
-    interrupt: Interrupt,
     arch: String,
     jobs: usize,
+    pkg_dir: PathBuf,
+    artifacts: Vec<ArtifactConfig>,
+    boot_device: Option<String>,
+    target: String,
+    target_name: String,
+    debian_arch: String,
+    packaging: Packaging,
+    /// FIT image whose signatures U-Boot verifies, if any
+    fit: Option<Fit>,
+    /// Components whose artifacts are embedded in U-Boot
+    dependencies: Vec<Input>,
+    /// Images produced by other components, passed as make variables
+    inputs: Vec<(String, PathBuf)>,
 }
 
-impl Uboot {
-    fn write_version(&self) -> Result<()> {
-        let mut file = std::fs::File::create(&self.version_file).context(
-            error::CreateFileError{path: self.version_file.clone()})?;
-        write!(file, "{}", self.version)
-            .context(error::FailedToWrite{path: self.version_file.clone()})?;
+/// U-Boot release, of the form YYYY.MM[-rcN]
+#[derive(PartialEq, Eq)]
+struct Version {
+    year: u32,
+    month: u32,
+    rc: Option<u32>,
+}
+
+impl Version {
+    /// Release candidates come before the final release
+    fn key(&self) -> (u32, u32, bool, Option<u32>) {
+        (self.year, self.month, self.rc.is_none(), self.rc)
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{:02}", self.year, self.month)?;
+        if let Some(rc) = self.rc {
+            write!(f, "-rc{}", rc)?;
+        }
         Ok(())
     }
+}
 
-    fn download(&self) -> Result<()> {
+impl Uboot {
+    /// Retrieve the U-Boot releases that are available on the server
+    fn list_releases(&self) -> Result<Vec<Version>> {
         let mut http_handle = curl::easy::Easy::new();
-        download::to_unpacked_dir(
-            &mut http_handle, &self.url, &self.download_dir, &self.source_dir)?;
+        let base_url = self.source.url.join("./").context(error::InvalidUbootURL{})?;
+        let listing = download::to_string(&mut http_handle, &base_url)?;
 
-        // Copy the initial configuration, if any
-        util::copy_config(&self.config, &self.build_dir)?;
+        // Whether it is an FTP listing or an HTML index, the archives'
+        // names can be found in there.
+        let mut releases = Vec::new();
+        for (idx, _) in listing.match_indices("u-boot-") {
+            let name = &listing[idx + 7..];
+            if let Some(end) = name.find(".tar.bz2") {
+                if let Some(version) = make_version(&name[..end]) {
+                    if ! releases.contains(&version) {
+                        releases.push(version);
+                    }
+                }
+            }
+        }
+        releases.sort();
+        Ok(releases)
+    }
+
+    /// Generate the maintainer script that writes the artifacts on the boot
+    /// device. Artifacts without an offset are not flashed.
+    fn get_postinst(&self, install_dir: &str) -> Result<Option<String>> {
+        let device = match &self.boot_device {
+            Some(device) => device,
+            None => return Ok(None),
+        };
 
-        // Apply patches on the working directory and then write the version.
-        // A sigint may not interrupt this...
-        self.interrupt.lock();
-        patch::apply_patches_in(&self.patches_dir, &self.source_dir)?;
-        self.write_version()
+        let mut script = format!("#!/bin/sh
+set -e
+
+# The boot device can be overridden when installing the package
+DEVICE=\"${{UBOOT_BOOT_DEVICE:-{}}}\"
+
+if [ \"$1\" = \"configure\" ]; then
+    if [ ! -b \"$DEVICE\" ]; then
+        echo \"$DEVICE is not a block device. U-Boot was NOT flashed.\" >&2
+        exit 0
+    fi
+", device);
+        for artifact in &self.artifacts {
+            if let Some(offset) = artifact.offset {
+                // dd only deals with offsets expressed in blocks
+                ensure!(offset % 512 == 0, error::InvalidOffset{
+                    file: artifact.file.clone(), offset: offset });
+                let file = artifact.file.file_name()
+                    .context(error::IllFormedPath{path: artifact.file.clone()})?;
+                script.push_str(&format!("    dd if={}/{} of=\"$DEVICE\" bs=512 seek={} conv=notrunc,fsync\n",
+                    install_dir, file.to_str().unwrap(), offset / 512));
+            }
+        }
+        script.push_str("fi\n");
+        Ok(Some(script))
     }
 
-    pub fn make(&self, make_target: &str, toolchain: &Toolchain) -> Result<()> {
-        toolchain.fetch()?;
-        let status = Command::new("make")
+    /// Generate the command to call make in U-Boot's sources
+    fn get_make_cmd(&self, toolchain: &Toolchain) -> Result<Command> {
+        let mut make_cmd = Command::new("make");
+        make_cmd
             .arg(format!("O={}", self.build_dir.to_str().unwrap()))
             .arg(format!("ARCH={}", self.arch))
             .arg(format!("CROSS_COMPILE={}", toolchain.cross_compile))
-            .arg("-C").arg(self.source_dir.clone())
-            .arg(format!("-j{}", self.jobs))
-            .arg("--")
-            .arg(make_target)
-            .status()
-            .context(error::ProgFailed{ proc: "make".to_string() })?;
-        ensure!(status.success(), error::MakeFailed{
-            target: make_target.to_string() });
-        Ok(())
+            .arg("-C").arg(self.source.source_dir.clone())
+            .arg(format!("-j{}", self.jobs));
+        component::add_inputs(&mut make_cmd, &self.inputs)?;
+        reproducible::set_env(&mut make_cmd, self.source_date_epoch());
+        Ok(make_cmd)
     }
 
-    pub fn fetch(&self) -> Result<()> {
-        if ! self.version_file.exists() {
-            ensure!(! self.source_dir.exists(), error::CorruptedSourceDir{
-                dir: self.source_dir.clone(),
-                version_file: self.version_file.clone(),
-            });
-            self.download()
+    /// Retrieve the path to mkimage. The one built along with U-Boot is
+    /// preferred to the one of the host.
+    pub fn mkimage(&self) -> PathBuf {
+        let mut path = self.build_dir.clone();
+        path.push("tools");
+        path.push("mkimage");
+        if path.is_file() {
+            path
         } else {
-            Ok(())
+            PathBuf::from("mkimage")
+        }
+    }
+
+    /// Retrieve the value of an option of the U-Boot configuration, without
+    /// its quotes. None is returned if it is not set.
+    pub fn config_value(&self, option: &str) -> Result<Option<String>> {
+        util::config_value(&self.build_dir, option)
+    }
+
+    fn config_number(&self, option: &str) -> Result<Option<u64>> {
+        match self.config_value(option)? {
+            Some(value) => {
+                let number = match value.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => value.parse().ok(),
+                };
+                Ok(Some(number.context(error::InvalidUbootOption{
+                    option: option.to_string(), value: value.clone() })?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Tell where U-Boot stores its environment, according to its
+    /// configuration
+    pub fn env_location(&self) -> Result<EnvLocation> {
+        let enabled = |option: &str| self.config_value(option).map(|v| v.as_deref() == Some("y"));
+        if enabled("CONFIG_ENV_IS_IN_FAT")? {
+            let file = self.config_value("CONFIG_ENV_FAT_FILE")?.unwrap_or("uboot.env".to_string());
+            Ok(EnvLocation::File(file))
+        } else if enabled("CONFIG_ENV_IS_IN_EXT4")? {
+            let file = self.config_value("CONFIG_ENV_EXT4_FILE")?.unwrap_or("/uboot.env".to_string());
+            Ok(EnvLocation::File(file.trim_start_matches('/').to_string()))
+        } else if enabled("CONFIG_ENV_IS_IN_MMC")? {
+            let mut offsets = Vec::new();
+            offsets.extend(self.config_number("CONFIG_ENV_OFFSET")?);
+            if enabled("CONFIG_SYS_REDUNDAND_ENVIRONMENT")? {
+                offsets.extend(self.config_number("CONFIG_ENV_OFFSET_REDUND")?);
+            }
+            Ok(EnvLocation::Raw(offsets))
+        } else {
+            Ok(EnvLocation::Unknown)
+        }
+    }
+
+    /// Create the binary image of the environment (uboot.env), as U-Boot
+    /// reads it: a CRC32 of the data, a flags byte if the environment is
+    /// redundant, then the variables, padded to CONFIG_ENV_SIZE. The
+    /// variables come on top of the default environment of U-Boot, when the
+    /// build produced it (u-boot-initial-env).
+    pub fn env_image(&self, vars: &BTreeMap<String, String>) -> Result<PathBuf> {
+        let size = self.config_number("CONFIG_ENV_SIZE")?
+            .context(error::NoEnvSize{})? as usize;
+        let redundant = self.config_value("CONFIG_SYS_REDUNDAND_ENVIRONMENT")?.as_deref() == Some("y");
+
+        let mut env = BTreeMap::new();
+        let mut default_env = self.build_dir.clone();
+        default_env.push("u-boot-initial-env");
+        if default_env.is_file() {
+            for line in util::read_file(&default_env)?.lines() {
+                if let Some(index) = line.find('=') {
+                    env.insert(line[..index].to_string(), line[index + 1..].to_string());
+                }
+            }
+        } else {
+            warn!("{:#?} not found. The environment only holds the variables of the target.",
+                default_env);
+        }
+        env.extend(vars.clone());
+        let image = env_data(&env, size, redundant)?;
+
+        let mut path = self.build_dir.clone();
+        path.push("uboot.env");
+        std::fs::write(&path, image).context(error::FailedToWrite{ path: path.clone() })?;
+        info!("U-Boot environment written to {:#?}", path);
+        Ok(path)
+    }
+
+    /// Retrieve the artifacts that are written raw on the boot device, with
+    /// their offsets
+    pub fn raw_artifacts(&self) -> Vec<(PathBuf, u64)> {
+        self.artifacts.iter()
+            .filter_map(|artifact| artifact.offset.map(|offset| {
+                let mut path = self.build_dir.clone();
+                path.push(&artifact.file);
+                (path, offset)
+            }))
+            .collect()
+    }
+
+}
+
+impl Component for Uboot {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        // Copy the initial configuration, if any
+        if self.source.fetch()? {
+            util::copy_config(&self.config, &self.build_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether a newer U-Boot release than the one pinned by the target
+    /// is available. All newer releases (including release candidates) are
+    /// reported, but only final releases are considered as updates, unless
+    /// the pinned version is itself a release candidate.
+    /// If the sources were never fetched, they are considered updatable.
+    fn check_update(&mut self) -> Result<bool> {
+        if ! self.source.is_fetched() {
+            return Ok(true);
+        }
+        let current = make_version(&self.source.version)
+            .context(error::InvalidUbootVersion{version: self.source.version.clone()})?;
+
+        let mut update = false;
+        for release in self.list_releases()? {
+            if release > current {
+                info!("U-Boot {} is available (currently using {})", release, current);
+                if release.rc.is_none() || current.rc.is_some() {
+                    update = true;
+                }
+            }
+        }
+        if ! update {
+            info!("Last version: {}", current);
+        }
+        Ok(update)
+    }
+
+    fn make(&mut self, make_target: &str, toolchain: &Toolchain) -> Result<Vec<MakeInvocation>> {
+        toolchain.fetch()?;
+        self.source.ensure_fetched()?;
+        util::refresh_config(&self.config, &self.build_dir)?;
+        let mut make_cmd = self.get_make_cmd(toolchain)?;
+        let mut invocations = vec![component::run_make(&mut make_cmd, make_target)?];
+
+        // With verified boot, the public key that signs the FIT must be in
+        // the control device tree. It is added to the one that was just
+        // built, and U-Boot is linked again with it.
+        let mut dtb = self.build_dir.clone();
+        dtb.push("u-boot.dtb");
+        if let (Some(fit), true) = (&self.fit, dtb.is_file()) {
+            if fit.is_signed() {
+                if self.config_value("CONFIG_FIT_SIGNATURE")?.as_deref() != Some("y") {
+                    warn!("CONFIG_FIT_SIGNATURE is not enabled. U-Boot will not check the FIT signatures.");
+                }
+                let pubkey_dtb = fit.pubkey_dtb(&self.mkimage(), &dtb, self.source_date_epoch())?;
+                let mut make_cmd = self.get_make_cmd(toolchain)?;
+                make_cmd.arg(format!("EXT_DTB={}", pubkey_dtb.to_str().unwrap()));
+                invocations.push(component::run_make(&mut make_cmd, make_target)?);
+            }
+        }
+        Ok(invocations)
+    }
+
+    /// Create a copy of the configuration described by the target (if any)
+    fn reconfigure(&self) -> Result<()> {
+        util::copy_config(&self.config, &self.build_dir)
+    }
+
+    /// Build a Debian package that contains the U-Boot artifacts declared
+    /// by the target. Installing the package flashes them on the boot device.
+    /// Upon success, the path to the created debian package is returned.
+    fn package(&mut self, toolchain: &Toolchain) -> Result<Packages> {
+        let make = self.make("all", toolchain)?;
+
+        let package = format!("u-boot-{}", self.target);
+        let deb_dir = debian::prepare(&self.pkg_dir, &package)?;
+
+        // Copy the artifacts in the package
+        let install_dir = format!("/usr/lib/u-boot/{}", self.target);
+        for artifact in &self.artifacts {
+            let mut from = self.build_dir.clone();
+            from.push(&artifact.file);
+            ensure!(from.is_file(), error::FileDoesNotExist{path: from.clone()});
+            let file = artifact.file.file_name()
+                .context(error::IllFormedPath{path: artifact.file.clone()})?;
+
+            let mut to = deb_dir.clone();
+            to.push(&install_dir[1..]);
+            std::fs::create_dir_all(&to).context(
+                error::CreateDirError{ path: to.clone() })?;
+            to.push(file);
+            std::fs::copy(&from, &to).context(error::CopyFailed{
+                from: from.clone(),
+                to: to.clone(),
+            })?;
         }
+
+        if let Some(postinst) = self.get_postinst(&install_dir)? {
+            debian::write_file(&deb_dir, "DEBIAN/postinst", &postinst, true)?;
+        }
+
+        // Debian versions cannot hold dashes (e.g. 2020.07-rc1), because they
+        // separate the revision. Release candidates must also sort before
+        // the final release.
+        let build = self.packaging.next_build(&self.name, &self.source.version, "deb")?;
+        let mut meta = self.packaging.metadata(&Package {
+            name: &package,
+            version: &self.source.version.replace('-', "~"),
+            summary: format!("U-Boot bootloader, version {} for {}",
+                self.source.version, self.target_name),
+            section: "custom/bootloader",
+            priority: "required",
+        }, build)?;
+        meta.description.push(format!("This package contains the U-Boot binaries for the {}.",
+            self.target_name));
+        meta.description.push("Upon installation, they are written on the boot device.".to_string());
+        let control = debian::control(&meta, &self.debian_arch);
+        debian::write_file(&deb_dir, "DEBIAN/control", &control, false)?;
+
+        Ok(Packages {
+            files: vec![debian::build(&self.pkg_dir, &package, self.source_date_epoch())?],
+            make: make,
+        })
+    }
+
+    fn fetched_version(&mut self) -> Result<Option<String>> {
+        self.source.fetched_version()
+    }
+
+    fn new_patches(&self) -> Result<Vec<PathBuf>> {
+        self.source.new_patches()
+    }
+
+    fn build_dir(&self) -> &PathBuf {
+        &self.build_dir
+    }
+
+    fn patches_dir(&self) -> &PathBuf {
+        &self.source.patches_dir
+    }
+
+    fn provenance(&self) -> Result<Provenance> {
+        self.source.provenance()
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.source.version_file.clone()];
+        files.extend(self.config.clone());
+        files
+    }
+
+    fn version_file(&self) -> Option<&PathBuf> {
+        Some(&self.source.version_file)
+    }
+
+    fn inputs(&self) -> Vec<Input> {
+        self.dependencies.clone()
+    }
+
+    /// Provide an image built by another component to U-Boot (e.g. BL31 for
+    /// TF-A, TEE for OP-TEE), as the make variable var.
+    fn add_input(&mut self, var: &str, path: PathBuf) {
+        self.inputs.push((var.to_string(), path));
     }
 }
 
+/// Parse a U-Boot version (YYYY.MM[-rcN]). None is returned if the input
+/// does not look like a U-Boot release.
+fn make_version(str_version: &str) -> Option<Version> {
+    let (base, rc) = match str_version.find("-rc") {
+        Some(idx) => (&str_version[..idx], Some(str_version[idx + 3..].parse().ok()?)),
+        None => (str_version, None),
+    };
+    let vec: Vec<&str> = base.split('.').collect();
+    if vec.len() != 2 || vec[0].len() != 4 || vec[1].len() != 2 {
+        return None;
+    }
+    Some(Version {
+        year: vec[0].parse().ok()?,
+        month: vec[1].parse().ok()?,
+        rc: rc,
+    })
+}
+
 /// Compose a path involving a given U-Boot version
 fn make_version_dir(base_dir: &PathBuf, version: &str) -> PathBuf {
     let mut path = base_dir.clone();
@@ -93,33 +462,121 @@ fn make_version_dir(base_dir: &PathBuf, version: &str) -> PathBuf {
     path
 }
 
-fn make_patches_dir(base_dir: &PathBuf, version: &str) -> PathBuf {
+/// Encode an environment as U-Boot reads it, in an area of size bytes
+fn env_data(env: &BTreeMap<String, String>, size: usize, redundant: bool) -> Result<Vec<u8>> {
+    let header = if redundant { 5 } else { 4 };
+    let mut data = Vec::new();
+    for (var, value) in env {
+        data.extend_from_slice(format!("{}={}", var, value).as_bytes());
+        data.push(0);
+    }
+    data.push(0);
+    ensure!(header + data.len() <= size, error::EnvTooLarge{ size: size });
+    data.resize(size - header, 0);
+
+    let mut image = util::crc32(&data).to_le_bytes().to_vec();
+    if redundant {
+        // Flags of the active copy
+        image.push(1);
+    }
+    image.extend(data);
+    Ok(image)
+}
+
+fn make_patches_dir(base_dir: &PathBuf, name: &str, version: &str) -> PathBuf {
     let mut path = base_dir.clone();
     path.push("patches");
-    path.push("uboot");
+    path.push(name);
     path.push(version);
     path
 }
 
-pub fn new(config: &Config, interrupt: Interrupt) -> Result<Uboot> {
-    let version = config.uboot.version.clone();
+pub fn new(config: &Config, name: &str, interrupt: Interrupt) -> Result<Uboot> {
+    let uboot: UbootConfig = config.component(name)?;
+    let version = uboot.version.clone();
     let url =  format!("ftp://ftp.denx.de/pub/u-boot/u-boot-{}.tar.bz2", version);
 
+    let download_dir = component::get_dir(&config.download_dir, name, "uboot");
+    let build_dir = component::get_dir(&config.build_dir, name, "uboot");
+
     // Compose the path to the version file
-    let mut v_file = config.download_dir.clone();
+    let mut v_file = download_dir.clone();
     v_file.push(format!("u-boot-{}.version", version));
 
+    let mut pkg_dir = config.build_dir.clone();
+    pkg_dir.push("packages");
+
+    // On targets that rely on TF-A, U-Boot embeds BL31. OP-TEE is then
+    // embedded in TF-A. Otherwise, U-Boot embeds OP-TEE.
+    let mut dependencies = Vec::new();
+    if let Some(tfa) = config.first_component("tfa") {
+        dependencies.push(Input {
+            component: tfa,
+            artifact: "bl31".to_string(),
+            var: "BL31".to_string(),
+        });
+    } else if let Some(optee) = config.first_component("optee") {
+        dependencies.push(Input {
+            component: optee,
+            artifact: "tee".to_string(),
+            var: "TEE".to_string(),
+        });
+    }
+
     Ok(Uboot {
-        download_dir: config.download_dir.clone(),
-        source_dir: make_version_dir(&config.download_dir, &version),
-        build_dir: make_version_dir(&config.build_dir, &version),
-        patches_dir: make_patches_dir(&config.lib_dir, &version),
-        version_file: v_file,
-        url: url::Url::parse(&url).context(error::InvalidUbootURL{})?,
-        config: config.uboot.config.clone(),
-        version: version,
+        name: name.to_string(),
+        source: Source {
+            pretty: "U-Boot".to_string(),
+            url: url::Url::parse(&url).context(error::InvalidUbootURL{})?,
+            archive: PathBuf::from(format!("u-boot-{}.tar.bz2", version)),
+            git_rev: None,
+            source_dir: make_version_dir(&download_dir, &version),
+            download_dir: download_dir,
+            version_file: v_file,
+            patches_dir: make_patches_dir(&config.lib_dir, name, &version),
+            version: version.clone(),
+            interrupt: interrupt,
+        },
+        build_dir: make_version_dir(&build_dir, &version),
+        config: config::make_config_path(&config.lib_dir, name, &version, &uboot.config)?,
         arch: config.toolchain.uboot_arch.clone(),
-        interrupt: interrupt,
         jobs: config.jobs,
+        pkg_dir: pkg_dir,
+        artifacts: uboot.artifacts,
+        boot_device: uboot.boot_device,
+        target: config.target.clone(),
+        target_name: config.target_name.clone(),
+        debian_arch: config.toolchain.debian_arch.clone(),
+        packaging: packaging::new(config),
+        fit: fit::new(config)?,
+        dependencies: dependencies,
+        inputs: Vec::new(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_layout() {
+        let mut env = BTreeMap::new();
+        env.insert("slot".to_string(), "a".to_string());
+        env.insert("bootcmd".to_string(), "run distro".to_string());
+        let vars = b"bootcmd=run distro\0slot=a\0\0";
+
+        let image = env_data(&env, 64, false).unwrap();
+        assert_eq!(image.len(), 64);
+        assert_eq!(image[0..4], 0x81c1_237eu32.to_le_bytes());
+        assert_eq!(&image[4..4 + vars.len()], vars);
+        assert!(image[4 + vars.len()..].iter().all(|b| *b == 0));
+
+        let image = env_data(&env, 64, true).unwrap();
+        assert_eq!(image.len(), 64);
+        assert_eq!(image[0..4], 0x705f_c3f2u32.to_le_bytes());
+        assert_eq!(image[4], 1);
+        assert_eq!(&image[5..5 + vars.len()], vars);
+
+        assert!(env_data(&env, 30, false).is_err());
+    }
+}
@@ -1,8 +1,9 @@
 /* This is part of mktcb - which is under the MIT License ********************/
 
-use snafu::{ResultExt, OptionExt};
+use snafu::{ResultExt, OptionExt, ensure};
 
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use crate::error::Result;
 use crate::error;
 use log::*;
@@ -16,6 +17,15 @@ pub fn url_last(url: &url::Url) -> Result<PathBuf> {
     Ok(std::path::PathBuf::from(filename))
 }
 
+/// Compose the path to the copy of the library configuration that was last
+/// installed in the build directory. The .config itself cannot be compared
+/// with the library configuration, as the build system rewrites it.
+fn get_config_ref(build_dir: &PathBuf) -> PathBuf {
+    let mut path = build_dir.clone();
+    path.push(".config.mktcb");
+    path
+}
+
 pub fn copy_config(opt_cfg: &Option<PathBuf>, build_dir: &PathBuf) -> Result<()> {
     // Let create the build directory. We will need it anyway.
     std::fs::create_dir_all(build_dir).context(
@@ -28,12 +38,91 @@ pub fn copy_config(opt_cfg: &Option<PathBuf>, build_dir: &PathBuf) -> Result<()>
             from: cfg.clone(),
             to: build_cfg,
         })?;
+        let cfg_ref = get_config_ref(build_dir);
+        std::fs::copy(cfg, &cfg_ref).context(error::CopyFailed{
+            from: cfg.clone(),
+            to: cfg_ref,
+        })?;
     } else {
         debug!("No configuration selected");
     }
     Ok(())
 }
 
+/// Determine whether the configuration of the build directory is outdated
+/// with regard to the library configuration. That is the case when the
+/// library configuration changed since it was last copied. For build
+/// directories that don't keep track of the copied configuration, the
+/// library configuration must be more recent than the .config.
+fn is_config_stale(cfg: &PathBuf, build_dir: &PathBuf) -> Result<bool> {
+    let mut build_cfg = build_dir.clone();
+    build_cfg.push(".config");
+    if ! build_cfg.exists() {
+        return Ok(true);
+    }
+
+    let cfg_ref = get_config_ref(build_dir);
+    if cfg_ref.exists() {
+        let lib_data = std::fs::read(cfg).context(
+            error::FailedToRead{ path: cfg.clone() })?;
+        let ref_data = std::fs::read(&cfg_ref).context(
+            error::FailedToRead{ path: cfg_ref.clone() })?;
+        Ok(lib_data != ref_data)
+    } else {
+        let mtime = |path: &PathBuf| {
+            std::fs::metadata(path)
+                .and_then(|m| m.modified())
+                .context(error::FailedToRead{ path: path.clone() })
+        };
+        Ok(mtime(cfg)? > mtime(&build_cfg)?)
+    }
+}
+
+/// Make sure the build directory uses the library configuration. If it is
+/// outdated, the library configuration is copied again.
+pub fn refresh_config(opt_cfg: &Option<PathBuf>, build_dir: &PathBuf) -> Result<()> {
+    if let Some(cfg) = opt_cfg {
+        if is_config_stale(cfg, build_dir)? {
+            warn!("Configuration {:#?} changed since it was copied to {:#?}. Reconfiguring.",
+                cfg, build_dir);
+            copy_config(opt_cfg, build_dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Retrieve the value of an option of the .config of a build directory
+/// (Kconfig). Quotes of string values are stripped.
+pub fn config_value(build_dir: &PathBuf, option: &str) -> Result<Option<String>> {
+    let mut path = build_dir.clone();
+    path.push(".config");
+    ensure!(path.is_file(), error::FileDoesNotExist{ path: path.clone() });
+    let prefix = format!("{}=", option);
+    Ok(read_file(&path)?.lines()
+        .find(|line| line.starts_with(&prefix))
+        .map(|line| line[prefix.len()..].trim_matches('"').to_string()))
+}
+
+/// Set a string option of the .config of a build directory. The .config is
+/// left untouched if the option already has this value, so the build system
+/// does not consider it changed. Returns whether it was changed.
+pub fn set_config_value(build_dir: &PathBuf, option: &str, value: &str) -> Result<bool> {
+    if config_value(build_dir, option)?.as_deref() == Some(value) {
+        return Ok(false);
+    }
+    let mut path = build_dir.clone();
+    path.push(".config");
+    let prefix = format!("{}=", option);
+    let unset = format!("# {} is not set", option);
+    let mut contents: String = read_file(&path)?.lines()
+        .filter(|line| ! line.starts_with(&prefix) && *line != unset)
+        .map(|line| format!("{}\n", line))
+        .collect();
+    contents.push_str(&format!("{}\"{}\"\n", prefix, value));
+    std::fs::write(&path, contents).context(error::FailedToWrite{ path: path.clone() })?;
+    Ok(true)
+}
+
 pub fn getenv(var: &str) -> Result<String> {
     std::env::var(var).context(error::MaintainerError{ var: var.to_string() })
 }
@@ -45,8 +134,124 @@ pub fn read_file(path: &std::path::PathBuf) -> Result<String> {
     let mut data = std::string::String::from_utf8(contents)
         .context(error::FailedToDecodeUTF8{})?;
     // Right-trim the string from any whitespaces (including newlines)
-    while let Some(idx) = data.rfind(char::is_whitespace) {
-        data.truncate(idx);
-    }
+    data.truncate(data.trim_end().len());
     Ok(data)
 }
+
+/// Convert a number of days since the epoch to a civil date (year, month,
+/// day). See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format a UNIX timestamp as an RFC 2822 date (e.g. "Thu, 21 May 2020
+/// 12:00:00 +0000"), as expected by Debian changelogs.
+pub fn rfc2822(timestamp: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+        "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = timestamp / 86400;
+    let secs = timestamp % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        secs / 3600, (secs % 3600) / 60, secs % 60)
+}
+
+/// Format a UNIX timestamp as an ISO 8601 date, in UTC (e.g.
+/// "2020-05-21T12:00:00Z")
+pub fn iso8601(timestamp: u64) -> String {
+    let secs = timestamp % 86400;
+    let (year, month, day) = civil_from_days(timestamp / 86400);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+        secs / 3600, (secs % 3600) / 60, secs % 60)
+}
+
+/// Compute a checksum of a file with one of the coreutils (md5sum,
+/// sha256sum, ...)
+pub fn checksum(prog: &str, path: &PathBuf) -> Result<String> {
+    let out = Command::new(prog)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .context(error::ProgFailed{ proc: prog.to_string() })?;
+    ensure!(out.status.success(), error::ChecksumFailed{ path: path.clone() });
+    let out = String::from_utf8(out.stdout).context(error::FailedToDecodeUTF8{})?;
+    Ok(out.split_whitespace().next().unwrap_or("").to_string())
+}
+
+/// Create a tar archive (.tar.gz) of a directory of parent, that does not
+/// depend on who created it, nor when: its files are dated at epoch
+pub fn tar(parent: &PathBuf, dir: &str, output: &PathBuf, epoch: u64) -> Result<()> {
+    let status = Command::new("tar")
+        .stdin(Stdio::null())
+        .arg("--sort=name")
+        .arg("--owner=0").arg("--group=0").arg("--numeric-owner")
+        .arg(format!("--mtime=@{}", epoch))
+        .arg("-C").arg(parent)
+        .arg("-czf").arg(output)
+        .arg(dir)
+        .status()
+        .context(error::ProgFailed{ proc: "tar".to_string() })?;
+    ensure!(status.success(), error::TarFailed{ path: output.clone() });
+    Ok(())
+}
+
+/// Retrieve the current time as a UNIX timestamp
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Compute the CRC32 (IEEE 802.3) of some data, as used by GPT headers
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // Leap days, including the one of a year divisible by 400
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(47540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47541), (2100, 3, 1));
+
+        assert_eq!(rfc2822(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(rfc2822(951_782_400), "Tue, 29 Feb 2000 00:00:00 +0000");
+        assert_eq!(rfc2822(1_590_062_400), "Thu, 21 May 2020 12:00:00 +0000");
+        assert_eq!(rfc2822(4_107_542_399), "Sun, 28 Feb 2100 23:59:59 +0000");
+        assert_eq!(iso8601(1_590_062_400), "2020-05-21T12:00:00Z");
+    }
+}
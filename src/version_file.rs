@@ -0,0 +1,242 @@
+/* This is part of mktcb - which is under the MIT License ********************/
+
+// Traits ---------------------------------------------------------------------
+use std::io::Write;
+// ----------------------------------------------------------------------------
+
+use std::path::PathBuf;
+
+use snafu::{ResultExt, ensure};
+use log::*;
+
+use crate::error::Result;
+use crate::error;
+use crate::interrupt::Interrupt;
+use crate::patch;
+use crate::util;
+
+/// Line that introduces the list of applied patches
+const PATCHES_HEADER: &str = "patches:";
+/// Prefix of the line holding the date of the sources
+const EPOCH_PREFIX: &str = "epoch:";
+
+/// Library patch that was applied on a source tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedPatch {
+    pub path: PathBuf,
+    /// SHA-256 of the patch when it was applied. This is None for version
+    /// files written by older versions of mktcb.
+    pub sha256: Option<String>,
+}
+
+impl AppliedPatch {
+    /// Record a patch as it is now
+    fn new(path: &PathBuf, patches_dir: &PathBuf) -> Result<AppliedPatch> {
+        Ok(AppliedPatch {
+            path: path.strip_prefix(patches_dir).unwrap_or(path).to_path_buf(),
+            sha256: Some(util::checksum("sha256sum", path)?),
+        })
+    }
+
+    /// Parse the line of a version file that records a patch. It is formatted
+    /// as sha256sum does: the SHA-256 of the patch, two spaces, then its path.
+    fn parse(line: &str) -> AppliedPatch {
+        match line.split_once("  ") {
+            Some((sha256, path)) if sha256.len() == 64 &&
+                sha256.chars().all(|c| c.is_ascii_hexdigit()) => AppliedPatch {
+                path: PathBuf::from(path),
+                sha256: Some(sha256.to_string()),
+            },
+            _ => AppliedPatch {
+                path: PathBuf::from(line),
+                sha256: None,
+            },
+        }
+    }
+}
+
+/// A version file keeps track of the state of a source tree in the download
+/// directory. Its first line is the version of the sources, followed by their
+/// date. Then come the library patches that were applied on them, in order,
+/// with their SHA-256. Patches are relative to the patches directory of the
+/// component.
+pub struct VersionFile {
+    pub version: String,
+    /// Date of the sources (UNIX timestamp), as released. Builds record it
+    /// instead of the current time. This is None for version files written
+    /// by older versions of mktcb.
+    pub epoch: Option<u64>,
+    /// Applied patches. This is None for version files written by older
+    /// versions of mktcb, which did not record patches.
+    pub patches: Option<Vec<AppliedPatch>>,
+}
+
+impl VersionFile {
+    /// Create the record of freshly retrieved sources, on which no patch
+    /// was applied yet
+    pub fn new(version: &str, epoch: u64) -> VersionFile {
+        VersionFile {
+            version: version.to_string(),
+            epoch: Some(epoch),
+            patches: Some(Vec::new()),
+        }
+    }
+
+    /// Apply a library patch on a source tree, and record it. patches_dir
+    /// is the patches directory of the component.
+    pub fn apply(&mut self, patch: &PathBuf, patches_dir: &PathBuf,
+                 source_dir: &PathBuf) -> Result<()> {
+        patch::patch(source_dir, patch)?;
+        if let Some(patches) = &mut self.patches {
+            patches.push(AppliedPatch::new(patch, patches_dir)?);
+        }
+        Ok(())
+    }
+
+    /// Apply all the library patches of a directory on a source tree, in
+    /// order, and record them
+    pub fn apply_all(&mut self, dir: &PathBuf, patches_dir: &PathBuf,
+                     source_dir: &PathBuf) -> Result<()> {
+        for entry in patch::list_patches_in(dir)? {
+            self.apply(&entry, patches_dir, source_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Record patches that are assumed to have been applied, for version
+    /// files that predate their recording
+    pub fn assume(&mut self, path: &PathBuf, patches: &[PathBuf],
+                  patches_dir: &PathBuf) -> Result<()> {
+        warn!("{:#?} does not record the applied patches. Assuming they are the \
+            ones of the library.", path);
+        let mut assumed = Vec::new();
+        for patch in patches {
+            assumed.push(AppliedPatch::new(patch, patches_dir)?);
+        }
+        self.patches = Some(assumed);
+        Ok(())
+    }
+
+    /// Retrieve the recorded patches, with their full path, if they were
+    /// recorded
+    pub fn applied(&self, patches_dir: &PathBuf) -> Option<Vec<AppliedPatch>> {
+        self.patches.as_ref().map(|patches| patches.iter()
+            .map(|p| {
+                let mut path = patches_dir.clone();
+                path.push(&p.path);
+                AppliedPatch {
+                    path: path,
+                    sha256: p.sha256.clone(),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Make sure that sources that were not recorded by a version file are not
+/// around: they would be the leftovers of an interrupted fetch.
+pub fn ensure_not_corrupted(path: &PathBuf, source_dir: &PathBuf) -> Result<()> {
+    ensure!(path.exists() || ! source_dir.exists(), error::CorruptedSourceDir{
+        dir: source_dir.clone(),
+        version_file: path.clone(),
+    });
+    Ok(())
+}
+
+/// Patch freshly retrieved sources with the library patches of a directory,
+/// and write their version file. Interrupts are held off meanwhile: a source
+/// tree that is partially patched cannot be recovered.
+pub fn patch_and_record(path: &PathBuf, version: &str, epoch: u64, interrupt: &Interrupt,
+                        dir: &PathBuf, patches_dir: &PathBuf, source_dir: &PathBuf) -> Result<()> {
+    let _guard = interrupt.lock();
+    let mut recorded = VersionFile::new(version, epoch);
+    recorded.apply_all(dir, patches_dir, source_dir)?;
+    write(path, &recorded)
+}
+
+pub fn load(path: &PathBuf) -> Result<VersionFile> {
+    let data = util::read_file(path)?;
+    let mut lines = data.lines();
+    let version = lines.next().unwrap_or("").trim().to_string();
+    let mut line = lines.next();
+    let epoch = match line.and_then(|l| l.trim().strip_prefix(EPOCH_PREFIX)) {
+        Some(value) => {
+            line = lines.next();
+            value.trim().parse().ok()
+        },
+        None => None,
+    };
+    let patches = match line {
+        Some(line) if line.trim() == PATCHES_HEADER => Some(lines
+            .filter(|line| ! line.trim().is_empty())
+            .map(|line| AppliedPatch::parse(line.trim()))
+            .collect()),
+        _ => None,
+    };
+    Ok(VersionFile {
+        version: version,
+        epoch: epoch,
+        patches: patches,
+    })
+}
+
+pub fn write(path: &PathBuf, contents: &VersionFile) -> Result<()> {
+    let mut file = std::fs::File::create(path).context(
+        error::CreateFileError{path: path.clone()})?;
+    writeln!(file, "{}", contents.version)
+        .context(error::FailedToWrite{path: path.clone()})?;
+    if let Some(epoch) = contents.epoch {
+        writeln!(file, "{} {}", EPOCH_PREFIX, epoch)
+            .context(error::FailedToWrite{path: path.clone()})?;
+    }
+    writeln!(file, "{}", PATCHES_HEADER)
+        .context(error::FailedToWrite{path: path.clone()})?;
+    for patch in contents.patches.iter().flatten() {
+        match &patch.sha256 {
+            Some(sha256) => writeln!(file, "{}  {}", sha256, patch.path.to_str().unwrap()),
+            None => writeln!(file, "{}", patch.path.to_str().unwrap()),
+        }.context(error::FailedToWrite{path: path.clone()})?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("mktcb-version-{}", std::process::id()));
+        let mut recorded = VersionFile::new("1.0", 1_600_000_000);
+        let sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        recorded.patches = Some(vec![
+            AppliedPatch {
+                path: PathBuf::from("1.0/0001-fix.patch"),
+                sha256: Some(sha256.to_string()),
+            },
+            AppliedPatch {
+                path: PathBuf::from("1.0/0002-old  style.patch"),
+                sha256: None,
+            },
+        ]);
+        write(&path, &recorded).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!(
+            "1.0\nepoch: 1600000000\npatches:\n{}  1.0/0001-fix.patch\n1.0/0002-old  style.patch\n",
+            sha256));
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.version, "1.0");
+        assert_eq!(loaded.epoch, Some(1_600_000_000));
+        assert_eq!(loaded.patches, recorded.patches);
+
+        // Version files of older versions of mktcb
+        std::fs::write(&path, "1.0\npatches:\n").unwrap();
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.epoch, None);
+        assert_eq!(loaded.patches, Some(vec![]));
+        std::fs::write(&path, "1.0\n").unwrap();
+        assert_eq!(load(&path).unwrap().patches, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}